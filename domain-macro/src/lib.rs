@@ -1,8 +1,8 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{Data, DataStruct, Fields, Type};
+use quote::{format_ident, quote};
+use syn::{Data, DataStruct, Fields, Lit, Meta, MetaNameValue, NestedMeta, Type};
 
 #[proc_macro_derive(WithMetadata)]
 pub fn with_metadata_derive(input: TokenStream) -> TokenStream {
@@ -39,13 +39,76 @@ fn impl_with_metadata_macro(ast: &syn::DeriveInput) -> TokenStream {
     if metadata_fields.len() != 1 {
         panic!("exactly one metadata field allowed for struct");
     }
-    let metadata_field = metadata_fields.get(0).unwrap();
+    let metadata_field = metadata_fields.first().unwrap();
     let field_ident = metadata_field.ident.as_ref().unwrap();
+    let field_name = serde_rename(metadata_field).unwrap_or_else(|| field_ident.to_string());
     let gen = quote! {
         impl WithMetadata for #name {
+           fn domain_metadata(&self) -> &Metadata {
+                &self.#field_ident
+           }
            fn domain_metadata_mut(&mut self) -> &mut Metadata {
                 &mut self.#field_ident
            }
+           fn metadata_field() -> &'static str {
+                #field_name
+           }
+        }
+    };
+    gen.into()
+}
+
+/// The name given by `#[serde(rename = "...")]`, if any.
+fn serde_rename(field: &syn::Field) -> Option<String> {
+    field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("serde"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .find_map(|nested| match nested {
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(name),
+                ..
+            })) if path.is_ident("rename") => Some(name.value()),
+            _ => None,
+        })
+}
+
+#[proc_macro_derive(FieldPaths)]
+pub fn field_paths_derive(input: TokenStream) -> TokenStream {
+    let ast = syn::parse(input).unwrap();
+    impl_field_paths_macro(&ast)
+}
+/// One `FieldPath` constant per field, named after the field in upper case
+/// and holding its serialized name.
+fn impl_field_paths_macro(ast: &syn::DeriveInput) -> TokenStream {
+    let name = &ast.ident;
+    let fields = match &ast.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => {
+            panic!("only struct with named fields allowed brah")
+        }
+    };
+    let constants = fields.iter().filter_map(|field| {
+        let ident = field.ident.as_ref()?.to_string();
+        let ident = ident.trim_start_matches("r#");
+        let constant = format_ident!("{}", ident.to_uppercase());
+        let path = serde_rename(field).unwrap_or_else(|| String::from(ident));
+        Some(quote! {
+            pub const #constant: FieldPath = FieldPath::new(#path);
+        })
+    });
+    let gen = quote! {
+        impl #name {
+            #(#constants)*
         }
     };
     gen.into()
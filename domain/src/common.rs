@@ -1,5 +1,5 @@
-use crate::OffsetDateTime;
 use crate::{Deserialize, Serialize};
+use crate::{FieldPath, FieldPaths, OffsetDateTime};
 use std::ops::Deref;
 
 #[derive(PartialOrd, PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -17,12 +17,24 @@ impl Default for Id {
     }
 }
 
-#[derive(PartialOrd, PartialEq, Debug, Serialize, Deserialize, crate::WithJsonProcessor)]
+#[derive(
+    PartialOrd,
+    PartialEq,
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+    crate::WithJsonProcessor,
+    FieldPaths,
+)]
 pub struct Metadata {
     id: Id,
     version: Option<u32>,
     creation_date: Option<OffsetDateTime>,
     updated_date: Option<OffsetDateTime>,
+    /// Set when the document is soft deleted, see `MongoRepository::set_soft_delete`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_date: Option<OffsetDateTime>,
 }
 
 impl Metadata {
@@ -44,6 +56,12 @@ impl Metadata {
     pub fn updated_date(&self) -> &Option<OffsetDateTime> {
         &self.updated_date
     }
+    pub fn deleted_date(&self) -> &Option<OffsetDateTime> {
+        &self.deleted_date
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_date.is_some()
+    }
     pub fn update_metadata(&mut self) {
         if let Some(version) = self.version {
             self.version = Some(version + 1);
@@ -65,12 +83,18 @@ impl Default for Metadata {
             version: Some(1),
             creation_date: Some(OffsetDateTime::now_utc()),
             updated_date: Default::default(),
+            deleted_date: Default::default(),
         }
     }
 }
 
 pub trait WithMetadata {
+    fn domain_metadata(&self) -> &Metadata;
     fn domain_metadata_mut(&mut self) -> &mut Metadata;
+    /// Name of the metadata field once serialized.
+    fn metadata_field() -> &'static str
+    where
+        Self: Sized;
 }
 pub trait WithJsonProcessor<'a> {
     type Output;
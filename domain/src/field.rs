@@ -0,0 +1,32 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+
+/// Path of a field once serialized, like `profile.email_address`. Derive
+/// `FieldPaths` on a struct to get one constant per field.
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct FieldPath(Cow<'static, str>);
+
+impl FieldPath {
+    pub const fn new(path: &'static str) -> FieldPath {
+        FieldPath(Cow::Borrowed(path))
+    }
+    /// `User::PROFILE.join(&Profile::EMAIL_ADDRESS)` is `profile.email_address`.
+    pub fn join(&self, child: &FieldPath) -> FieldPath {
+        FieldPath(Cow::Owned(format!("{}.{}", self.0, child.0)))
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for FieldPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for FieldPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
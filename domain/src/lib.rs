@@ -1,13 +1,16 @@
 mod command;
 mod common;
+mod field;
 mod user;
 pub use command::*;
 pub use common::Id;
 pub use common::Metadata;
 pub use common::WithJsonProcessor;
 pub use common::WithMetadata;
+pub use domain_macro::FieldPaths;
 pub use domain_macro::WithJsonProcessor;
 pub use domain_macro::WithMetadata;
+pub use field::FieldPath;
 pub use serde::{Deserialize, Serialize};
 pub use time::OffsetDateTime;
 pub use user::Address;
@@ -1,7 +1,16 @@
-use crate::{Id, Metadata, WithJsonProcessor, WithMetadata};
+use crate::{FieldPath, FieldPaths, Id, Metadata, WithJsonProcessor, WithMetadata};
 use serde::{Deserialize, Serialize};
 
-#[derive(PartialOrd, PartialEq, Debug, Serialize, Deserialize, WithMetadata, WithJsonProcessor)]
+#[derive(
+    PartialOrd,
+    PartialEq,
+    Debug,
+    Serialize,
+    Deserialize,
+    WithMetadata,
+    WithJsonProcessor,
+    FieldPaths,
+)]
 pub struct User {
     domain_metadata: Metadata,
     nickname: String,
@@ -55,7 +64,7 @@ impl User {
     }
 }
 
-#[derive(PartialOrd, PartialEq, Default, Debug, Serialize, Deserialize)]
+#[derive(PartialOrd, PartialEq, Default, Debug, Serialize, Deserialize, FieldPaths)]
 pub struct Profile {
     picture: Option<Metadata>,
     firstname: String,
@@ -139,7 +148,7 @@ impl Profile {
     }
 }
 
-#[derive(PartialOrd, PartialEq, Default, Debug, Serialize, Deserialize)]
+#[derive(PartialOrd, PartialEq, Default, Debug, Serialize, Deserialize, FieldPaths)]
 pub struct Address {
     street: String,
     number: String,
@@ -218,7 +227,7 @@ impl Address {
 mod tests {
 
     use crate::user::{Address, Profile, User};
-    use crate::{WithJsonProcessor, WithMetadata};
+    use crate::{FieldPath, Id, Metadata, Serialize, WithJsonProcessor, WithMetadata};
 
     #[test]
     fn test_user_creation() {
@@ -255,4 +264,39 @@ mod tests {
         let json = user.domain_metadata_mut().to_json();
         println!("{}", json.unwrap());
     }
+
+    #[test]
+    fn test_metadata_field() {
+        #[derive(Serialize, crate::WithMetadata)]
+        struct Renamed {
+            #[serde(rename = "metadata")]
+            domain_metadata: Metadata,
+        }
+        assert_eq!("domain_metadata", User::metadata_field());
+        assert_eq!("metadata", Renamed::metadata_field());
+    }
+
+    #[test]
+    fn test_field_paths() {
+        #[derive(Serialize, crate::FieldPaths)]
+        struct Renamed {
+            #[serde(rename = "_id")]
+            id: Id,
+            r#type: String,
+        }
+        assert_eq!("profile", User::PROFILE.as_str());
+        assert_eq!(
+            "profile.address.po_box",
+            User::PROFILE
+                .join(&Profile::ADDRESS)
+                .join(&Address::PO_BOX)
+                .as_str()
+        );
+        assert_eq!(
+            "domain_metadata.version",
+            User::DOMAIN_METADATA.join(&Metadata::VERSION).to_string()
+        );
+        assert_eq!("_id", Renamed::ID.as_str());
+        assert_eq!("type", Renamed::TYPE.as_str());
+    }
 }
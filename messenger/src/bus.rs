@@ -0,0 +1,94 @@
+use crate::envelope::Envelope;
+use crate::error::MessengerError;
+use crate::message::{Message, MessageOptions};
+use crate::messenger::Messenger;
+use crate::publisher::PublishOutcome;
+use crate::subscription::SubscribeOptions;
+use domain::WithJsonProcessor;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+
+/// What handlers need from a broker: publishing and typed subscriptions whose
+/// envelopes are acked through `Envelope`. Implemented by `Messenger` and by
+/// `InMemoryBus` for tests.
+#[async_trait::async_trait]
+pub trait MessageBus: Send + Sync {
+    fn application_name(&self) -> &str;
+
+    async fn publish_message(
+        &self,
+        routing_key: &str,
+        message: &Message,
+    ) -> Result<PublishOutcome, MessengerError>;
+
+    async fn subscribe_typed_with_options<T>(
+        &self,
+        routing_key: &str,
+        options: &SubscribeOptions,
+    ) -> anyhow::Result<BoxStream<'static, Result<Envelope<T>, MessengerError>>>
+    where
+        T: for<'a> WithJsonProcessor<'a, Output = T> + Send + 'static;
+
+    async fn publish_with<'a, P>(
+        &self,
+        routing_key: &str,
+        payload: &P,
+        options: MessageOptions,
+    ) -> Result<PublishOutcome, MessengerError>
+    where
+        P: WithJsonProcessor<'a> + Sync,
+    {
+        let message = Message::from_payload(self.application_name(), payload, options)?;
+        self.publish_message(routing_key, &message).await
+    }
+
+    async fn publish<'a, P>(
+        &self,
+        routing_key: &str,
+        payload: &P,
+    ) -> Result<PublishOutcome, MessengerError>
+    where
+        P: WithJsonProcessor<'a> + Sync,
+    {
+        self.publish_with(routing_key, payload, MessageOptions::default())
+            .await
+    }
+
+    async fn subscribe_typed<T>(
+        &self,
+        routing_key: &str,
+    ) -> anyhow::Result<BoxStream<'static, Result<Envelope<T>, MessengerError>>>
+    where
+        T: for<'a> WithJsonProcessor<'a, Output = T> + Send + 'static,
+    {
+        self.subscribe_typed_with_options(routing_key, &SubscribeOptions::default())
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl MessageBus for Messenger {
+    fn application_name(&self) -> &str {
+        Messenger::application_name(self)
+    }
+
+    async fn publish_message(
+        &self,
+        routing_key: &str,
+        message: &Message,
+    ) -> Result<PublishOutcome, MessengerError> {
+        Messenger::publish_message(self, routing_key, message).await
+    }
+
+    async fn subscribe_typed_with_options<T>(
+        &self,
+        routing_key: &str,
+        options: &SubscribeOptions,
+    ) -> anyhow::Result<BoxStream<'static, Result<Envelope<T>, MessengerError>>>
+    where
+        T: for<'a> WithJsonProcessor<'a, Output = T> + Send + 'static,
+    {
+        let envelopes = Messenger::subscribe_typed_with_options(self, routing_key, options).await?;
+        Ok(envelopes.boxed())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::error::MessengerError;
+use crate::pool::{Connection, Pool};
+use deadpool::managed;
+use lapin::options::{ConfirmSelectOptions, ExchangeDeclareOptions};
+use lapin::types::FieldTable;
+use lapin::{Channel, ExchangeKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+
+const CLOSE_REPLY_CODE: u16 = 200;
+
+struct PublisherSlot {
+    connection: Connection,
+    channel: Channel,
+}
+
+/// Owns the long-lived channels of a `Messenger`: one confirm-mode publisher
+/// channel per dedicated connection, and the consumer channels opened on a
+/// connection shared by all subscriptions. Channels found closed are reopened
+/// on next use.
+pub(crate) struct ChannelManager {
+    pool: Pool,
+    publishers: Vec<Mutex<Option<PublisherSlot>>>,
+    next_publisher: AtomicUsize,
+    consumer_connection: Mutex<Option<Connection>>,
+    consumers: Mutex<Vec<Channel>>,
+}
+
+impl std::fmt::Debug for ChannelManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelManager")
+            .field("pool", &self.pool)
+            .field("publishers", &self.publishers.len())
+            .finish()
+    }
+}
+
+async fn close_connection(connection: Connection) -> Result<(), MessengerError> {
+    if connection.status().connected() {
+        connection.close(CLOSE_REPLY_CODE, "bye").await?;
+    }
+    // a closed connection must not go back to the pool
+    let _ = managed::Object::take(connection);
+    Ok(())
+}
+
+impl ChannelManager {
+    pub(crate) fn new(pool: Pool, publisher_channels: usize) -> ChannelManager {
+        ChannelManager {
+            pool,
+            publishers: (0..publisher_channels.max(1))
+                .map(|_| Mutex::new(None))
+                .collect(),
+            next_publisher: AtomicUsize::new(0),
+            consumer_connection: Mutex::new(None),
+            consumers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Declares the topic exchange of the messenger on a short-lived channel.
+    pub(crate) async fn declare_exchange(&self, exchange: &str) -> Result<(), MessengerError> {
+        let conn = self.pool.get().await?;
+        let channel = conn.create_channel().await?;
+        channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    passive: false,
+                    durable: true,
+                    auto_delete: false,
+                    internal: false,
+                    nowait: false,
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel.close(CLOSE_REPLY_CODE, "declared").await?;
+        Ok(())
+    }
+
+    pub(crate) async fn publisher_channel(&self) -> Result<(usize, Channel), MessengerError> {
+        let idx = self.next_publisher.fetch_add(1, Ordering::Relaxed) % self.publishers.len();
+        let mut slot = self.publishers[idx].lock().await;
+        if let Some(publisher) = slot.as_ref() {
+            if publisher.channel.status().connected() {
+                return Ok((idx, publisher.channel.clone()));
+            }
+            tracing::warn!("publisher channel {idx} is closed, reopening it");
+        }
+        *slot = None;
+        let connection = self.pool.get().await?;
+        let channel = connection.create_channel().await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        *slot = Some(PublisherSlot {
+            connection,
+            channel: channel.clone(),
+        });
+        Ok((idx, channel))
+    }
+
+    /// Drops a publisher channel after a channel-level error so the next
+    /// publish on that slot opens a fresh one.
+    pub(crate) async fn invalidate_publisher(&self, idx: usize) {
+        let mut slot = self.publishers[idx].lock().await;
+        if let Some(publisher) = slot.take() {
+            let _ = publisher.channel.close(CLOSE_REPLY_CODE, "reset").await;
+        }
+    }
+
+    pub(crate) async fn consumer_channel(&self) -> Result<Channel, MessengerError> {
+        let mut connection = self.consumer_connection.lock().await;
+        let connected = connection
+            .as_ref()
+            .map(|c| c.status().connected())
+            .unwrap_or(false);
+        if !connected {
+            *connection = Some(self.pool.get().await?);
+        }
+        let channel = connection
+            .as_ref()
+            .expect("consumer connection was just set")
+            .create_channel()
+            .await?;
+        let mut consumers = self.consumers.lock().await;
+        consumers.retain(|c| c.status().connected());
+        consumers.push(channel.clone());
+        Ok(channel)
+    }
+
+    pub(crate) async fn close(&self) -> Result<(), MessengerError> {
+        for slot in &self.publishers {
+            if let Some(publisher) = slot.lock().await.take() {
+                if publisher.channel.status().connected() {
+                    publisher.channel.close(CLOSE_REPLY_CODE, "bye").await?;
+                }
+            }
+        }
+        for channel in self.consumers.lock().await.drain(..) {
+            if channel.status().connected() {
+                channel.close(CLOSE_REPLY_CODE, "bye").await?;
+            }
+        }
+        self.consumer_connection.lock().await.take();
+        Ok(())
+    }
+
+    /// Closes the channels, then the connections, and the pool for good.
+    pub(crate) async fn shutdown(&self) -> Result<(), MessengerError> {
+        for slot in &self.publishers {
+            if let Some(publisher) = slot.lock().await.take() {
+                if publisher.channel.status().connected() {
+                    publisher.channel.close(CLOSE_REPLY_CODE, "bye").await?;
+                }
+                close_connection(publisher.connection).await?;
+            }
+        }
+        for channel in self.consumers.lock().await.drain(..) {
+            if channel.status().connected() {
+                channel.close(CLOSE_REPLY_CODE, "bye").await?;
+            }
+        }
+        if let Some(connection) = self.consumer_connection.lock().await.take() {
+            close_connection(connection).await?;
+        }
+        self.pool.close();
+        Ok(())
+    }
+}
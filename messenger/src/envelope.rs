@@ -0,0 +1,333 @@
+use crate::error::{DecodeError, MessengerError};
+use crate::memory::MemoryAcker;
+use crate::message::Message;
+use crate::shutdown::InFlight;
+use crate::subscription::{self, RetryPolicy};
+use domain::{Id, OffsetDateTime, WithJsonProcessor};
+use lapin::acker::Acker;
+use lapin::message::Delivery;
+use lapin::options::{BasicAckOptions, BasicNackOptions, BasicPublishOptions, BasicRejectOptions};
+use lapin::{BasicProperties, Channel};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What happened to a delivery handed back with `Envelope::retry_later`.
+#[derive(PartialEq, Debug, Clone)]
+pub enum RetryOutcome {
+    Requeued,
+    Scheduled { attempt: u32, delay: Duration },
+    DeadLettered,
+}
+
+#[derive(Debug)]
+pub(crate) struct RetryContext {
+    channel: Channel,
+    queue: String,
+    policy: RetryPolicy,
+}
+
+impl RetryContext {
+    pub(crate) fn new(channel: Channel, queue: &str, policy: RetryPolicy) -> RetryContext {
+        RetryContext {
+            channel,
+            queue: String::from(queue),
+            policy,
+        }
+    }
+}
+
+/// Settles a delivery with whatever it came from: the broker, or an `InMemoryBus`.
+#[derive(Debug)]
+pub(crate) enum Acknowledger {
+    Amqp {
+        acker: Acker,
+        retry: Option<Arc<RetryContext>>,
+    },
+    Memory(MemoryAcker),
+}
+
+impl Acknowledger {
+    pub(crate) async fn ack(&self) -> Result<(), MessengerError> {
+        match self {
+            Acknowledger::Amqp { acker, .. } => acker.ack(BasicAckOptions::default()).await?,
+            Acknowledger::Memory(acker) => acker.ack(),
+        }
+        Ok(())
+    }
+    pub(crate) async fn nack(&self, requeue: bool) -> Result<(), MessengerError> {
+        match self {
+            Acknowledger::Amqp { acker, .. } => {
+                acker
+                    .nack(BasicNackOptions {
+                        multiple: false,
+                        requeue,
+                    })
+                    .await?
+            }
+            Acknowledger::Memory(acker) => acker.reject(requeue),
+        }
+        Ok(())
+    }
+    pub(crate) async fn reject(&self, requeue: bool) -> Result<(), MessengerError> {
+        match self {
+            Acknowledger::Amqp { acker, .. } => {
+                acker.reject(BasicRejectOptions { requeue }).await?
+            }
+            Acknowledger::Memory(acker) => acker.reject(requeue),
+        }
+        Ok(())
+    }
+}
+
+/// A decoded delivery: the typed payload, the `Message` it came in and the
+/// handles to settle it with the broker.
+#[derive(Debug)]
+pub struct Envelope<T> {
+    payload: T,
+    message: Message,
+    routing_key: String,
+    redelivered: bool,
+    attempt: u32,
+    data: Vec<u8>,
+    properties: BasicProperties,
+    acker: Acknowledger,
+    in_flight: Option<InFlight>,
+}
+
+impl<T> Envelope<T>
+where
+    T: for<'a> WithJsonProcessor<'a, Output = T>,
+{
+    pub(crate) fn from_delivery(
+        delivery: Delivery,
+        retry: Option<Arc<RetryContext>>,
+    ) -> Result<Envelope<T>, MessengerError> {
+        let Delivery {
+            routing_key,
+            redelivered,
+            properties,
+            data,
+            acker,
+            ..
+        } = delivery;
+        Envelope::decode(
+            routing_key.as_str(),
+            redelivered,
+            properties,
+            data,
+            Acknowledger::Amqp { acker, retry },
+        )
+    }
+
+    pub(crate) fn decode(
+        routing_key: &str,
+        redelivered: bool,
+        properties: BasicProperties,
+        data: Vec<u8>,
+        acker: Acknowledger,
+    ) -> Result<Envelope<T>, MessengerError> {
+        let decoded = Message::from_json_slice(&data[..])
+            .and_then(|message| T::from_json_slice(&message.payload()[..]).map(|p| (message, p)));
+        match decoded {
+            Ok((message, payload)) => Ok(Envelope {
+                payload,
+                message,
+                routing_key: String::from(routing_key),
+                redelivered,
+                attempt: subscription::attempt_from_headers(properties.headers()),
+                data,
+                properties,
+                acker,
+                in_flight: None,
+            }),
+            Err(e) => Err(MessengerError::Decode(Box::new(DecodeError::new(
+                routing_key,
+                data,
+                acker,
+                e,
+            )))),
+        }
+    }
+}
+
+impl<T> Envelope<T> {
+    pub fn payload(&self) -> &T {
+        &self.payload
+    }
+    pub fn into_payload(self) -> T {
+        self.payload
+    }
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+    pub fn id(&self) -> &Id {
+        self.message.id()
+    }
+    pub fn creation_date(&self) -> OffsetDateTime {
+        self.message.creation_date()
+    }
+    pub fn sender(&self) -> &str {
+        self.message.sender()
+    }
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+    pub fn redelivered(&self) -> bool {
+        self.redelivered
+    }
+    /// Where the sender of a request expects its reply, if it is one.
+    pub fn reply_to(&self) -> Option<&str> {
+        self.properties.reply_to().as_ref().map(|r| r.as_str())
+    }
+    /// 1 for the first delivery, incremented each time `retry_later` schedules a retry.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+    /// Counts the envelope as in flight for `ShutdownToken::drained` until it is dropped.
+    pub(crate) fn track(self, in_flight: InFlight) -> Self {
+        Envelope {
+            in_flight: Some(in_flight),
+            ..self
+        }
+    }
+    pub(crate) fn into_parts(self) -> (T, Envelope<()>) {
+        let Envelope {
+            payload,
+            message,
+            routing_key,
+            redelivered,
+            attempt,
+            data,
+            properties,
+            acker,
+            in_flight,
+        } = self;
+        (
+            payload,
+            Envelope {
+                payload: (),
+                message,
+                routing_key,
+                redelivered,
+                attempt,
+                data,
+                properties,
+                acker,
+                in_flight,
+            },
+        )
+    }
+    pub async fn ack(&self) -> Result<(), MessengerError> {
+        self.acker.ack().await
+    }
+    pub async fn nack(&self, requeue: bool) -> Result<(), MessengerError> {
+        self.acker.nack(requeue).await
+    }
+    pub async fn reject(&self, requeue: bool) -> Result<(), MessengerError> {
+        self.acker.reject(requeue).await
+    }
+
+    /// Hands a failed delivery back. Without a retry policy it is requeued;
+    /// with one it is delayed in the retry queue for its attempt, or moved to
+    /// the dead-letter queue once the attempts are exhausted.
+    pub async fn retry_later(&self) -> Result<RetryOutcome, MessengerError> {
+        let retry = match &self.acker {
+            Acknowledger::Amqp {
+                retry: Some(retry), ..
+            } => retry,
+            Acknowledger::Amqp { retry: None, .. } => {
+                self.nack(true).await?;
+                return Ok(RetryOutcome::Requeued);
+            }
+            Acknowledger::Memory(acker) => return Ok(acker.retry_later(self.attempt)),
+        };
+        if self.attempt >= retry.policy.max_attempts() {
+            self.reject(false).await?;
+            return Ok(RetryOutcome::DeadLettered);
+        }
+        let properties = self
+            .properties
+            .clone()
+            .with_headers(subscription::with_attempt(
+                self.properties.headers(),
+                self.attempt + 1,
+            ));
+        let confirmation = retry
+            .channel
+            .basic_publish(
+                "",
+                &subscription::retry_queue(&retry.queue, self.attempt),
+                BasicPublishOptions::default(),
+                &self.data,
+                properties,
+            )
+            .await?
+            .await?;
+        if confirmation.is_nack() {
+            return Err(MessengerError::Nacked {
+                message_id: self.id().clone(),
+            });
+        }
+        self.ack().await?;
+        Ok(RetryOutcome::Scheduled {
+            attempt: self.attempt + 1,
+            delay: retry.policy.delay(self.attempt),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::Envelope;
+    use crate::error::MessengerError;
+    use crate::message::Message;
+    use domain::{CreateUserCommand, Metadata, WithJsonProcessor};
+    use lapin::message::Delivery;
+
+    fn delivery(data: Vec<u8>) -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "User".into(),
+            routing_key: "CreateUserCommand".into(),
+            redelivered: false,
+            properties: Default::default(),
+            data,
+            acker: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_decode_envelope() {
+        let command = CreateUserCommand {
+            domain_metadata: Metadata::default(),
+            nickname: String::from("nordine"),
+            password: String::from("kikoo"),
+            confirm_password: String::from("kikoo"),
+            email: String::from("kikoo@lol.com"),
+        };
+        let message = Message::new("wesh", command.to_json().unwrap().into_bytes());
+        let data = message.to_json().unwrap().into_bytes();
+        let envelope = Envelope::<CreateUserCommand>::from_delivery(delivery(data), None).unwrap();
+        assert_eq!(&command, envelope.payload());
+        assert_eq!("wesh", envelope.sender());
+        assert_eq!(message.id(), envelope.id());
+        assert_eq!("CreateUserCommand", envelope.routing_key());
+        assert_eq!(1, envelope.attempt());
+    }
+
+    #[test]
+    fn test_decode_failure() {
+        let message = Message::new("wesh", b"{\"oops\": true}".to_vec());
+        let data = message.to_json().unwrap().into_bytes();
+        let res = Envelope::<CreateUserCommand>::from_delivery(delivery(data.clone()), None);
+        match res {
+            Err(MessengerError::Decode(e)) => {
+                assert_eq!("CreateUserCommand", e.routing_key());
+                assert_eq!(&data, e.data());
+            }
+            _ => panic!("expected a decode error"),
+        }
+        let res = Envelope::<CreateUserCommand>::from_delivery(delivery(b"garbage".to_vec()), None);
+        assert!(matches!(res, Err(MessengerError::Decode(_))));
+    }
+}
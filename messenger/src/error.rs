@@ -0,0 +1,197 @@
+use crate::envelope::Acknowledger;
+use crate::pool::PoolError;
+use domain::Id;
+use lapin::protocol::AMQPErrorKind;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum MessengerError {
+    Amqp(lapin::Error),
+    Pool(PoolError),
+    Serialization(anyhow::Error),
+    Decode(Box<DecodeError>),
+    Nacked {
+        message_id: Id,
+    },
+    Returned {
+        message_id: Id,
+        reply_code: u16,
+        reply_text: String,
+    },
+    ConfirmTimeout {
+        message_id: Id,
+        timeout: Duration,
+    },
+    ConfirmNotRequested {
+        message_id: Id,
+    },
+    RequestTimeout {
+        request_id: Id,
+        routing_key: String,
+        timeout: Duration,
+    },
+    Remote {
+        request_id: Id,
+        message: String,
+    },
+    ReplyChannelClosed {
+        request_id: Id,
+    },
+    /// The connection to the broker is down and could not be restored in time,
+    /// or the `Messenger` gave up reconnecting.
+    Disconnected,
+}
+
+/// A delivery whose content could not be decoded. It is still unacknowledged,
+/// so the consumer decides whether to reject it or let it be redelivered.
+#[derive(Debug)]
+pub struct DecodeError {
+    routing_key: String,
+    data: Vec<u8>,
+    acker: Acknowledger,
+    source: anyhow::Error,
+}
+
+impl DecodeError {
+    pub(crate) fn new(
+        routing_key: &str,
+        data: Vec<u8>,
+        acker: Acknowledger,
+        source: anyhow::Error,
+    ) -> Self {
+        DecodeError {
+            routing_key: String::from(routing_key),
+            data,
+            acker,
+            source,
+        }
+    }
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+    pub fn data(&self) -> &Vec<u8> {
+        &self.data
+    }
+    pub fn source(&self) -> &anyhow::Error {
+        &self.source
+    }
+    pub async fn reject(&self, requeue: bool) -> Result<(), MessengerError> {
+        self.acker.reject(requeue).await
+    }
+}
+
+impl Display for MessengerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessengerError::Amqp(e) => write!(f, "amqp error: {e}"),
+            MessengerError::Pool(e) => write!(f, "could not get a connection: {e}"),
+            MessengerError::Serialization(e) => write!(f, "could not serialize message: {e}"),
+            MessengerError::Nacked { message_id } => {
+                write!(
+                    f,
+                    "message {} was nacked by the broker",
+                    message_id.as_str()
+                )
+            }
+            MessengerError::Returned {
+                message_id,
+                reply_code,
+                reply_text,
+            } => write!(
+                f,
+                "message {} was returned by the broker: {reply_code} {reply_text}",
+                message_id.as_str()
+            ),
+            MessengerError::ConfirmTimeout {
+                message_id,
+                timeout,
+            } => write!(
+                f,
+                "no confirmation for message {} after {:?}",
+                message_id.as_str(),
+                timeout
+            ),
+            MessengerError::ConfirmNotRequested { message_id } => write!(
+                f,
+                "message {} was published on a channel without confirms",
+                message_id.as_str()
+            ),
+            MessengerError::RequestTimeout {
+                request_id,
+                routing_key,
+                timeout,
+            } => write!(
+                f,
+                "no reply to request {} on {routing_key} after {:?}",
+                request_id.as_str(),
+                timeout
+            ),
+            MessengerError::Remote {
+                request_id,
+                message,
+            } => write!(
+                f,
+                "request {} failed remotely: {message}",
+                request_id.as_str()
+            ),
+            MessengerError::ReplyChannelClosed { request_id } => write!(
+                f,
+                "reply channel closed while waiting for request {}",
+                request_id.as_str()
+            ),
+            MessengerError::Disconnected => write!(f, "not connected to the broker"),
+            MessengerError::Decode(e) => write!(
+                f,
+                "could not decode message from {}: {}",
+                e.routing_key, e.source
+            ),
+        }
+    }
+}
+
+impl MessengerError {
+    /// Whether the error means the connection to the broker is lost, rather
+    /// than a channel or a message being refused.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            MessengerError::Amqp(e) => is_connection_error(e),
+            MessengerError::Pool(PoolError::Backend(e)) => is_connection_error(e),
+            MessengerError::Pool(PoolError::Timeout(_)) => true,
+            MessengerError::Disconnected => true,
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn is_connection_error(error: &lapin::Error) -> bool {
+    match error {
+        lapin::Error::IOError(_) | lapin::Error::InvalidConnectionState(_) => true,
+        lapin::Error::ProtocolError(e) => matches!(e.kind(), AMQPErrorKind::Hard(_)),
+        _ => false,
+    }
+}
+
+impl std::error::Error for MessengerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MessengerError::Amqp(e) => Some(e),
+            MessengerError::Pool(e) => Some(e),
+            MessengerError::Serialization(e) => Some(e.as_ref()),
+            MessengerError::Decode(e) => Some(e.source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<lapin::Error> for MessengerError {
+    fn from(e: lapin::Error) -> Self {
+        MessengerError::Amqp(e)
+    }
+}
+
+impl From<PoolError> for MessengerError {
+    fn from(e: PoolError) -> Self {
+        MessengerError::Pool(e)
+    }
+}
@@ -0,0 +1,192 @@
+use crate::envelope::Envelope;
+use crate::error::MessengerError;
+use domain::Id;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Remembers which messages a consumer already processed, for as long as its
+/// retention allows.
+#[async_trait::async_trait]
+pub trait ProcessedMessages: Send + Sync {
+    async fn contains(&self, consumer: &str, message_id: &Id) -> anyhow::Result<bool>;
+    async fn record(&self, consumer: &str, message_id: &Id) -> anyhow::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct InMemoryProcessedMessages {
+    retention: Duration,
+    processed: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl InMemoryProcessedMessages {
+    pub fn new(retention: Duration) -> InMemoryProcessedMessages {
+        InMemoryProcessedMessages {
+            retention,
+            processed: Default::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ProcessedMessages for InMemoryProcessedMessages {
+    async fn contains(&self, consumer: &str, message_id: &Id) -> anyhow::Result<bool> {
+        let mut processed = self.processed.lock().unwrap();
+        processed.retain(|_, at| at.elapsed() < self.retention);
+        Ok(processed.contains_key(&(String::from(consumer), String::from(message_id.as_str()))))
+    }
+    async fn record(&self, consumer: &str, message_id: &Id) -> anyhow::Result<()> {
+        self.processed.lock().unwrap().insert(
+            (String::from(consumer), String::from(message_id.as_str())),
+            Instant::now(),
+        );
+        Ok(())
+    }
+}
+
+/// Skips the deliveries a consumer already processed. Handlers record the
+/// envelopes they processed themselves, in the same transaction as their
+/// effects when the store has them, so that a crash before the ack cannot
+/// have an envelope processed twice. A failed attempt is not recorded and
+/// can still be retried.
+pub struct IdempotentConsumer<S> {
+    consumer: String,
+    processed: Arc<S>,
+}
+
+impl<S> IdempotentConsumer<S>
+where
+    S: ProcessedMessages + 'static,
+{
+    /// `consumer` identifies the subscription, usually its queue name.
+    pub fn new(consumer: &str, processed: Arc<S>) -> IdempotentConsumer<S> {
+        IdempotentConsumer {
+            consumer: String::from(consumer),
+            processed,
+        }
+    }
+
+    pub fn consumer(&self) -> &str {
+        &self.consumer
+    }
+
+    /// Acks and drops the envelopes already processed by this consumer.
+    /// Errors of the store let the envelope through rather than losing it.
+    pub fn skip_processed<T>(
+        &self,
+        envelopes: BoxStream<'static, Result<Envelope<T>, MessengerError>>,
+    ) -> BoxStream<'static, Result<Envelope<T>, MessengerError>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let consumer = self.consumer.clone();
+        let processed = Arc::clone(&self.processed);
+        envelopes
+            .filter_map(move |envelope| {
+                let consumer = consumer.clone();
+                let processed = Arc::clone(&processed);
+                async move {
+                    let envelope = match envelope {
+                        Ok(envelope) => envelope,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    match processed.contains(&consumer, envelope.id()).await {
+                        Ok(true) => {
+                            tracing::info!(
+                                "{consumer} already processed message {}, skipping it",
+                                envelope.id().as_str()
+                            );
+                            match envelope.ack().await {
+                                Ok(()) => None,
+                                Err(e) => Some(Err(e)),
+                            }
+                        }
+                        Ok(false) => Some(Ok(envelope)),
+                        Err(e) => {
+                            tracing::warn!("could not check if message was processed: {e}");
+                            Some(Ok(envelope))
+                        }
+                    }
+                }
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::MessageBus;
+    use crate::idempotency::{IdempotentConsumer, InMemoryProcessedMessages, ProcessedMessages};
+    use crate::memory::InMemoryBus;
+    use domain::{Id, Metadata, UserCreatedEvent};
+    use futures_util::StreamExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_retention() {
+        let id = Id::default();
+        let kept = InMemoryProcessedMessages::new(Duration::from_secs(60));
+        kept.record("user_ms_CreateUserCommand", &id).await.unwrap();
+        assert!(kept
+            .contains("user_ms_CreateUserCommand", &id)
+            .await
+            .unwrap());
+        assert!(!kept
+            .contains("mailer_CreateUserCommand", &id)
+            .await
+            .unwrap());
+
+        let expired = InMemoryProcessedMessages::new(Duration::ZERO);
+        expired
+            .record("user_ms_CreateUserCommand", &id)
+            .await
+            .unwrap();
+        assert!(!expired
+            .contains("user_ms_CreateUserCommand", &id)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_skip_processed() {
+        let bus = InMemoryBus::new("user_ms");
+        let processed = Arc::new(InMemoryProcessedMessages::new(Duration::from_secs(60)));
+        let idempotent = IdempotentConsumer::new("user_ms_UserCreatedEvent", processed);
+        let mut events = idempotent.skip_processed(
+            bus.subscribe_typed::<UserCreatedEvent>("UserCreatedEvent")
+                .await
+                .unwrap(),
+        );
+        let event = |nickname: &str| UserCreatedEvent {
+            domain_metadata: Metadata::default(),
+            email: String::from("kikoo@lol.com"),
+            nickname: String::from(nickname),
+        };
+
+        let duplicate = bus
+            .publish("UserCreatedEvent", &event("duplicate"))
+            .await
+            .unwrap();
+        idempotent
+            .processed
+            .record(idempotent.consumer(), duplicate.message_id())
+            .await
+            .unwrap();
+        let new = bus
+            .publish("UserCreatedEvent", &event("new"))
+            .await
+            .unwrap();
+
+        // the duplicate is acked without being handed out
+        let envelope = events.next().await.unwrap().unwrap();
+        assert_eq!(new.message_id(), envelope.id());
+        assert_eq!("new", envelope.payload().nickname);
+        assert_eq!(0, bus.ready_count("user_ms_UserCreatedEvent"));
+        assert_eq!(1, bus.unacked_count("user_ms_UserCreatedEvent"));
+        envelope.ack().await.unwrap();
+        assert_eq!(0, bus.unacked_count("user_ms_UserCreatedEvent"));
+    }
+}
@@ -1,10 +1,36 @@
+mod bus;
+mod channels;
+mod config;
+mod envelope;
+mod error;
+mod idempotency;
+mod memory;
+mod message;
 pub mod messages;
 mod messenger;
+mod pool;
+mod publisher;
+mod reconnect;
+mod rpc;
+mod runner;
+mod shutdown;
+mod subscription;
 
+pub use bus::MessageBus;
+pub use config::{MessengerConfig, PoolSettings, PublishPolicy, ReconnectPolicy, TlsConfig};
+pub use envelope::{Envelope, RetryOutcome};
+pub use error::{DecodeError, MessengerError};
+pub use idempotency::{IdempotentConsumer, InMemoryProcessedMessages, ProcessedMessages};
+pub use memory::InMemoryBus;
+pub use message::{Message, MessageOptions};
 pub use messenger::to_message;
-pub use messenger::Message;
 pub use messenger::Messenger;
+pub use publisher::PublishOutcome;
+pub use reconnect::ConnectionState;
+pub use runner::HandlerRunner;
+pub use shutdown::{ShutdownToken, Tracked};
+pub use subscription::{RetryPolicy, SubscribeOptions};
 
-pub use deadpool_lapin::lapin::options::{
+pub use lapin::options::{
     BasicAckOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
 };
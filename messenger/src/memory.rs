@@ -0,0 +1,590 @@
+use crate::bus::MessageBus;
+use crate::envelope::{Acknowledger, Envelope, RetryOutcome};
+use crate::error::MessengerError;
+use crate::message::Message;
+use crate::publisher::PublishOutcome;
+use crate::subscription::{self, RetryPolicy, SubscribeOptions};
+use domain::WithJsonProcessor;
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use lapin::BasicProperties;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const NO_ROUTE: u16 = 312;
+
+/// In-process stand-in for the topic exchange of a `Messenger`, meant for tests.
+///
+/// Buses created with `with_application` share the same broker. Like with
+/// RabbitMQ, each application gets one queue per routing key it subscribes to,
+/// shared by its subscriptions and kept when they are dropped. Unsettled
+/// deliveries of a dropped subscription are redelivered. Retries are scheduled
+/// immediately, the delays of the `RetryPolicy` are not applied.
+#[derive(Debug, Clone)]
+pub struct InMemoryBus {
+    application_name: String,
+    broker: Arc<Mutex<Broker>>,
+}
+
+#[derive(Debug, Default)]
+struct Broker {
+    queues: BTreeMap<String, Queue>,
+    next_tag: u64,
+    next_consumer: u64,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    bindings: BTreeSet<String>,
+    retry: Option<RetryPolicy>,
+    ready: VecDeque<Stored>,
+    unacked: BTreeMap<u64, (u64, Stored)>,
+    notify: Arc<Notify>,
+}
+
+#[derive(Debug, Clone)]
+struct Stored {
+    routing_key: String,
+    redelivered: bool,
+    properties: BasicProperties,
+    data: Vec<u8>,
+}
+
+impl Queue {
+    fn push_back(&mut self, stored: Stored) {
+        self.ready.push_back(stored);
+        self.notify.notify_one();
+    }
+    fn push_front(&mut self, stored: Stored) {
+        self.ready.push_front(stored);
+        self.notify.notify_one();
+    }
+}
+
+impl Broker {
+    fn route(&mut self, routing_key: &str, stored: &Stored) -> usize {
+        let mut routed = 0;
+        for queue in self.queues.values_mut() {
+            // once per queue, even when several of its bindings match
+            let bindings = &queue.bindings;
+            if bindings.iter().any(|b| topic_matches(b, routing_key)) {
+                queue.push_back(stored.clone());
+                routed += 1;
+            }
+        }
+        routed
+    }
+
+    /// Like RabbitMQ, refuses to declare an existing queue with another retry
+    /// policy rather than changing it for every consumer of the queue.
+    fn declare(
+        &mut self,
+        name: &str,
+        binding: Option<&str>,
+        retry: Option<RetryPolicy>,
+    ) -> anyhow::Result<()> {
+        match self.queues.get_mut(name) {
+            Some(queue) if queue.retry != retry => anyhow::bail!(
+                "PRECONDITION_FAILED - queue {name} is declared with retry {:?}, not {retry:?}",
+                queue.retry
+            ),
+            Some(queue) => queue.bindings.extend(binding.map(String::from)),
+            None => {
+                let queue = Queue {
+                    bindings: binding.map(String::from).into_iter().collect(),
+                    retry,
+                    ..Default::default()
+                };
+                self.queues.insert(String::from(name), queue);
+            }
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, name: &str, consumer: u64, prefetch: Option<u16>) -> Option<(u64, Stored)> {
+        let queue = self.queues.get_mut(name)?;
+        if let Some(prefetch) = prefetch {
+            let unacked = queue.unacked.values().filter(|(c, _)| *c == consumer);
+            if unacked.count() >= prefetch as usize {
+                return None;
+            }
+        }
+        let stored = queue.ready.pop_front()?;
+        self.next_tag += 1;
+        queue
+            .unacked
+            .insert(self.next_tag, (consumer, stored.clone()));
+        Some((self.next_tag, stored))
+    }
+
+    fn settle(&mut self, name: &str, tag: u64) -> Option<Stored> {
+        let queue = self.queues.get_mut(name)?;
+        let (_, stored) = queue.unacked.remove(&tag)?;
+        // a consumer may be waiting for room in its prefetch window
+        queue.notify.notify_waiters();
+        Some(stored)
+    }
+
+    fn reject(&mut self, name: &str, tag: u64, requeue: bool) {
+        let stored = match self.settle(name, tag) {
+            Some(stored) => stored,
+            None => return,
+        };
+        let queue = self
+            .queues
+            .get_mut(name)
+            .expect("queue of a delivery exists");
+        if requeue {
+            queue.push_front(Stored {
+                redelivered: true,
+                ..stored
+            });
+        } else if queue.retry.is_some() {
+            let dlq = subscription::dead_letter_queue(name);
+            self.queues.entry(dlq).or_default().push_back(stored);
+        }
+    }
+
+    fn retry_later(&mut self, name: &str, tag: u64, attempt: u32) -> RetryOutcome {
+        let policy = match self.queues.get(name).and_then(|q| q.retry.clone()) {
+            Some(policy) => policy,
+            None => {
+                self.reject(name, tag, true);
+                return RetryOutcome::Requeued;
+            }
+        };
+        if attempt >= policy.max_attempts() {
+            self.reject(name, tag, false);
+            return RetryOutcome::DeadLettered;
+        }
+        if let Some(stored) = self.settle(name, tag) {
+            let headers = subscription::with_attempt(stored.properties.headers(), attempt + 1);
+            let queue = self
+                .queues
+                .get_mut(name)
+                .expect("queue of a delivery exists");
+            queue.push_back(Stored {
+                redelivered: false,
+                properties: stored.properties.with_headers(headers),
+                ..stored
+            });
+        }
+        RetryOutcome::Scheduled {
+            attempt: attempt + 1,
+            delay: policy.delay(attempt),
+        }
+    }
+
+    fn release(&mut self, consumer: u64) {
+        for queue in self.queues.values_mut() {
+            let tags: Vec<u64> = queue
+                .unacked
+                .iter()
+                .filter(|(_, (c, _))| *c == consumer)
+                .map(|(tag, _)| *tag)
+                .collect();
+            for tag in tags.into_iter().rev() {
+                if let Some((_, stored)) = queue.unacked.remove(&tag) {
+                    queue.push_front(Stored {
+                        redelivered: true,
+                        ..stored
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MemoryAcker {
+    broker: Arc<Mutex<Broker>>,
+    queue: String,
+    tag: u64,
+}
+
+impl MemoryAcker {
+    pub(crate) fn ack(&self) {
+        lock(&self.broker).settle(&self.queue, self.tag);
+    }
+    pub(crate) fn reject(&self, requeue: bool) {
+        lock(&self.broker).reject(&self.queue, self.tag, requeue);
+    }
+    pub(crate) fn retry_later(&self, attempt: u32) -> RetryOutcome {
+        lock(&self.broker).retry_later(&self.queue, self.tag, attempt)
+    }
+}
+
+/// Hands the unsettled deliveries of a subscription back to its queue when
+/// the subscription is dropped, like closing its channel would.
+struct ConsumerGuard {
+    broker: Arc<Mutex<Broker>>,
+    consumer: u64,
+}
+
+impl Drop for ConsumerGuard {
+    fn drop(&mut self) {
+        lock(&self.broker).release(self.consumer);
+    }
+}
+
+fn lock(broker: &Mutex<Broker>) -> MutexGuard<'_, Broker> {
+    broker
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl InMemoryBus {
+    pub fn new(application_name: &str) -> InMemoryBus {
+        InMemoryBus {
+            application_name: String::from(application_name),
+            broker: Default::default(),
+        }
+    }
+
+    /// Another application connected to the same broker.
+    pub fn with_application(&self, application_name: &str) -> InMemoryBus {
+        InMemoryBus {
+            application_name: String::from(application_name),
+            broker: Arc::clone(&self.broker),
+        }
+    }
+
+    /// Number of messages waiting in `queue`, e.g. `user_ms_CreateUserCommand.dlq`.
+    pub fn ready_count(&self, queue: &str) -> usize {
+        lock(&self.broker)
+            .queues
+            .get(queue)
+            .map(|q| q.ready.len())
+            .unwrap_or(0)
+    }
+
+    /// Number of messages of `queue` delivered but not settled yet.
+    pub fn unacked_count(&self, queue: &str) -> usize {
+        lock(&self.broker)
+            .queues
+            .get(queue)
+            .map(|q| q.unacked.len())
+            .unwrap_or(0)
+    }
+}
+
+#[async_trait::async_trait]
+impl MessageBus for InMemoryBus {
+    fn application_name(&self) -> &str {
+        &self.application_name
+    }
+
+    async fn publish_message(
+        &self,
+        routing_key: &str,
+        message: &Message,
+    ) -> Result<PublishOutcome, MessengerError> {
+        let stored = Stored {
+            routing_key: String::from(routing_key),
+            redelivered: false,
+            properties: message.amqp_properties(),
+            data: message
+                .to_json()
+                .map_err(MessengerError::Serialization)?
+                .into_bytes(),
+        };
+        if lock(&self.broker).route(routing_key, &stored) == 0 && message.mandatory() {
+            return Err(MessengerError::Returned {
+                message_id: message.id().clone(),
+                reply_code: NO_ROUTE,
+                reply_text: String::from("NO_ROUTE"),
+            });
+        }
+        Ok(PublishOutcome::new(
+            message.id(),
+            "",
+            routing_key,
+            Duration::ZERO,
+        ))
+    }
+
+    async fn subscribe_typed_with_options<T>(
+        &self,
+        routing_key: &str,
+        options: &SubscribeOptions,
+    ) -> anyhow::Result<BoxStream<'static, Result<Envelope<T>, MessengerError>>>
+    where
+        T: for<'a> WithJsonProcessor<'a, Output = T> + Send + 'static,
+    {
+        let queue = options
+            .queue()
+            .clone()
+            .unwrap_or_else(|| subscription::queue_name(&self.application_name, routing_key));
+        let (notify, consumer) = {
+            let mut broker = lock(&self.broker);
+            if options.retry().is_some() {
+                broker.declare(&subscription::dead_letter_queue(&queue), None, None)?;
+            }
+            broker.declare(&queue, Some(routing_key), options.retry().clone())?;
+            broker.next_consumer += 1;
+            let notify = Arc::clone(&broker.queues[&queue].notify);
+            (notify, broker.next_consumer)
+        };
+        let guard = ConsumerGuard {
+            broker: Arc::clone(&self.broker),
+            consumer,
+        };
+        let prefetch = options.prefetch();
+        let deliveries = stream::unfold(guard, move |guard| {
+            let queue = queue.clone();
+            let notify = Arc::clone(&notify);
+            async move {
+                loop {
+                    let notified = notify.notified();
+                    let taken = lock(&guard.broker).take(&queue, guard.consumer, prefetch);
+                    if let Some((tag, stored)) = taken {
+                        let acker = Acknowledger::Memory(MemoryAcker {
+                            broker: Arc::clone(&guard.broker),
+                            queue: queue.clone(),
+                            tag,
+                        });
+                        let envelope = Envelope::decode(
+                            &stored.routing_key,
+                            stored.redelivered,
+                            stored.properties,
+                            stored.data,
+                            acker,
+                        );
+                        return Some((envelope, guard));
+                    }
+                    notified.await;
+                }
+            }
+        });
+        Ok(deliveries.boxed())
+    }
+}
+
+/// Topic exchange matching: `*` stands for exactly one word, `#` for zero or more.
+pub(crate) fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches(pattern: &[&str], key: &[&str]) -> bool {
+        match (pattern.first(), key.first()) {
+            (None, None) => true,
+            (Some(&"#"), _) => {
+                matches(&pattern[1..], key) || (!key.is_empty() && matches(pattern, &key[1..]))
+            }
+            (Some(&"*"), Some(_)) => matches(&pattern[1..], &key[1..]),
+            (Some(word), Some(k)) if word == k => matches(&pattern[1..], &key[1..]),
+            _ => false,
+        }
+    }
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let key: Vec<&str> = routing_key.split('.').collect();
+    matches(&pattern, &key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::MessageBus;
+    use crate::envelope::RetryOutcome;
+    use crate::memory::{topic_matches, InMemoryBus};
+    use crate::message::MessageOptions;
+    use crate::subscription::{RetryPolicy, SubscribeOptions};
+    use crate::MessengerError;
+    use domain::{Metadata, UserCreatedEvent};
+    use futures_util::StreamExt;
+    use std::time::Duration;
+
+    fn event(nickname: &str) -> UserCreatedEvent {
+        UserCreatedEvent {
+            domain_metadata: Metadata::default(),
+            email: String::from("kikoo@lol.com"),
+            nickname: String::from(nickname),
+        }
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("user.created", "user.created"));
+        assert!(!topic_matches("user.created", "user.deleted"));
+        assert!(topic_matches("user.*", "user.created"));
+        assert!(!topic_matches("user.*", "user.created.eu"));
+        assert!(!topic_matches("user.*", "user"));
+        assert!(topic_matches("user.#", "user"));
+        assert!(topic_matches("user.#", "user.created.eu"));
+        assert!(topic_matches("#", "anything.at.all"));
+        assert!(topic_matches("*.created.#", "user.created"));
+        assert!(topic_matches("#.eu", "user.created.eu"));
+        assert!(!topic_matches("#.eu", "user.created.us"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_per_application() {
+        let user_ms = InMemoryBus::new("user_ms");
+        let mailer = user_ms.with_application("mailer");
+        let mut first = user_ms
+            .subscribe_typed::<UserCreatedEvent>("user.*")
+            .await
+            .unwrap();
+        let mut second = user_ms
+            .subscribe_typed::<UserCreatedEvent>("user.*")
+            .await
+            .unwrap();
+        let mut mails = mailer
+            .subscribe_typed::<UserCreatedEvent>("user.#")
+            .await
+            .unwrap();
+
+        mailer.publish("user.created", &event("a")).await.unwrap();
+        mailer.publish("user.created", &event("b")).await.unwrap();
+        // both subscriptions of user_ms compete on the same queue
+        let a = first.next().await.unwrap().unwrap();
+        let b = second.next().await.unwrap().unwrap();
+        assert_eq!("a", a.payload().nickname);
+        assert_eq!("b", b.payload().nickname);
+        assert_eq!("mailer", a.sender());
+        assert_eq!(2, mailer.ready_count("mailer_user.#"));
+        let mail = mails.next().await.unwrap().unwrap();
+        assert_eq!("a", mail.payload().nickname);
+
+        let dropped = mailer.publish("order.created", &event("c")).await;
+        assert!(dropped.is_ok());
+        let mandatory = MessageOptions::default().set_mandatory(true);
+        let unrouted = mailer
+            .publish_with("order.created", &event("c"), mandatory)
+            .await;
+        assert!(matches!(unrouted, Err(MessengerError::Returned { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_ack_and_redelivery() {
+        let bus = InMemoryBus::new("user_ms");
+        let queue = "user_ms_user.created";
+        let mut subscription = bus
+            .subscribe_typed::<UserCreatedEvent>("user.created")
+            .await
+            .unwrap();
+        bus.publish("user.created", &event("a")).await.unwrap();
+        bus.publish("user.created", &event("b")).await.unwrap();
+
+        let a = subscription.next().await.unwrap().unwrap();
+        assert!(!a.redelivered());
+        a.nack(true).await.unwrap();
+        let a = subscription.next().await.unwrap().unwrap();
+        assert_eq!("a", a.payload().nickname);
+        assert!(a.redelivered());
+        a.ack().await.unwrap();
+
+        let b = subscription.next().await.unwrap().unwrap();
+        assert_eq!("b", b.payload().nickname);
+        assert_eq!(1, bus.unacked_count(queue));
+        // the queue outlives its subscription, unsettled deliveries go back to it
+        drop(subscription);
+        assert_eq!(0, bus.unacked_count(queue));
+        assert_eq!(1, bus.ready_count(queue));
+        bus.publish("user.created", &event("c")).await.unwrap();
+
+        let mut subscription = bus
+            .subscribe_typed::<UserCreatedEvent>("user.created")
+            .await
+            .unwrap();
+        let b = subscription.next().await.unwrap().unwrap();
+        assert_eq!("b", b.payload().nickname);
+        assert!(b.redelivered());
+        b.ack().await.unwrap();
+        let c = subscription.next().await.unwrap().unwrap();
+        assert_eq!("c", c.payload().nickname);
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_dead_letter() {
+        let bus = InMemoryBus::new("user_ms");
+        let options =
+            SubscribeOptions::default().set_retry(RetryPolicy::default().set_max_attempts(2));
+        let mut subscription = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &options)
+            .await
+            .unwrap();
+        bus.publish("user.created", &event("a")).await.unwrap();
+
+        let first = subscription.next().await.unwrap().unwrap();
+        assert_eq!(1, first.attempt());
+        assert!(matches!(
+            first.retry_later().await.unwrap(),
+            RetryOutcome::Scheduled { attempt: 2, .. }
+        ));
+        let second = subscription.next().await.unwrap().unwrap();
+        assert_eq!(2, second.attempt());
+        assert_eq!(
+            RetryOutcome::DeadLettered,
+            second.retry_later().await.unwrap()
+        );
+        assert_eq!(1, bus.ready_count("user_ms_user.created.dlq"));
+        assert_eq!(0, bus.ready_count("user_ms_user.created"));
+    }
+
+    #[tokio::test]
+    async fn test_named_queue() {
+        let bus = InMemoryBus::new("user_ms");
+        let options = SubscribeOptions::default()
+            .set_queue("user_ms_user.created.v2")
+            .set_retry(RetryPolicy::default().set_max_attempts(1));
+        let mut subscription = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &options)
+            .await
+            .unwrap();
+        bus.publish("user.created", &event("a")).await.unwrap();
+        assert_eq!(1, bus.ready_count("user_ms_user.created.v2"));
+        assert_eq!(0, bus.ready_count("user_ms_user.created"));
+
+        let a = subscription.next().await.unwrap().unwrap();
+        a.retry_later().await.unwrap();
+        assert_eq!(1, bus.ready_count("user_ms_user.created.v2.dlq"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_bindings() {
+        let bus = InMemoryBus::new("user_ms");
+        let options = SubscribeOptions::default().set_queue("user_ms_users");
+        let mut created = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &options)
+            .await
+            .unwrap();
+        let _all = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.*", &options)
+            .await
+            .unwrap();
+        bus.publish("user.created", &event("a")).await.unwrap();
+        bus.publish("user.updated", &event("b")).await.unwrap();
+
+        assert_eq!(2, bus.ready_count("user_ms_users"));
+        let a = created.next().await.unwrap().unwrap();
+        assert_eq!("a", a.payload().nickname);
+
+        // the queue keeps the retry policy it was declared with
+        let retry = options.set_retry(RetryPolicy::default());
+        assert!(bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &retry)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let bus = InMemoryBus::new("user_ms");
+        let options = SubscribeOptions::default().set_prefetch(2);
+        let mut subscription = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &options)
+            .await
+            .unwrap();
+        for nickname in ["a", "b", "c"] {
+            bus.publish("user.created", &event(nickname)).await.unwrap();
+        }
+
+        let a = subscription.next().await.unwrap().unwrap();
+        let _b = subscription.next().await.unwrap().unwrap();
+        let blocked = tokio::time::timeout(Duration::from_millis(20), subscription.next()).await;
+        assert!(blocked.is_err());
+        assert_eq!(1, bus.ready_count("user_ms_user.created"));
+
+        let (c, _) = tokio::join!(subscription.next(), a.ack());
+        assert_eq!("c", c.unwrap().unwrap().payload().nickname);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::error::MessengerError;
+use domain::{Id, OffsetDateTime, WithJsonProcessor};
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use lapin::BasicProperties;
+use std::collections::BTreeMap;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+const CAUSATION_ID_HEADER: &str = "causation-id";
+const SCHEMA_VERSION_HEADER: &str = "schema-version";
+const PERSISTENT: u8 = 2;
+
+#[derive(
+    PartialOrd, PartialEq, Debug, domain::Serialize, domain::Deserialize, domain::WithJsonProcessor,
+)]
+pub struct Message {
+    id: Id,
+    creation_date: OffsetDateTime,
+    sender: String,
+    #[serde(default)]
+    correlation_id: Option<Id>,
+    #[serde(default)]
+    causation_id: Option<Id>,
+    #[serde(default)]
+    message_type: Option<String>,
+    #[serde(default)]
+    schema_version: Option<u32>,
+    #[serde(default)]
+    content_type: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    mandatory: bool,
+    payload: Vec<u8>,
+}
+
+/// Tracing and typing information attached to a published `Message`.
+///
+/// A message starts a new conversation unless it is `caused_by` another one,
+/// in which case it inherits its correlation id and records it as its cause.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct MessageOptions {
+    correlation_id: Option<Id>,
+    causation_id: Option<Id>,
+    message_type: Option<String>,
+    schema_version: Option<u32>,
+    headers: BTreeMap<String, String>,
+    mandatory: bool,
+}
+
+impl MessageOptions {
+    pub fn caused_by(cause: &Message) -> MessageOptions {
+        MessageOptions {
+            correlation_id: Some(cause.correlation_id().clone()),
+            causation_id: Some(cause.id().clone()),
+            ..Default::default()
+        }
+    }
+    pub fn set_correlation_id(self, correlation_id: &Id) -> Self {
+        MessageOptions {
+            correlation_id: Some(correlation_id.clone()),
+            ..self
+        }
+    }
+    pub fn set_message_type(self, message_type: &str) -> Self {
+        MessageOptions {
+            message_type: Some(String::from(message_type)),
+            ..self
+        }
+    }
+    pub fn set_schema_version(self, schema_version: u32) -> Self {
+        MessageOptions {
+            schema_version: Some(schema_version),
+            ..self
+        }
+    }
+    /// Has the broker return the message as `MessengerError::Returned` when no
+    /// queue is bound to its routing key, instead of dropping it.
+    pub fn set_mandatory(self, mandatory: bool) -> Self {
+        MessageOptions { mandatory, ..self }
+    }
+    pub fn add_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(String::from(key), String::from(value));
+        self
+    }
+}
+
+impl Message {
+    pub(crate) fn new(sender: &str, payload: Vec<u8>) -> Message {
+        Message {
+            creation_date: OffsetDateTime::now_utc(),
+            id: Id::default(),
+            sender: String::from(sender),
+            correlation_id: None,
+            causation_id: None,
+            message_type: None,
+            schema_version: None,
+            content_type: Some(String::from(JSON_CONTENT_TYPE)),
+            headers: BTreeMap::new(),
+            mandatory: false,
+            payload,
+        }
+    }
+
+    pub(crate) fn with_options(
+        sender: &str,
+        message_type: &str,
+        payload: Vec<u8>,
+        options: MessageOptions,
+    ) -> Message {
+        let message = Message::new(sender, payload);
+        Message {
+            correlation_id: options.correlation_id,
+            causation_id: options.causation_id,
+            message_type: options
+                .message_type
+                .or_else(|| Some(String::from(message_type))),
+            schema_version: options.schema_version.or(Some(1)),
+            headers: options.headers,
+            mandatory: options.mandatory,
+            ..message
+        }
+    }
+
+    /// Builds the message `sender` would publish for `payload`, e.g. to store
+    /// it and publish it later with `MessageBus::publish_message`.
+    pub fn from_payload<'a, P>(
+        sender: &str,
+        payload: &P,
+        options: MessageOptions,
+    ) -> Result<Message, MessengerError>
+    where
+        P: WithJsonProcessor<'a>,
+    {
+        let payload = payload.to_json().map_err(MessengerError::Serialization)?;
+        Ok(Message::with_options(
+            sender,
+            &type_name::<P>(),
+            payload.into_bytes(),
+            options,
+        ))
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+    pub fn creation_date(&self) -> OffsetDateTime {
+        self.creation_date
+    }
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+    /// Id shared by every message of a conversation: the id of the message
+    /// that started it.
+    pub fn correlation_id(&self) -> &Id {
+        self.correlation_id.as_ref().unwrap_or(&self.id)
+    }
+    /// Id of the message this one was published in reaction to.
+    pub fn causation_id(&self) -> &Option<Id> {
+        &self.causation_id
+    }
+    pub fn message_type(&self) -> &Option<String> {
+        &self.message_type
+    }
+    pub fn schema_version(&self) -> &Option<u32> {
+        &self.schema_version
+    }
+    pub fn content_type(&self) -> &Option<String> {
+        &self.content_type
+    }
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+    pub fn mandatory(&self) -> bool {
+        self.mandatory
+    }
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+    pub fn payload(&self) -> &Vec<u8> {
+        &self.payload
+    }
+    pub fn payload_as_string(&self) -> String {
+        String::from_utf8_lossy(&self.payload[..]).into_owned()
+    }
+
+    pub(crate) fn amqp_properties(&self) -> BasicProperties {
+        let mut headers = FieldTable::default();
+        for (key, value) in &self.headers {
+            headers.insert(
+                ShortString::from(key.as_str()),
+                AMQPValue::LongString(LongString::from(value.as_str())),
+            );
+        }
+        if let Some(causation_id) = &self.causation_id {
+            headers.insert(
+                ShortString::from(CAUSATION_ID_HEADER),
+                AMQPValue::LongString(LongString::from(causation_id.as_str())),
+            );
+        }
+        if let Some(schema_version) = self.schema_version {
+            headers.insert(
+                ShortString::from(SCHEMA_VERSION_HEADER),
+                AMQPValue::LongLongInt(schema_version as i64),
+            );
+        }
+        let mut properties = BasicProperties::default()
+            .with_message_id(ShortString::from(self.id.as_str()))
+            .with_correlation_id(ShortString::from(self.correlation_id().as_str()))
+            .with_app_id(ShortString::from(self.sender.as_str()))
+            .with_timestamp(self.creation_date.unix_timestamp() as u64)
+            .with_delivery_mode(PERSISTENT)
+            .with_headers(headers);
+        if let Some(message_type) = &self.message_type {
+            properties = properties.with_kind(ShortString::from(message_type.as_str()));
+        }
+        if let Some(content_type) = &self.content_type {
+            properties = properties.with_content_type(ShortString::from(content_type.as_str()));
+        }
+        properties
+    }
+}
+
+/// `UserCreatedEvent` for `domain::command::UserCreatedEvent`, and
+/// `Vec<UserCreatedEvent>` for a `Vec` of them: every path in the name loses
+/// its module.
+pub(crate) fn type_name<T>() -> String {
+    let name = std::any::type_name::<T>();
+    let mut short = String::with_capacity(name.len());
+    let mut start = 0;
+    for (i, c) in name.char_indices() {
+        if matches!(
+            c,
+            '<' | '>' | ',' | ' ' | '(' | ')' | '[' | ']' | ';' | '&' | '*'
+        ) {
+            short.push_str(last_segment(&name[start..i]));
+            short.push(c);
+            start = i + c.len_utf8();
+        }
+    }
+    short.push_str(last_segment(&name[start..]));
+    short
+}
+
+fn last_segment(path: &str) -> &str {
+    path.rsplit("::").next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{type_name, Message, MessageOptions};
+    use domain::{UserCreatedEvent, WithJsonProcessor};
+    use lapin::types::AMQPValue;
+
+    #[test]
+    fn test_message_chain() {
+        let command = Message::with_options(
+            "gateway",
+            "CreateUserCommand",
+            b"{}".to_vec(),
+            MessageOptions::default().add_header("tenant", "acme"),
+        );
+        assert_eq!(command.id(), command.correlation_id());
+        assert_eq!(&None, command.causation_id());
+        assert_eq!(Some("acme"), command.header("tenant"));
+
+        let event = Message::with_options(
+            "user_ms",
+            &type_name::<UserCreatedEvent>(),
+            b"{}".to_vec(),
+            MessageOptions::caused_by(&command),
+        );
+        assert_eq!(command.id(), event.correlation_id());
+        assert_eq!(&Some(command.id().clone()), event.causation_id());
+        assert_eq!(
+            &Some(String::from("UserCreatedEvent")),
+            event.message_type()
+        );
+        assert_eq!(&Some(1), event.schema_version());
+        assert!(!event.mandatory());
+
+        let mandatory = Message::with_options(
+            "user_ms",
+            "UserCreatedEvent",
+            b"{}".to_vec(),
+            MessageOptions::default().set_mandatory(true),
+        );
+        let decoded = Message::from_json(&mandatory.to_json().unwrap()).unwrap();
+        assert!(decoded.mandatory());
+    }
+
+    #[test]
+    fn test_type_name() {
+        assert_eq!("UserCreatedEvent", type_name::<UserCreatedEvent>());
+        assert_eq!(
+            "Vec<UserCreatedEvent>",
+            type_name::<Vec<UserCreatedEvent>>()
+        );
+        assert_eq!(
+            "HashMap<String, (u32, Option<UserCreatedEvent>)>",
+            type_name::<std::collections::HashMap<String, (u32, Option<UserCreatedEvent>)>>()
+        );
+    }
+
+    #[test]
+    fn test_amqp_properties() {
+        let command = Message::new("gateway", b"{}".to_vec());
+        let event = Message::with_options(
+            "user_ms",
+            "UserCreatedEvent",
+            b"{}".to_vec(),
+            MessageOptions::caused_by(&command).set_schema_version(2),
+        );
+        let properties = event.amqp_properties();
+        assert_eq!(
+            Some(event.id().as_str()),
+            properties.message_id().as_ref().map(|id| id.as_str())
+        );
+        assert_eq!(
+            Some(command.id().as_str()),
+            properties.correlation_id().as_ref().map(|id| id.as_str())
+        );
+        assert_eq!(
+            Some("UserCreatedEvent"),
+            properties.kind().as_ref().map(|kind| kind.as_str())
+        );
+        assert_eq!(
+            Some("application/json"),
+            properties.content_type().as_ref().map(|ct| ct.as_str())
+        );
+        let headers = properties.headers().as_ref().unwrap().inner();
+        assert_eq!(
+            Some(&AMQPValue::LongLongInt(2)),
+            headers.get("schema-version")
+        );
+        assert!(headers.contains_key("causation-id"));
+    }
+
+    #[test]
+    fn test_legacy_message_still_decodes() {
+        let message = Message::new("wesh", b"{}".to_vec());
+        let mut json: serde_json::Value =
+            serde_json::from_str(&message.to_json().unwrap()).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.retain(|k, _| ["id", "creation_date", "sender", "payload"].contains(&k.as_str()));
+        let legacy = Message::from_json(&json.to_string()).unwrap();
+        assert_eq!(message.id(), legacy.correlation_id());
+        assert_eq!(&None, legacy.content_type());
+        assert!(legacy.headers().is_empty());
+    }
+}
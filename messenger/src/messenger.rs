@@ -1,166 +1,447 @@
-use deadpool_lapin::lapin::message::Delivery;
-use deadpool_lapin::lapin::options::{
-    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
-};
-use deadpool_lapin::lapin::publisher_confirm::Confirmation;
-use deadpool_lapin::lapin::types::FieldTable;
-
-use deadpool_lapin::lapin::{
-    options::BasicConsumeOptions, options::BasicPublishOptions, BasicProperties, Consumer,
-    ExchangeKind,
-};
-use deadpool_lapin::{Config, CreatePoolError, Pool, Runtime};
-use domain::{Id, OffsetDateTime, WithJsonProcessor};
-
-use std::env::var;
-
-const AMQP_HOST: &str = "AMQP_HOST";
-const AMQP_PORT: &str = "AMQP_PORT";
+use lapin::message::Delivery;
+use lapin::publisher_confirm::PublisherConfirm;
+use lapin::types::ShortString;
+
+use domain::WithJsonProcessor;
+use futures_util::{Future, Stream, StreamExt};
+use lapin::{options::BasicPublishOptions, BasicProperties, Channel};
+
+use crate::channels::ChannelManager;
+use crate::config::MessengerConfig;
+use crate::envelope::Envelope;
+use crate::error::MessengerError;
+use crate::message::{Message, MessageOptions};
+use crate::pool;
+use crate::publisher::{self, PublishOutcome};
+use crate::reconnect::{ConnectionMonitor, ConnectionState, Supervisor};
+use crate::rpc::{self, RpcClient};
+use crate::shutdown::{ShutdownToken, Tracked};
+use crate::subscription::{SubscribeOptions, Subscription};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
 #[derive(Debug)]
 pub struct Messenger {
-    pool: Pool,
+    channels: Arc<ChannelManager>,
+    config: MessengerConfig,
     exchange: String,
     application_name: String,
+    rpc: Mutex<Option<Arc<RpcClient>>>,
+    shutdown: ShutdownToken,
+    monitor: Arc<ConnectionMonitor>,
+    _supervisor: Supervisor,
 }
-#[derive(
-    PartialOrd, PartialEq, Debug, domain::Serialize, domain::Deserialize, domain::WithJsonProcessor,
-)]
-pub struct Message {
-    id: Id,
-    creation_date: OffsetDateTime,
-    sender: String,
-    payload: Vec<u8>,
-}
+impl Messenger {
+    pub async fn new(exchange: &str, application_name: &str) -> anyhow::Result<Messenger> {
+        let config = MessengerConfig::from_env()?;
+        Messenger::with_config(exchange, application_name, config).await
+    }
 
-impl Message {
-    pub fn id(&self) -> &Id {
-        &self.id
+    pub async fn with_config(
+        exchange: &str,
+        application_name: &str,
+        config: MessengerConfig,
+    ) -> anyhow::Result<Messenger> {
+        let monitor = Arc::new(ConnectionMonitor::default());
+        let pool = pool::create_pool(&config, application_name, Arc::clone(&monitor))?;
+        let channels = Arc::new(ChannelManager::new(pool, config.publisher_channels()));
+        channels.declare_exchange(exchange).await?;
+        let supervisor = Supervisor::spawn(
+            Arc::clone(&channels),
+            Arc::clone(&monitor),
+            exchange,
+            config.reconnect().clone(),
+        );
+        Ok(Messenger {
+            channels,
+            config,
+            exchange: String::from(exchange),
+            application_name: String::from(application_name),
+            rpc: Mutex::new(None),
+            shutdown: ShutdownToken::default(),
+            monitor,
+            _supervisor: supervisor,
+        })
     }
-    pub fn creation_date(&self) -> OffsetDateTime {
-        self.creation_date
+
+    pub async fn subscribe(
+        &self,
+        routing_key: &str,
+    ) -> anyhow::Result<impl Stream<Item = Result<Tracked<Delivery>, MessengerError>>> {
+        self.subscribe_with_options(routing_key, &SubscribeOptions::default())
+            .await
     }
-    pub fn sender(&self) -> &str {
-        &self.sender
+
+    /// Raw deliveries of the queue of this application bound to `routing_key`.
+    /// Like typed subscriptions, it resumes after a reconnection and stops on
+    /// shutdown.
+    pub async fn subscribe_with_options(
+        &self,
+        routing_key: &str,
+        options: &SubscribeOptions,
+    ) -> anyhow::Result<impl Stream<Item = Result<Tracked<Delivery>, MessengerError>>> {
+        let deliveries = self.subscription(routing_key, options).deliveries().await?;
+        let shutdown = self.shutdown.clone();
+        // boxed so callers can poll the stream without pinning it
+        Ok(deliveries
+            .take_until(Box::pin(self.shutdown.cancelled()))
+            .map(move |delivery| {
+                delivery.map(|(delivery, _)| Tracked::new(delivery, shutdown.track()))
+            }))
     }
-    pub fn payload(&self) -> &Vec<u8> {
-        &self.payload
+
+    pub async fn subscribe_typed<T>(
+        &self,
+        routing_key: &str,
+    ) -> anyhow::Result<impl Stream<Item = Result<Envelope<T>, MessengerError>>>
+    where
+        T: for<'a> WithJsonProcessor<'a, Output = T>,
+    {
+        self.subscribe_typed_with_options(routing_key, &SubscribeOptions::default())
+            .await
+    }
+
+    pub async fn subscribe_typed_with_options<T>(
+        &self,
+        routing_key: &str,
+        options: &SubscribeOptions,
+    ) -> anyhow::Result<impl Stream<Item = Result<Envelope<T>, MessengerError>>>
+    where
+        T: for<'a> WithJsonProcessor<'a, Output = T>,
+    {
+        let deliveries = self.subscription(routing_key, options).deliveries().await?;
+        let shutdown = self.shutdown.clone();
+        Ok(deliveries
+            .take_until(self.shutdown.cancelled())
+            .map(move |delivery| {
+                let (delivery, retry) = delivery?;
+                Envelope::from_delivery(delivery, retry)
+                    .map(|envelope| envelope.track(shutdown.track()))
+            }))
     }
-    pub fn payload_as_string(&self) -> String {
-        String::from_utf8_lossy(&self.payload[..]).into_owned()
+
+    fn subscription(&self, routing_key: &str, options: &SubscribeOptions) -> Subscription {
+        Subscription::new(
+            Arc::clone(&self.channels),
+            Arc::clone(&self.monitor),
+            &self.exchange,
+            &self.application_name,
+            routing_key,
+            options,
+        )
     }
-}
 
-impl Messenger {
-    pub async fn new(exchange: &str, application_name: &str) -> anyhow::Result<Messenger> {
-        let pool = Messenger::create_pool()?;
-        let _ = Messenger::declare_exchange(&pool, exchange).await?;
-        Ok(Messenger {
-            pool,
-            exchange: String::from(exchange),
-            application_name: String::from(application_name),
-        })
+    pub async fn publish<'a, P>(
+        &self,
+        routing_key: &str,
+        payload: &P,
+    ) -> Result<PublishOutcome, MessengerError>
+    where
+        P: WithJsonProcessor<'a>,
+    {
+        self.publish_with(routing_key, payload, MessageOptions::default())
+            .await
     }
 
-    async fn declare_exchange(pool: &Pool, exchange: &str) -> anyhow::Result<()> {
-        let conn = pool.clone().get().await?;
-        let channel = conn.create_channel().await?;
-        channel
-            .exchange_declare(
-                exchange,
-                ExchangeKind::Topic,
-                ExchangeDeclareOptions {
-                    passive: false,
-                    durable: true,
-                    auto_delete: false,
-                    internal: false,
-                    nowait: false,
-                },
-                FieldTable::default(),
-            )
-            .await?;
-        Ok(())
+    pub async fn publish_with<'a, P>(
+        &self,
+        routing_key: &str,
+        payload: &P,
+        options: MessageOptions,
+    ) -> Result<PublishOutcome, MessengerError>
+    where
+        P: WithJsonProcessor<'a>,
+    {
+        let message = self.to_message(payload, options)?;
+        self.publish_message(routing_key, &message).await
     }
 
-    pub async fn subscribe(&self, routing_key: &str) -> anyhow::Result<Consumer> {
-        let connection = self.pool.clone().get().await?;
-        let channel = connection.create_channel().await?;
-        let q = format!("{}_{routing_key}", self.application_name);
-        let _ = channel
-            .queue_declare(
-                &q,
-                QueueDeclareOptions {
-                    passive: false,
-                    durable: true,
-                    exclusive: false,
-                    auto_delete: false,
-                    nowait: false,
-                },
-                FieldTable::default(),
-            )
-            .await?;
-        let _ = channel
-            .queue_bind(
-                &q,
+    /// Publishes `request` and waits up to `timeout` for the reply of the
+    /// service serving `routing_key`, using RabbitMQ direct reply-to.
+    pub async fn request<'a, Req, Resp>(
+        &self,
+        routing_key: &str,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, MessengerError>
+    where
+        Req: WithJsonProcessor<'a>,
+        Resp: for<'b> WithJsonProcessor<'b, Output = Resp>,
+    {
+        let message = self.to_message(request, MessageOptions::default())?;
+        let request_id = message.id().clone();
+        let properties = message
+            .amqp_properties()
+            .with_reply_to(ShortString::from(rpc::DIRECT_REPLY_TO));
+        let data = message.to_json().map_err(MessengerError::Serialization)?;
+
+        let client = self.rpc_client().await?;
+        let reply = client.register(&request_id);
+        let published = async {
+            let confirm = self
+                .basic_publish(
+                    client.channel(),
+                    &self.exchange,
+                    routing_key,
+                    true,
+                    data.as_bytes(),
+                    properties,
+                )
+                .await?;
+            publisher::await_confirm(
+                confirm,
+                self.config.confirm_timeout(),
+                &request_id,
                 &self.exchange,
                 routing_key,
-                QueueBindOptions::default(),
-                FieldTable::default(),
             )
-            .await?;
-        let consumer = channel
-            .basic_consume(
-                &q,
-                self.application_name.as_str(),
-                BasicConsumeOptions {
-                    no_local: true,
-                    no_ack: false,
-                    exclusive: false,
-                    nowait: false,
-                },
-                FieldTable::default(),
-            )
-            .await?;
-        Ok(consumer)
+            .await
+        }
+        .await;
+        if let Err(e) = published {
+            client.forget(&request_id);
+            return Err(e);
+        }
+        let reply = match tokio::time::timeout(timeout, reply).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => return Err(MessengerError::ReplyChannelClosed { request_id }),
+            Err(_) => {
+                client.forget(&request_id);
+                return Err(MessengerError::RequestTimeout {
+                    request_id,
+                    routing_key: String::from(routing_key),
+                    timeout,
+                });
+            }
+        };
+        rpc::decode_reply(&request_id, &reply)
+    }
+
+    /// Answers the requests sent to `routing_key` with `handler` until the
+    /// subscription ends. A handler error is sent back to the requester as a
+    /// `MessengerError::Remote`.
+    pub async fn serve<Req, Resp, F, Fut>(
+        &self,
+        routing_key: &str,
+        handler: F,
+    ) -> anyhow::Result<()>
+    where
+        Req: for<'a> WithJsonProcessor<'a, Output = Req>,
+        Resp: for<'a> WithJsonProcessor<'a>,
+        F: Fn(Req) -> Fut,
+        Fut: Future<Output = anyhow::Result<Resp>>,
+    {
+        let mut requests = Box::pin(self.subscribe_typed::<Req>(routing_key).await?);
+        while let Some(request) = requests.next().await {
+            let (payload, envelope) = match request {
+                Ok(envelope) => envelope.into_parts(),
+                Err(MessengerError::Decode(e)) => {
+                    tracing::warn!(
+                        "rejecting undecodable request on {routing_key}: {}",
+                        e.source()
+                    );
+                    e.reject(false).await?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let result = handler(payload).await;
+            match envelope.reply_to() {
+                Some(reply_to) => {
+                    let reply = match &result {
+                        Ok(response) => {
+                            self.to_message(response, rpc::reply_options(envelope.message(), false))
+                        }
+                        Err(e) => self.to_message(
+                            &rpc::remote_error(e),
+                            rpc::reply_options(envelope.message(), true),
+                        ),
+                    }?;
+                    // replies to direct reply-to must not be mandatory
+                    if let Err(e) = self.send("", reply_to, &reply, false).await {
+                        tracing::warn!(
+                            "could not reply to request {}: {e}",
+                            envelope.id().as_str()
+                        );
+                    }
+                }
+                None => tracing::warn!(
+                    "request {} on {routing_key} has no reply-to, dropping the reply",
+                    envelope.id().as_str()
+                ),
+            }
+            envelope.ack().await?;
+        }
+        Ok(())
+    }
+
+    fn to_message<'a, P>(
+        &self,
+        payload: &P,
+        options: MessageOptions,
+    ) -> Result<Message, MessengerError>
+    where
+        P: WithJsonProcessor<'a>,
+    {
+        Message::from_payload(&self.application_name, payload, options)
     }
 
-    pub async fn publish<'a>(
+    /// Publishes a message built beforehand, keeping its id.
+    pub async fn publish_message(
         &self,
         routing_key: &str,
-        payload: &impl WithJsonProcessor<'a>,
-    ) -> anyhow::Result<Confirmation> {
-        let connection = self.pool.clone().get().await?;
-        let channel = connection.create_channel().await?;
-        let payload = payload.to_json()?;
-
-        let message = Message {
-            creation_date: OffsetDateTime::now_utc(),
-            id: Id::default(),
-            sender: String::from(&self.application_name),
-            payload: payload.into_bytes(),
+        message: &Message,
+    ) -> Result<PublishOutcome, MessengerError> {
+        self.send(&self.exchange, routing_key, message, message.mandatory())
+            .await
+    }
+
+    async fn send(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        message: &Message,
+        mandatory: bool,
+    ) -> Result<PublishOutcome, MessengerError> {
+        let properties = message.amqp_properties();
+        let data = message.to_json().map_err(MessengerError::Serialization)?;
+        let policy = self.config.publish_policy();
+
+        self.monitor.ready_to_publish(policy).await?;
+        let (idx, channel) = self.observe(self.channels.publisher_channel().await)?;
+        let confirm = match self
+            .basic_publish(
+                &channel,
+                exchange,
+                routing_key,
+                mandatory,
+                data.as_bytes(),
+                properties.clone(),
+            )
+            .await
+        {
+            Ok(confirm) => confirm,
+            Err(e) => {
+                tracing::warn!("publish failed on channel {idx}, retrying on a new one: {e}");
+                self.channels.invalidate_publisher(idx).await;
+                self.monitor.report(&MessengerError::Amqp(e));
+                self.monitor.ready_to_publish(policy).await?;
+                let (_, channel) = self.observe(self.channels.publisher_channel().await)?;
+                self.observe(
+                    self.basic_publish(
+                        &channel,
+                        exchange,
+                        routing_key,
+                        mandatory,
+                        data.as_bytes(),
+                        properties,
+                    )
+                    .await
+                    .map_err(MessengerError::from),
+                )?
+            }
         };
-        let message = message.to_json()?;
+        self.observe(
+            publisher::await_confirm(
+                confirm,
+                self.config.confirm_timeout(),
+                message.id(),
+                exchange,
+                routing_key,
+            )
+            .await,
+        )
+    }
+
+    /// Reports the errors meaning the connection is lost to the monitor.
+    fn observe<T>(&self, result: Result<T, MessengerError>) -> Result<T, MessengerError> {
+        if let Err(e) = &result {
+            self.monitor.report(e);
+        }
+        result
+    }
 
-        let confirmation = channel
+    async fn basic_publish(
+        &self,
+        channel: &Channel,
+        exchange: &str,
+        routing_key: &str,
+        mandatory: bool,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> lapin::Result<PublisherConfirm> {
+        channel
             .basic_publish(
-                &self.exchange,
+                exchange,
                 routing_key,
-                BasicPublishOptions::default(),
-                message.into_bytes(),
-                BasicProperties::default(),
+                BasicPublishOptions {
+                    mandatory,
+                    immediate: false,
+                },
+                payload,
+                properties,
             )
-            .await?
-            .await?;
-        Ok(confirmation)
+            .await
     }
 
-    fn create_pool() -> Result<Pool, CreatePoolError> {
-        let amqp_host = var(AMQP_HOST).unwrap_or_else(|_| String::from("127.0.0.1"));
-        let amqp_port = var(AMQP_PORT).unwrap_or_else(|_| String::from("5672"));
-        let cfg = Config {
-            url: Some(format!("amqp://{amqp_host}:{amqp_port}")),
-            ..Default::default()
-        };
-        cfg.create_pool(Some(Runtime::Tokio1))
+    async fn rpc_client(&self) -> Result<Arc<RpcClient>, MessengerError> {
+        let mut rpc = self.rpc.lock().await;
+        if let Some(client) = rpc.as_ref() {
+            if client.is_connected() {
+                return Ok(Arc::clone(client));
+            }
+            tracing::warn!("reply channel is closed, reopening it");
+        }
+        let channel = self.observe(self.channels.consumer_channel().await)?;
+        let client = Arc::new(RpcClient::start(channel).await?);
+        *rpc = Some(Arc::clone(&client));
+        Ok(client)
+    }
+
+    /// Closes the publisher and consumer channels held by this messenger.
+    pub async fn close(&self) -> Result<(), MessengerError> {
+        self.channels.close().await
+    }
+
+    /// Token cancelling the subscriptions of this messenger, e.g. from a
+    /// signal handler.
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        self.shutdown.clone()
+    }
+
+    /// Stops the subscriptions, waits up to `deadline` for the envelopes
+    /// being handled, then closes channels and connections. Envelopes still
+    /// unsettled are requeued by the broker.
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), MessengerError> {
+        self.shutdown.cancel();
+        if tokio::time::timeout(deadline, self.shutdown.drained())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "{} envelopes still in flight after {:?}, closing anyway",
+                self.shutdown.in_flight(),
+                deadline
+            );
+        }
+        self.monitor.close();
+        if let Some(rpc) = self.rpc.lock().await.take() {
+            rpc.close().await?;
+        }
+        self.channels.shutdown().await
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.monitor.state()
+    }
+
+    pub fn application_name(&self) -> &str {
+        &self.application_name
+    }
+
+    pub fn config(&self) -> &MessengerConfig {
+        &self.config
     }
 }
 
@@ -0,0 +1,118 @@
+use crate::config::MessengerConfig;
+use crate::reconnect::ConnectionMonitor;
+use deadpool::managed::{self, PoolConfig, RecycleError, RecycleResult, Timeouts};
+use deadpool::Runtime;
+use lapin::tcp::{OwnedIdentity, OwnedTLSConfig};
+use lapin::uri::AMQPUri;
+use lapin::{ConnectionProperties, ConnectionState, Error};
+use std::sync::Arc;
+
+pub type Pool = managed::Pool<ConnectionManager>;
+pub type PoolError = managed::PoolError<Error>;
+pub type Connection = managed::Object<ConnectionManager>;
+
+pub struct ConnectionManager {
+    uri: AMQPUri,
+    properties: ConnectionProperties,
+    cert_chain: Option<String>,
+    identity: Option<(Vec<u8>, String)>,
+    monitor: Arc<ConnectionMonitor>,
+}
+
+impl std::fmt::Debug for ConnectionManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionManager")
+            .field("host", &self.uri.authority.host)
+            .field("port", &self.uri.authority.port)
+            .field("vhost", &self.uri.vhost)
+            .finish()
+    }
+}
+
+impl ConnectionManager {
+    fn tls_config(&self) -> OwnedTLSConfig {
+        OwnedTLSConfig {
+            identity: self.identity.as_ref().map(|(der, password)| OwnedIdentity {
+                der: der.clone(),
+                password: password.clone(),
+            }),
+            cert_chain: self.cert_chain.clone(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl managed::Manager for ConnectionManager {
+    type Type = lapin::Connection;
+    type Error = Error;
+
+    async fn create(&self) -> Result<lapin::Connection, Error> {
+        let connection = lapin::Connection::connect_uri_with_config(
+            self.uri.clone(),
+            self.properties.clone(),
+            self.tls_config(),
+        )
+        .await?;
+        let monitor = Arc::clone(&self.monitor);
+        connection.on_error(move |e| monitor.connection_lost(&e));
+        Ok(connection)
+    }
+
+    async fn recycle(&self, conn: &mut lapin::Connection) -> RecycleResult<Error> {
+        match conn.status().state() {
+            ConnectionState::Connected => Ok(()),
+            other_state => Err(RecycleError::Message(format!(
+                "lapin connection is in state: {:?}",
+                other_state
+            ))),
+        }
+    }
+}
+
+pub(crate) fn create_pool(
+    config: &MessengerConfig,
+    application_name: &str,
+    monitor: Arc<ConnectionMonitor>,
+) -> anyhow::Result<Pool> {
+    let connection_name = config
+        .connection_name()
+        .clone()
+        .unwrap_or_else(|| String::from(application_name));
+    let properties = ConnectionProperties::default()
+        .with_connection_name(connection_name.into())
+        .with_executor(tokio_executor_trait::Tokio::current())
+        .with_reactor(tokio_reactor_trait::Tokio);
+    let (cert_chain, identity) = match config.tls_config() {
+        Some(tls_config) => {
+            let tls = tls_config.load()?;
+            (
+                tls.cert_chain,
+                tls.identity
+                    .map(|identity| (identity.der, identity.password)),
+            )
+        }
+        None => (None, None),
+    };
+    let manager = ConnectionManager {
+        uri: config.amqp_uri()?,
+        properties,
+        cert_chain,
+        identity,
+        monitor,
+    };
+    let settings = config.pool();
+    let mut pool_config = PoolConfig::default();
+    if let Some(max_size) = settings.max_size() {
+        pool_config.max_size = max_size;
+    }
+    pool_config.timeouts = Timeouts {
+        wait: settings.wait_timeout(),
+        create: settings.create_timeout(),
+        recycle: settings.recycle_timeout(),
+    };
+    let pool = Pool::builder(manager)
+        .config(pool_config)
+        .runtime(Runtime::Tokio1)
+        .build()?;
+    Ok(pool)
+}
@@ -0,0 +1,129 @@
+use crate::error::MessengerError;
+use domain::Id;
+use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
+use std::time::{Duration, Instant};
+
+/// A message the broker has taken responsibility for: routed to at least one
+/// queue and acked while the channel was in confirm mode.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PublishOutcome {
+    message_id: Id,
+    exchange: String,
+    routing_key: String,
+    confirmed_in: Duration,
+}
+
+impl PublishOutcome {
+    pub(crate) fn new(
+        message_id: &Id,
+        exchange: &str,
+        routing_key: &str,
+        confirmed_in: Duration,
+    ) -> PublishOutcome {
+        PublishOutcome {
+            message_id: message_id.clone(),
+            exchange: String::from(exchange),
+            routing_key: String::from(routing_key),
+            confirmed_in,
+        }
+    }
+    pub fn message_id(&self) -> &Id {
+        &self.message_id
+    }
+    pub fn exchange(&self) -> &str {
+        &self.exchange
+    }
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+    pub fn confirmed_in(&self) -> Duration {
+        self.confirmed_in
+    }
+}
+
+pub(crate) async fn await_confirm(
+    confirm: PublisherConfirm,
+    timeout: Duration,
+    message_id: &Id,
+    exchange: &str,
+    routing_key: &str,
+) -> Result<PublishOutcome, MessengerError> {
+    let started = Instant::now();
+    let confirmation = tokio::time::timeout(timeout, confirm).await.map_err(|_| {
+        MessengerError::ConfirmTimeout {
+            message_id: message_id.clone(),
+            timeout,
+        }
+    })??;
+    to_outcome(
+        confirmation,
+        message_id,
+        exchange,
+        routing_key,
+        started.elapsed(),
+    )
+}
+
+fn to_outcome(
+    confirmation: Confirmation,
+    message_id: &Id,
+    exchange: &str,
+    routing_key: &str,
+    confirmed_in: Duration,
+) -> Result<PublishOutcome, MessengerError> {
+    match confirmation {
+        Confirmation::Ack(None) => Ok(PublishOutcome::new(
+            message_id,
+            exchange,
+            routing_key,
+            confirmed_in,
+        )),
+        Confirmation::Ack(Some(returned)) | Confirmation::Nack(Some(returned)) => {
+            Err(MessengerError::Returned {
+                message_id: message_id.clone(),
+                reply_code: returned.reply_code,
+                reply_text: returned.reply_text.to_string(),
+            })
+        }
+        Confirmation::Nack(None) => Err(MessengerError::Nacked {
+            message_id: message_id.clone(),
+        }),
+        Confirmation::NotRequested => Err(MessengerError::ConfirmNotRequested {
+            message_id: message_id.clone(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MessengerError;
+    use crate::publisher::to_outcome;
+    use domain::Id;
+    use lapin::publisher_confirm::Confirmation;
+    use std::time::Duration;
+
+    #[test]
+    fn test_confirmation_to_outcome() {
+        let id = Id::default();
+        let outcome = to_outcome(
+            Confirmation::Ack(None),
+            &id,
+            "User",
+            "UserCreatedEvent",
+            Duration::from_millis(3),
+        )
+        .unwrap();
+        assert_eq!(&id, outcome.message_id());
+        assert_eq!("User", outcome.exchange());
+        assert_eq!("UserCreatedEvent", outcome.routing_key());
+
+        let nacked = to_outcome(Confirmation::Nack(None), &id, "User", "x", Duration::ZERO);
+        assert!(matches!(nacked, Err(MessengerError::Nacked { .. })));
+        let not_requested =
+            to_outcome(Confirmation::NotRequested, &id, "User", "x", Duration::ZERO);
+        assert!(matches!(
+            not_requested,
+            Err(MessengerError::ConfirmNotRequested { .. })
+        ));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::channels::ChannelManager;
+use crate::config::{PublishPolicy, ReconnectPolicy};
+use crate::error::MessengerError;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ConnectionState {
+    Connected,
+    /// The connection was lost, `attempt` is the reconnection being tried.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// Shut down, or the reconnection attempts are exhausted.
+    Closed,
+}
+
+/// State of the connection to the broker, shared by the publishers and the
+/// subscriptions of a `Messenger`. Whoever notices the connection is gone
+/// reports it here and the `Supervisor` restores it.
+#[derive(Debug)]
+pub(crate) struct ConnectionMonitor {
+    sender: watch::Sender<ConnectionState>,
+    receiver: watch::Receiver<ConnectionState>,
+    transition: Mutex<()>,
+    pending_publishes: AtomicUsize,
+}
+
+impl Default for ConnectionMonitor {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(ConnectionState::Connected);
+        ConnectionMonitor {
+            sender,
+            receiver,
+            transition: Mutex::new(()),
+            pending_publishes: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ConnectionMonitor {
+    pub(crate) fn state(&self) -> ConnectionState {
+        *self.receiver.borrow()
+    }
+
+    fn set(&self, state: ConnectionState) {
+        let _transition = self.transition.lock().unwrap();
+        let current = self.state();
+        if current == state || current == ConnectionState::Closed {
+            return;
+        }
+        match state {
+            ConnectionState::Connected => tracing::info!("connection to the broker restored"),
+            ConnectionState::Reconnecting { attempt } => {
+                tracing::warn!("reconnecting to the broker, attempt {attempt}")
+            }
+            ConnectionState::Closed => tracing::info!("connection to the broker closed"),
+        }
+        let _ = self.sender.send(state);
+    }
+
+    pub(crate) fn connection_lost(&self, reason: &dyn Display) {
+        let _transition = self.transition.lock().unwrap();
+        if self.state() == ConnectionState::Connected {
+            tracing::warn!("lost the connection to the broker: {reason}");
+            let _ = self
+                .sender
+                .send(ConnectionState::Reconnecting { attempt: 1 });
+        }
+    }
+
+    /// Starts a reconnection if `error` means the connection is gone.
+    pub(crate) fn report(&self, error: &MessengerError) {
+        if error.is_connection_error() {
+            self.connection_lost(error);
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.set(ConnectionState::Closed);
+    }
+
+    /// Resolves once connected, fails once closed.
+    pub(crate) async fn connected(&self) -> Result<(), MessengerError> {
+        let mut receiver = self.receiver.clone();
+        loop {
+            let state = *receiver.borrow();
+            match state {
+                ConnectionState::Connected => return Ok(()),
+                ConnectionState::Closed => return Err(MessengerError::Disconnected),
+                ConnectionState::Reconnecting { .. } => {}
+            }
+            if receiver.changed().await.is_err() {
+                return Err(MessengerError::Disconnected);
+            }
+        }
+    }
+
+    /// Lets a publish through according to `policy` while reconnecting.
+    pub(crate) async fn ready_to_publish(
+        &self,
+        policy: &PublishPolicy,
+    ) -> Result<(), MessengerError> {
+        match (self.state(), policy) {
+            (ConnectionState::Connected, _) => Ok(()),
+            (ConnectionState::Closed, _) | (_, PublishPolicy::Fail) => {
+                Err(MessengerError::Disconnected)
+            }
+            (
+                ConnectionState::Reconnecting { .. },
+                PublishPolicy::Buffer {
+                    max_pending,
+                    timeout_ms,
+                },
+            ) => {
+                if self.pending_publishes.fetch_add(1, Ordering::SeqCst) >= *max_pending {
+                    self.pending_publishes.fetch_sub(1, Ordering::SeqCst);
+                    tracing::warn!("{max_pending} publishes already waiting for the broker");
+                    return Err(MessengerError::Disconnected);
+                }
+                let connected =
+                    tokio::time::timeout(Duration::from_millis(*timeout_ms), self.connected())
+                        .await;
+                self.pending_publishes.fetch_sub(1, Ordering::SeqCst);
+                connected.unwrap_or(Err(MessengerError::Disconnected))
+            }
+        }
+    }
+}
+
+/// Restores the connection of a `Messenger` each time it is reported lost:
+/// connects again with backoff and re-declares the exchange. Subscriptions
+/// re-declare their queues and bindings themselves once connected.
+#[derive(Debug)]
+pub(crate) struct Supervisor {
+    monitor: Arc<ConnectionMonitor>,
+    task: JoinHandle<()>,
+}
+
+impl Supervisor {
+    pub(crate) fn spawn(
+        channels: Arc<ChannelManager>,
+        monitor: Arc<ConnectionMonitor>,
+        exchange: &str,
+        policy: ReconnectPolicy,
+    ) -> Supervisor {
+        let task = tokio::spawn(supervise(
+            channels,
+            Arc::clone(&monitor),
+            String::from(exchange),
+            policy,
+        ));
+        Supervisor { monitor, task }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.monitor.close();
+        self.task.abort();
+    }
+}
+
+async fn supervise(
+    channels: Arc<ChannelManager>,
+    monitor: Arc<ConnectionMonitor>,
+    exchange: String,
+    policy: ReconnectPolicy,
+) {
+    let mut state = monitor.receiver.clone();
+    loop {
+        while *state.borrow() == ConnectionState::Connected {
+            if state.changed().await.is_err() {
+                return;
+            }
+        }
+        let mut attempt = 1;
+        loop {
+            if monitor.state() == ConnectionState::Closed {
+                return;
+            }
+            if policy.max_attempts().is_some_and(|max| attempt > max) {
+                tracing::error!(
+                    "could not reconnect to the broker after {} attempts",
+                    attempt - 1
+                );
+                monitor.close();
+                return;
+            }
+            monitor.set(ConnectionState::Reconnecting { attempt });
+            tokio::time::sleep(policy.delay(attempt)).await;
+            match channels.declare_exchange(&exchange).await {
+                Ok(()) => {
+                    monitor.set(ConnectionState::Connected);
+                    break;
+                }
+                Err(e) => tracing::warn!("reconnection attempt {attempt} failed: {e}"),
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::PublishPolicy;
+    use crate::reconnect::{ConnectionMonitor, ConnectionState};
+    use crate::MessengerError;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_publish_policy() {
+        let monitor = Arc::new(ConnectionMonitor::default());
+        let buffer = PublishPolicy::buffer(1, Duration::from_secs(5));
+        monitor
+            .ready_to_publish(&PublishPolicy::Fail)
+            .await
+            .unwrap();
+
+        monitor.connection_lost(&"connection reset by peer");
+        assert_eq!(
+            ConnectionState::Reconnecting { attempt: 1 },
+            monitor.state()
+        );
+        assert!(matches!(
+            monitor.ready_to_publish(&PublishPolicy::Fail).await,
+            Err(MessengerError::Disconnected)
+        ));
+        let waiting = tokio::spawn({
+            let monitor = Arc::clone(&monitor);
+            let buffer = buffer.clone();
+            async move { monitor.ready_to_publish(&buffer).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // the buffer only holds one publish
+        assert!(matches!(
+            monitor.ready_to_publish(&buffer).await,
+            Err(MessengerError::Disconnected)
+        ));
+        monitor.set(ConnectionState::Connected);
+        waiting.await.unwrap().unwrap();
+
+        monitor.close();
+        monitor.set(ConnectionState::Connected);
+        assert_eq!(ConnectionState::Closed, monitor.state());
+        assert!(monitor.connected().await.is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::MessengerError;
+use crate::message::{Message, MessageOptions};
+use domain::{Id, WithJsonProcessor};
+use futures_util::StreamExt;
+use lapin::options::{BasicConsumeOptions, ConfirmSelectOptions};
+use lapin::types::FieldTable;
+use lapin::Channel;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// RabbitMQ pseudo-queue used to receive replies without declaring a queue.
+/// Requests must be published on the channel consuming from it.
+pub(crate) const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+const RPC_STATUS_HEADER: &str = "rpc-status";
+const RPC_ERROR: &str = "error";
+
+#[derive(PartialEq, Debug, domain::Serialize, domain::Deserialize, domain::WithJsonProcessor)]
+pub(crate) struct RemoteError {
+    message: String,
+}
+
+type Pending = Arc<Mutex<HashMap<String, oneshot::Sender<Message>>>>;
+
+/// Channel consuming direct replies, dispatching each one to the request it
+/// answers, matched on the reply's causation id.
+#[derive(Debug)]
+pub(crate) struct RpcClient {
+    channel: Channel,
+    pending: Pending,
+}
+
+impl RpcClient {
+    pub(crate) async fn start(channel: Channel) -> Result<RpcClient, MessengerError> {
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        let mut consumer = channel
+            .basic_consume(
+                DIRECT_REPLY_TO,
+                "",
+                BasicConsumeOptions {
+                    no_local: false,
+                    no_ack: true,
+                    exclusive: false,
+                    nowait: false,
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let pending: Pending = Default::default();
+        let dispatch = Arc::clone(&pending);
+        tokio::spawn(async move {
+            while let Some(delivery) = consumer.next().await {
+                let reply = match delivery
+                    .map_err(anyhow::Error::from)
+                    .and_then(|d| Message::from_json_slice(&d.data[..]))
+                {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        tracing::warn!("dropping unreadable reply: {e}");
+                        continue;
+                    }
+                };
+                let request_id = match reply.causation_id() {
+                    Some(id) => id.as_str().to_string(),
+                    None => continue,
+                };
+                let waiting = dispatch.lock().unwrap().remove(&request_id);
+                match waiting {
+                    Some(tx) => {
+                        let _ = tx.send(reply);
+                    }
+                    None => tracing::debug!("no request waiting for reply to {request_id}"),
+                }
+            }
+            // wake up the requests still waiting, they will never get a reply
+            dispatch.lock().unwrap().clear();
+        });
+        Ok(RpcClient { channel, pending })
+    }
+
+    pub(crate) fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub(crate) async fn close(&self) -> Result<(), MessengerError> {
+        if self.is_connected() {
+            self.channel.close(200, "bye").await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.channel.status().connected()
+    }
+
+    pub(crate) fn register(&self, request_id: &Id) -> oneshot::Receiver<Message> {
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(request_id.as_str().to_string(), tx);
+        rx
+    }
+
+    pub(crate) fn forget(&self, request_id: &Id) {
+        self.pending.lock().unwrap().remove(request_id.as_str());
+    }
+}
+
+/// Options of the reply to `request`, flagged as an error when the handler failed.
+pub(crate) fn reply_options(request: &Message, failed: bool) -> MessageOptions {
+    let options = MessageOptions::caused_by(request);
+    if failed {
+        options.add_header(RPC_STATUS_HEADER, RPC_ERROR)
+    } else {
+        options
+    }
+}
+
+pub(crate) fn remote_error(error: &anyhow::Error) -> RemoteError {
+    RemoteError {
+        message: format!("{error:#}"),
+    }
+}
+
+pub(crate) fn decode_reply<T>(request_id: &Id, reply: &Message) -> Result<T, MessengerError>
+where
+    T: for<'a> WithJsonProcessor<'a, Output = T>,
+{
+    if reply.header(RPC_STATUS_HEADER) == Some(RPC_ERROR) {
+        let error = RemoteError::from_json_slice(&reply.payload()[..])
+            .map_err(MessengerError::Serialization)?;
+        return Err(MessengerError::Remote {
+            request_id: request_id.clone(),
+            message: error.message,
+        });
+    }
+    T::from_json_slice(&reply.payload()[..]).map_err(MessengerError::Serialization)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MessengerError;
+    use crate::message::Message;
+    use crate::rpc::{decode_reply, remote_error, reply_options};
+    use domain::{CreateUserCommand, Metadata, WithJsonProcessor};
+
+    fn command() -> CreateUserCommand {
+        CreateUserCommand {
+            domain_metadata: Metadata::default(),
+            nickname: String::from("nordine"),
+            password: String::from("kikoo"),
+            confirm_password: String::from("kikoo"),
+            email: String::from("kikoo@lol.com"),
+        }
+    }
+
+    #[test]
+    fn test_decode_reply() {
+        let request = Message::new("gateway", b"{}".to_vec());
+        let command = command();
+        let reply = Message::with_options(
+            "user_ms",
+            "CreateUserCommand",
+            command.to_json().unwrap().into_bytes(),
+            reply_options(&request, false),
+        );
+        assert_eq!(&Some(request.id().clone()), reply.causation_id());
+        let decoded = decode_reply::<CreateUserCommand>(request.id(), &reply).unwrap();
+        assert_eq!(command, decoded);
+    }
+
+    #[test]
+    fn test_decode_remote_error() {
+        let request = Message::new("gateway", b"{}".to_vec());
+        let error = remote_error(&anyhow::anyhow!("user not found"));
+        let reply = Message::with_options(
+            "user_ms",
+            "RemoteError",
+            error.to_json().unwrap().into_bytes(),
+            reply_options(&request, true),
+        );
+        match decode_reply::<CreateUserCommand>(request.id(), &reply) {
+            Err(MessengerError::Remote {
+                request_id,
+                message,
+            }) => {
+                assert_eq!(request.id(), &request_id);
+                assert_eq!("user not found", message);
+            }
+            other => panic!("expected a remote error, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use crate::envelope::Envelope;
+use crate::error::MessengerError;
+use futures_util::{future, pin_mut, Stream, StreamExt};
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+type KeyHasher<T> = Arc<dyn Fn(&Envelope<T>) -> u64 + Send + Sync>;
+
+/// Handles the deliveries of a subscription up to `concurrency` at a time.
+///
+/// The prefetch of the subscription bounds how many deliveries the runner can
+/// work on, it should be at least `concurrency`.
+pub struct HandlerRunner<T> {
+    concurrency: usize,
+    key: Option<KeyHasher<T>>,
+}
+
+impl<T> HandlerRunner<T> {
+    pub fn new(concurrency: usize) -> HandlerRunner<T> {
+        HandlerRunner {
+            concurrency: concurrency.max(1),
+            key: None,
+        }
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Handles the envelopes with the same key one after the other, in the
+    /// order they were delivered. Keys are spread over `concurrency` lanes, so
+    /// a slow envelope also holds back the other keys of its lane.
+    pub fn ordered_by<K, F>(self, key: F) -> Self
+    where
+        K: Hash,
+        F: Fn(&Envelope<T>) -> K + Send + Sync + 'static,
+    {
+        let key = move |envelope: &Envelope<T>| {
+            let mut hasher = DefaultHasher::new();
+            key(envelope).hash(&mut hasher);
+            hasher.finish()
+        };
+        HandlerRunner {
+            key: Some(Arc::new(key)),
+            ..self
+        }
+    }
+
+    /// Runs `handler` on every item of `envelopes` until the stream ends.
+    /// Items that could not be decoded have no key and go to any lane.
+    pub async fn run<S, F, Fut>(&self, envelopes: S, handler: F)
+    where
+        S: Stream<Item = Result<Envelope<T>, MessengerError>>,
+        F: Fn(Result<Envelope<T>, MessengerError>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
+                return envelopes
+                    .for_each_concurrent(self.concurrency, handler)
+                    .await
+            }
+        };
+        let (lanes, receivers): (Vec<_>, Vec<_>) =
+            (0..self.concurrency).map(|_| mpsc::channel(1)).unzip();
+        let dispatch = async move {
+            pin_mut!(envelopes);
+            let mut next_lane = 0;
+            while let Some(item) = envelopes.next().await {
+                let lane = match &item {
+                    Ok(envelope) => (key(envelope) % lanes.len() as u64) as usize,
+                    Err(_) => {
+                        next_lane = (next_lane + 1) % lanes.len();
+                        next_lane
+                    }
+                };
+                if lanes[lane].send(item).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let handler = &handler;
+        let workers = future::join_all(receivers.into_iter().map(|mut receiver| async move {
+            while let Some(item) = receiver.recv().await {
+                handler(item).await;
+            }
+        }));
+        future::join(dispatch, workers).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::MessageBus;
+    use crate::memory::InMemoryBus;
+    use crate::runner::HandlerRunner;
+    use crate::subscription::SubscribeOptions;
+    use domain::{Metadata, UserCreatedEvent};
+    use futures_util::StreamExt;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    async fn publish(bus: &InMemoryBus, nickname: &str, email: &str) {
+        let event = UserCreatedEvent {
+            domain_metadata: Metadata::default(),
+            email: String::from(email),
+            nickname: String::from(nickname),
+        };
+        bus.publish("user.created", &event).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent() {
+        let bus = InMemoryBus::new("user_ms");
+        let options = SubscribeOptions::default().set_prefetch(3);
+        let subscription = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &options)
+            .await
+            .unwrap();
+        for i in 0..6 {
+            publish(&bus, "nordine", &format!("{i}@lol.com")).await;
+        }
+
+        let active = AtomicUsize::new(0);
+        let max_active = AtomicUsize::new(0);
+        HandlerRunner::new(3)
+            .run(subscription.take(6), |envelope| async {
+                let envelope = envelope.unwrap();
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                max_active.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                envelope.ack().await.unwrap();
+            })
+            .await;
+        assert_eq!(3, max_active.load(Ordering::SeqCst));
+        assert_eq!(0, bus.unacked_count("user_ms_user.created"));
+    }
+
+    #[tokio::test]
+    async fn test_ordered_by_key() {
+        let bus = InMemoryBus::new("user_ms");
+        let options = SubscribeOptions::default().set_prefetch(8);
+        let subscription = bus
+            .subscribe_typed_with_options::<UserCreatedEvent>("user.created", &options)
+            .await
+            .unwrap();
+        for i in 0..4 {
+            for nickname in ["nordine", "kikoo", "lol"] {
+                publish(&bus, nickname, &i.to_string()).await;
+            }
+        }
+
+        let handled: Mutex<HashMap<String, Vec<String>>> = Default::default();
+        HandlerRunner::<UserCreatedEvent>::new(2)
+            .ordered_by(|envelope| envelope.payload().nickname.clone())
+            .run(subscription.take(12), |envelope| async {
+                let envelope = envelope.unwrap();
+                // the first envelopes of a key take the longest
+                let delay = 4 - envelope.payload().email.parse::<u64>().unwrap();
+                tokio::time::sleep(Duration::from_millis(delay * 5)).await;
+                handled
+                    .lock()
+                    .unwrap()
+                    .entry(envelope.payload().nickname.clone())
+                    .or_default()
+                    .push(envelope.payload().email.clone());
+                envelope.ack().await.unwrap();
+            })
+            .await;
+        let handled = handled.into_inner().unwrap();
+        assert_eq!(3, handled.len());
+        for emails in handled.values() {
+            assert_eq!(&vec!["0", "1", "2", "3"], emails);
+        }
+    }
+}
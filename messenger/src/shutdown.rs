@@ -0,0 +1,158 @@
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Cancellation shared by a `Messenger` and its subscriptions. Once cancelled,
+/// subscriptions stop yielding deliveries; the deliveries already handed out
+/// are tracked until dropped so shutdown can wait for them.
+#[derive(Debug, Clone)]
+pub struct ShutdownToken {
+    cancel: Arc<watch::Sender<bool>>,
+    cancelled: watch::Receiver<bool>,
+    in_flight: Arc<AtomicUsize>,
+    settled: Arc<watch::Sender<()>>,
+    drained: watch::Receiver<()>,
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        let (cancel, cancelled) = watch::channel(false);
+        let (settled, drained) = watch::channel(());
+        ShutdownToken {
+            cancel: Arc::new(cancel),
+            cancelled,
+            in_flight: Default::default(),
+            settled: Arc::new(settled),
+            drained,
+        }
+    }
+}
+
+impl ShutdownToken {
+    pub fn cancel(&self) {
+        let _ = self.cancel.send(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Resolves once `cancel` has been called.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut cancelled = self.cancelled.clone();
+        async move {
+            while !*cancelled.borrow() {
+                if cancelled.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Number of envelopes handed out and not dropped yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once no envelope is in flight.
+    pub async fn drained(&self) {
+        let mut drained = self.drained.clone();
+        while self.in_flight() > 0 {
+            if drained.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn track(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            in_flight: Arc::clone(&self.in_flight),
+            settled: Arc::clone(&self.settled),
+        }
+    }
+}
+
+/// A raw delivery, counted as in flight until dropped.
+#[derive(Debug)]
+pub struct Tracked<T> {
+    inner: T,
+    _in_flight: InFlight,
+}
+
+impl<T> Tracked<T> {
+    pub(crate) fn new(inner: T, in_flight: InFlight) -> Tracked<T> {
+        Tracked {
+            inner,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    in_flight: Arc<AtomicUsize>,
+    settled: Arc<watch::Sender<()>>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _ = self.settled.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::{ShutdownToken, Tracked};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel_and_drain() {
+        let token = ShutdownToken::default();
+        let cancelled = tokio::spawn(token.cancelled());
+        assert!(!token.is_cancelled());
+
+        let first = token.track();
+        let second = token.track();
+        assert_eq!(2, token.in_flight());
+        token.cancel();
+        cancelled.await.unwrap();
+        assert!(token.is_cancelled());
+
+        drop(first);
+        let waiting = tokio::time::timeout(Duration::from_millis(20), token.drained()).await;
+        assert!(waiting.is_err());
+        drop(second);
+        tokio::time::timeout(Duration::from_millis(20), token.drained())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tracked() {
+        let token = ShutdownToken::default();
+        let delivery = Tracked::new(String::from("delivery"), token.track());
+        assert_eq!("delivery", delivery.as_str());
+        assert_eq!(1, token.in_flight());
+        drop(delivery);
+        assert_eq!(0, token.in_flight());
+    }
+}
@@ -0,0 +1,454 @@
+use crate::channels::ChannelManager;
+use crate::envelope::RetryContext;
+use crate::error::MessengerError;
+use crate::reconnect::ConnectionMonitor;
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
+use lapin::message::Delivery;
+use lapin::options::{
+    BasicConsumeOptions, BasicQosOptions, ConfirmSelectOptions, QueueBindOptions,
+    QueueDeclareOptions,
+};
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use lapin::{Channel, ChannelState, Consumer};
+use std::sync::Arc;
+use std::time::Duration;
+
+const ATTEMPT_HEADER: &str = "x-attempt";
+const DEAD_LETTER_EXCHANGE: &str = "x-dead-letter-exchange";
+const DEAD_LETTER_ROUTING_KEY: &str = "x-dead-letter-routing-key";
+const MESSAGE_TTL: &str = "x-message-ttl";
+
+/// Exponential backoff applied between two attempts of a failed delivery.
+///
+/// Attempt `n` (starting at 1) that fails is retried after
+/// `initial_delay * multiplier^(n-1)`, capped at `max_delay`. Once
+/// `max_attempts` deliveries have failed the message goes to the dead-letter queue.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    multiplier: u32,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+    pub fn set_max_attempts(self, max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..self
+        }
+    }
+    pub fn set_initial_delay(self, initial_delay: Duration) -> Self {
+        RetryPolicy {
+            initial_delay,
+            ..self
+        }
+    }
+    pub fn set_multiplier(self, multiplier: u32) -> Self {
+        RetryPolicy {
+            multiplier: multiplier.max(1),
+            ..self
+        }
+    }
+    pub fn set_max_delay(self, max_delay: Duration) -> Self {
+        RetryPolicy { max_delay, ..self }
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SubscribeOptions {
+    retry: Option<RetryPolicy>,
+    prefetch: Option<u16>,
+    queue: Option<String>,
+    dead_letter_policy: bool,
+}
+
+impl SubscribeOptions {
+    pub fn retry(&self) -> &Option<RetryPolicy> {
+        &self.retry
+    }
+    /// Enables the `<queue>.dlq` dead-letter queue and the `<queue>.retry.<n>`
+    /// delay queues for this subscription.
+    pub fn set_retry(self, retry: RetryPolicy) -> Self {
+        SubscribeOptions {
+            retry: Some(retry),
+            ..self
+        }
+    }
+    pub fn prefetch(&self) -> Option<u16> {
+        self.prefetch
+    }
+    /// Maximum number of deliveries of this subscription left unacked at a
+    /// time. Unlimited by default.
+    pub fn set_prefetch(self, prefetch: u16) -> Self {
+        SubscribeOptions {
+            prefetch: Some(prefetch.max(1)),
+            ..self
+        }
+    }
+    pub fn queue(&self) -> &Option<String> {
+        &self.queue
+    }
+    /// Consumes from `queue` instead of `<application>_<routing key>`.
+    pub fn set_queue(self, queue: &str) -> Self {
+        SubscribeOptions {
+            queue: Some(String::from(queue)),
+            ..self
+        }
+    }
+    pub fn dead_letter_policy(&self) -> bool {
+        self.dead_letter_policy
+    }
+    /// Declares the queue without dead-letter arguments, leaving them to a
+    /// broker policy. RabbitMQ refuses to redeclare an existing queue with
+    /// other arguments but applies policies to it, so this is how a queue
+    /// declared before retries were turned on gets its dead-letter queue.
+    pub fn set_dead_letter_policy(self, dead_letter_policy: bool) -> Self {
+        SubscribeOptions {
+            dead_letter_policy,
+            ..self
+        }
+    }
+}
+
+/// Everything a subscription declares on the broker, kept to declare it again
+/// and resume consuming after the connection was restored.
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    channels: Arc<ChannelManager>,
+    monitor: Arc<ConnectionMonitor>,
+    exchange: String,
+    application_name: String,
+    routing_key: String,
+    queue: String,
+    options: SubscribeOptions,
+}
+
+struct Consuming {
+    channel: Channel,
+    consumer: Consumer,
+    retry: Option<Arc<RetryContext>>,
+}
+
+enum ResumeState {
+    Consuming(Box<Consuming>),
+    Lost,
+    Done,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        channels: Arc<ChannelManager>,
+        monitor: Arc<ConnectionMonitor>,
+        exchange: &str,
+        application_name: &str,
+        routing_key: &str,
+        options: &SubscribeOptions,
+    ) -> Subscription {
+        Subscription {
+            channels,
+            monitor,
+            exchange: String::from(exchange),
+            application_name: String::from(application_name),
+            routing_key: String::from(routing_key),
+            queue: options
+                .queue()
+                .clone()
+                .unwrap_or_else(|| queue_name(application_name, routing_key)),
+            options: options.clone(),
+        }
+    }
+
+    /// Declares the queues and bindings of the subscription, then consumes
+    /// on a new channel. Deliveries resume on a new channel whenever the
+    /// consumer is lost, until the channel is closed on purpose or the
+    /// messenger gives up reconnecting.
+    pub(crate) async fn deliveries(
+        self,
+    ) -> Result<
+        BoxStream<'static, Result<(Delivery, Option<Arc<RetryContext>>), MessengerError>>,
+        MessengerError,
+    > {
+        let consuming = self
+            .consume()
+            .await
+            .inspect_err(|e| self.monitor.report(e))?;
+        Ok(stream::unfold(
+            (self, ResumeState::Consuming(Box::new(consuming))),
+            |(subscription, state)| subscription.next_delivery(state),
+        )
+        .boxed())
+    }
+
+    async fn next_delivery(
+        self,
+        mut state: ResumeState,
+    ) -> Option<(
+        Result<(Delivery, Option<Arc<RetryContext>>), MessengerError>,
+        (Subscription, ResumeState),
+    )> {
+        loop {
+            let consuming = match &mut state {
+                ResumeState::Consuming(consuming) => consuming,
+                ResumeState::Lost => match self.resume().await {
+                    Ok(consuming) => {
+                        state = ResumeState::Consuming(Box::new(consuming));
+                        continue;
+                    }
+                    Err(e) => return Some((Err(e), (self, ResumeState::Done))),
+                },
+                ResumeState::Done => return None,
+            };
+            match consuming.consumer.next().await {
+                Some(Ok(delivery)) => {
+                    let retry = consuming.retry.clone();
+                    return Some((Ok((delivery, retry)), (self, state)));
+                }
+                Some(Err(e)) => {
+                    tracing::warn!("consumer on {} failed: {e}", self.queue);
+                    self.monitor.report(&MessengerError::Amqp(e));
+                }
+                None if consuming.channel.status().state() == ChannelState::Closed => {
+                    return None;
+                }
+                None => tracing::warn!("consumer on {} was cancelled", self.queue),
+            }
+            state = ResumeState::Lost;
+        }
+    }
+
+    async fn resume(&self) -> Result<Consuming, MessengerError> {
+        loop {
+            self.monitor.connected().await?;
+            match self.consume().await {
+                Ok(consuming) => {
+                    tracing::info!("resumed consuming from {}", self.queue);
+                    return Ok(consuming);
+                }
+                Err(e) if e.is_connection_error() => self.monitor.report(&e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn consume(&self) -> Result<Consuming, MessengerError> {
+        let channel = self.channels.consumer_channel().await?;
+        if let Some(prefetch) = self.options.prefetch() {
+            channel
+                .basic_qos(prefetch, BasicQosOptions::default())
+                .await?;
+        }
+        let q = &self.queue;
+        let durable = QueueDeclareOptions {
+            passive: false,
+            durable: true,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+        };
+        if let Some(retry) = self.options.retry() {
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await?;
+            channel
+                .queue_declare(&dead_letter_queue(q), durable, FieldTable::default())
+                .await?;
+            for attempt in 1..retry.max_attempts() {
+                channel
+                    .queue_declare(
+                        &retry_queue(q, attempt),
+                        durable,
+                        retry_queue_arguments(q, retry.delay(attempt)),
+                    )
+                    .await?;
+            }
+        }
+        let _ = channel
+            .queue_declare(q, durable, queue_arguments(q, &self.options))
+            .await?;
+        channel
+            .queue_bind(
+                q,
+                &self.exchange,
+                &self.routing_key,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        let consumer = channel
+            .basic_consume(
+                q,
+                &self.application_name,
+                BasicConsumeOptions {
+                    no_local: true,
+                    no_ack: false,
+                    exclusive: false,
+                    nowait: false,
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let retry = self
+            .options
+            .retry()
+            .clone()
+            .map(|policy| Arc::new(RetryContext::new(channel.clone(), q, policy)));
+        Ok(Consuming {
+            channel,
+            consumer,
+            retry,
+        })
+    }
+}
+
+/// Queue of `application_name` bound to `routing_key`, shared by all its instances.
+pub(crate) fn queue_name(application_name: &str, routing_key: &str) -> String {
+    format!("{application_name}_{routing_key}")
+}
+
+pub(crate) fn dead_letter_queue(queue: &str) -> String {
+    format!("{queue}.dlq")
+}
+
+pub(crate) fn retry_queue(queue: &str, attempt: u32) -> String {
+    format!("{queue}.retry.{attempt}")
+}
+
+/// Arguments of the main queue: rejected messages end up in the dead-letter queue.
+pub(crate) fn queue_arguments(queue: &str, options: &SubscribeOptions) -> FieldTable {
+    let mut args = FieldTable::default();
+    if options.retry.is_some() && !options.dead_letter_policy {
+        args.insert(
+            ShortString::from(DEAD_LETTER_EXCHANGE),
+            AMQPValue::LongString(LongString::from("")),
+        );
+        args.insert(
+            ShortString::from(DEAD_LETTER_ROUTING_KEY),
+            AMQPValue::LongString(LongString::from(dead_letter_queue(queue))),
+        );
+    }
+    args
+}
+
+/// Arguments of a delay queue: messages expire back into the main queue.
+pub(crate) fn retry_queue_arguments(queue: &str, delay: Duration) -> FieldTable {
+    let mut args = FieldTable::default();
+    args.insert(
+        ShortString::from(MESSAGE_TTL),
+        AMQPValue::LongLongInt(delay.as_millis() as i64),
+    );
+    args.insert(
+        ShortString::from(DEAD_LETTER_EXCHANGE),
+        AMQPValue::LongString(LongString::from("")),
+    );
+    args.insert(
+        ShortString::from(DEAD_LETTER_ROUTING_KEY),
+        AMQPValue::LongString(LongString::from(queue)),
+    );
+    args
+}
+
+pub(crate) fn attempt_from_headers(headers: &Option<FieldTable>) -> u32 {
+    headers
+        .as_ref()
+        .and_then(|h| h.inner().get(ATTEMPT_HEADER))
+        .and_then(|value| match value {
+            AMQPValue::LongLongInt(v) => Some(*v as u32),
+            AMQPValue::LongInt(v) => Some(*v as u32),
+            AMQPValue::LongUInt(v) => Some(*v),
+            AMQPValue::ShortInt(v) => Some(*v as u32),
+            AMQPValue::ShortUInt(v) => Some(*v as u32),
+            _ => None,
+        })
+        .unwrap_or(1)
+}
+
+pub(crate) fn with_attempt(headers: &Option<FieldTable>, attempt: u32) -> FieldTable {
+    let mut headers = headers.clone().unwrap_or_default();
+    headers.insert(
+        ShortString::from(ATTEMPT_HEADER),
+        AMQPValue::LongLongInt(attempt as i64),
+    );
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subscription::{
+        attempt_from_headers, dead_letter_queue, queue_arguments, retry_queue, with_attempt,
+        RetryPolicy, SubscribeOptions,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default()
+            .set_initial_delay(Duration::from_millis(500))
+            .set_multiplier(3)
+            .set_max_delay(Duration::from_secs(10));
+        assert_eq!(Duration::from_millis(500), policy.delay(1));
+        assert_eq!(Duration::from_millis(1500), policy.delay(2));
+        assert_eq!(Duration::from_millis(4500), policy.delay(3));
+        assert_eq!(Duration::from_secs(10), policy.delay(4));
+        assert_eq!(Duration::from_secs(10), policy.delay(40));
+    }
+
+    #[test]
+    fn test_queue_names() {
+        assert_eq!(
+            "user_ms_CreateUserCommand.dlq",
+            dead_letter_queue("user_ms_CreateUserCommand")
+        );
+        assert_eq!(
+            "user_ms_CreateUserCommand.retry.2",
+            retry_queue("user_ms_CreateUserCommand", 2)
+        );
+    }
+
+    #[test]
+    fn test_queue_arguments() {
+        let queue = "user_ms_CreateUserCommand";
+        let retry = SubscribeOptions::default().set_retry(RetryPolicy::default());
+        let args = queue_arguments(queue, &retry);
+        assert!(args.inner().contains_key("x-dead-letter-exchange"));
+        assert!(queue_arguments(queue, &retry.set_dead_letter_policy(true))
+            .inner()
+            .is_empty());
+        assert!(queue_arguments(queue, &SubscribeOptions::default())
+            .inner()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_attempt_header() {
+        assert_eq!(1, attempt_from_headers(&None));
+        let headers = with_attempt(&None, 3);
+        assert_eq!(3, attempt_from_headers(&Some(headers)));
+    }
+}
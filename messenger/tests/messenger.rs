@@ -6,8 +6,11 @@ mod test {
     use futures_util::StreamExt;
     use messenger::BasicAckOptions;
     use messenger::Message;
+    use messenger::MessageOptions;
     use messenger::Messenger;
+    use messenger::MessengerError;
     use std::sync::Arc;
+    use std::time::Duration;
 
     use std::error::Error;
     #[tokio::test]
@@ -60,6 +63,64 @@ mod test {
         let _ = futures_util::future::join_all(vec![publisher_fut2]).await;
     }
 
+    #[tokio::test]
+    async fn mandatory_publish() {
+        let messenger = Messenger::new("ohaio", "wesh").await.unwrap();
+        let user = create_user();
+        // nobody listens, the event is dropped
+        messenger.publish("NobodyListens", &user).await.unwrap();
+        let res = messenger
+            .publish_with(
+                "NobodyListens",
+                &user,
+                MessageOptions::default().set_mandatory(true),
+            )
+            .await;
+        assert!(matches!(res, Err(MessengerError::Returned { .. })));
+    }
+
+    #[tokio::test]
+    async fn request_reply() {
+        let server = Arc::new(Messenger::new("ohaio", "rpc_server").await.unwrap());
+        let client = Messenger::new("ohaio", "rpc_client").await.unwrap();
+        let _server_fut = tokio::task::spawn(async move {
+            server
+                .serve("GetUser", |command: CreateUserCommand| async move {
+                    if command.nickname == "ghost" {
+                        anyhow::bail!("user not found");
+                    }
+                    Ok(create_user())
+                })
+                .await
+                .unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut command = CreateUserCommand {
+            domain_metadata: Metadata::default(),
+            nickname: String::from("nickk"),
+            password: String::from("kikoo"),
+            confirm_password: String::from("kikoo"),
+            email: String::from("kikoo@lol.com"),
+        };
+        let user: User = client
+            .request("GetUser", &command, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!("nickk", user.nickname());
+
+        command.nickname = String::from("ghost");
+        let res = client
+            .request::<_, User>("GetUser", &command, Duration::from_secs(5))
+            .await;
+        assert!(matches!(res, Err(MessengerError::Remote { .. })));
+
+        let res = client
+            .request::<_, User>("NobodyServesThis", &command, Duration::from_secs(1))
+            .await;
+        assert!(matches!(res, Err(MessengerError::Returned { .. })));
+    }
+
     fn create_user() -> User {
         let profile = Profile::new(
             Some(Default::default()),
@@ -85,14 +146,13 @@ mod test {
         let mut consumer = messenger.subscribe(&routing_key).await?;
         let mut count = 0;
         while let Some(msg) = consumer.next().await {
-            let (channel, delivery) = msg.expect("error in consumer");
+            let delivery = msg.expect("error in consumer");
             let msg = messenger::to_message(&delivery)?;
-            let _ = on_message(msg).await?;
-            let _ = delivery.ack(BasicAckOptions::default()).await?;
+            on_message(msg).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
             count += 1;
             if let Some(nb_message_before_close) = close_after_message {
                 if count == nb_message_before_close {
-                    channel.close(100, "BYE").await?;
                     return Ok(());
                 }
             }
@@ -0,0 +1,89 @@
+#[cfg(test)]
+mod test {
+    use domain::{CreateUserCommand, Metadata, WithJsonProcessor};
+    use lapin::options::BasicPublishOptions;
+    use lapin::{BasicProperties, Connection, ConnectionProperties};
+    use messenger::{Messenger, MessengerConfig};
+    use std::time::{Duration, Instant};
+
+    const EXCHANGE: &str = "bench";
+    const ROUTING_KEY: &str = "bench.publish";
+    const MESSAGES: usize = 2000;
+
+    fn command() -> CreateUserCommand {
+        CreateUserCommand {
+            domain_metadata: Metadata::default(),
+            nickname: String::from("nordine"),
+            password: String::from("kikoo"),
+            confirm_password: String::from("kikoo"),
+            email: String::from("kikoo@lol.com"),
+        }
+    }
+
+    fn report(label: &str, elapsed: Duration) -> f64 {
+        let rate = MESSAGES as f64 / elapsed.as_secs_f64();
+        println!("{label}: {MESSAGES} messages in {elapsed:?} ({rate:.0} msg/s)");
+        rate
+    }
+
+    // what publish used to do: a fresh channel for every message
+    async fn channel_per_publish() -> Duration {
+        let uri = MessengerConfig::from_env().unwrap().amqp_uri().unwrap();
+        let connection = Connection::connect_uri(
+            uri,
+            ConnectionProperties::default()
+                .with_executor(tokio_executor_trait::Tokio::current())
+                .with_reactor(tokio_reactor_trait::Tokio),
+        )
+        .await
+        .unwrap();
+        let payload = command().to_json().unwrap();
+        let started = Instant::now();
+        for _ in 0..MESSAGES {
+            let channel = connection.create_channel().await.unwrap();
+            channel
+                .basic_publish(
+                    EXCHANGE,
+                    ROUTING_KEY,
+                    BasicPublishOptions::default(),
+                    payload.as_bytes(),
+                    BasicProperties::default(),
+                )
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+        }
+        let elapsed = started.elapsed();
+        connection.close(200, "bye").await.unwrap();
+        elapsed
+    }
+
+    async fn reused_publisher_channel() -> Duration {
+        let messenger = Messenger::new(EXCHANGE, "bench").await.unwrap();
+        let command = command();
+        let started = Instant::now();
+        for _ in 0..MESSAGES {
+            messenger.publish(ROUTING_KEY, &command).await.unwrap();
+        }
+        let elapsed = started.elapsed();
+        messenger.close().await.unwrap();
+        elapsed
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark, needs a running broker: cargo test -p messenger --test publish_throughput -- --ignored --nocapture"]
+    async fn publish_throughput() {
+        let messenger = Messenger::new(EXCHANGE, "bench").await.unwrap();
+        // routed to a queue, as the messages of a real application would be
+        let _consumer = messenger.subscribe(ROUTING_KEY).await.unwrap();
+
+        let before = report("channel per publish", channel_per_publish().await);
+        let after = report(
+            "long-lived confirm channel",
+            reused_publisher_channel().await,
+        );
+        println!("speedup: {:.2}x", after / before);
+        messenger.close().await.unwrap();
+    }
+}
@@ -1,18 +1,26 @@
 use core::panic;
-use domain::{
-    CreateUserCommand, Id, Metadata, Profile, UserCreatedEvent, WithJsonProcessor, WithMetadata,
-};
+use domain::{CreateUserCommand, Id, Metadata, UserCreatedEvent};
 use futures_util::StreamExt;
-use messenger::Messenger;
+use messenger::{
+    Envelope, HandlerRunner, IdempotentConsumer, Message, MessageOptions, Messenger,
+    MessengerError, ProcessedMessages, RetryPolicy, SubscribeOptions,
+};
+use outbox::{MongoProcessedMessages, Outbox, OutboxEntry, OutboxRelay};
+use std::env::var;
 use std::sync::Arc;
-use store::{MongoRepository, Repository, StoreClient};
+use std::time::Duration;
+use store::{MongoRepository, Repository, StoreClient, StoreError};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
+use user::{User, APP_NAME, USER_COLLECTION};
 
-const APP_NAME: &str = "user_ms";
-const USER_COLLECTION: &str = "user";
-const USER_CREATED_DEFAULT_ROLE: &str = "USER";
+const PROCESSED_MESSAGES_RETENTION_SECS: &str = "PROCESSED_MESSAGES_RETENTION_SECS";
+const CREATE_USER_CONCURRENCY: usize = 8;
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+const DEFAULT_PROCESSED_MESSAGES_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
 
 #[tokio::main]
 async fn main() {
@@ -40,12 +48,46 @@ async fn run(messenger: Messenger, store_client: StoreClient) {
     let messenger = Arc::new(messenger);
     let store_client = Arc::new(store_client);
 
-    let handles = vec![spawn_on_create_user_command(
-        Arc::clone(&messenger),
-        Arc::clone(&store_client),
-    )];
+    let handles = vec![
+        spawn_on_create_user_command(Arc::clone(&messenger), Arc::clone(&store_client)),
+        spawn_outbox_relay(Arc::clone(&messenger), Arc::clone(&store_client)),
+    ];
+
+    let mut tasks = futures_util::future::join_all(handles);
+    let stopped = tokio::select! {
+        signal = shutdown_signal() => {
+            match signal {
+                Ok(signal) => tracing::info!("received {signal}, shutting down"),
+                Err(e) => tracing::error!("could not listen for shutdown signals: {e}"),
+            }
+            false
+        }
+        _ = &mut tasks => {
+            tracing::warn!("all tasks stopped");
+            true
+        }
+    };
+    let deadline = Instant::now() + SHUTDOWN_DEADLINE;
+    if !stopped {
+        // lets the handlers and the relay finish before the messenger closes
+        messenger.shutdown_token().cancel();
+        if tokio::time::timeout_at(deadline, tasks).await.is_err() {
+            tracing::warn!("tasks still running after {SHUTDOWN_DEADLINE:?}, stopping anyway");
+        }
+    }
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    match messenger.shutdown(remaining).await {
+        Ok(()) => tracing::info!("{APP_NAME} stopped"),
+        Err(e) => tracing::error!("could not shut down messenger cleanly: {e}"),
+    }
+}
 
-    let _res = futures_util::future::join_all(handles).await;
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res.map(|_| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
 }
 fn spawn_on_create_user_command(
     messenger: Arc<Messenger>,
@@ -54,76 +96,410 @@ fn spawn_on_create_user_command(
     tokio::task::spawn(async move { on_create_user_command(messenger, store_client).await })
 }
 
+fn spawn_outbox_relay(
+    messenger: Arc<Messenger>,
+    store_client: Arc<StoreClient>,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::task::spawn(async move {
+        let shutdown = messenger.shutdown_token();
+        OutboxRelay::new(Outbox::new(&store_client), messenger)
+            .run(&shutdown)
+            .await;
+        Ok(())
+    })
+}
+
 #[tracing::instrument(skip_all)]
 async fn on_create_user_command(
     messenger: Arc<Messenger>,
     store_client: Arc<StoreClient>,
 ) -> anyhow::Result<()> {
-    let db = store_client.get_db();
-    let collection = db.collection::<User>(USER_COLLECTION);
-    let repository = MongoRepository::new(collection);
-    let mut consumer = messenger
-        .subscribe(messenger::messages::CREATE_USER_COMMAND)
-        .await?;
-
-    while let Some(msg) = consumer.next().await {
-        let (_channel, delivery) = msg?;
-        let msg = messenger::to_message(&delivery)?;
-        tracing::info!(
-            "received create user command from {} at {}",
-            msg.sender(),
-            msg.creation_date()
-        );
-        let payload = CreateUserCommand::from_json_slice(&msg.payload()[..]);
-        match payload {
-            Err(msg) => tracing::error!("payload could not be parsed: {}", msg),
-            Ok(payload) => {
-                let user = User::from_create_command(payload);
-                let _ = repository.insert_one(&user).await?;
-                let email = String::from(user.profile.email_address());
-                let confirmation = messenger
-                    .publish(
-                        messenger::messages::USER_CREATED_EVENT,
-                        &UserCreatedEvent {
-                            domain_metadata: Metadata::new_with_default(&user.id),
-                            email,
-                            nickname: user.nickname,
-                        },
-                    )
-                    .await?;
-                tracing::info!("confirmation: {}", confirmation.is_ack());
+    let repository =
+        MongoRepository::new(store_client.get_db().collection::<User>(USER_COLLECTION))
+            .set_indexes(User::indexes());
+    repository.ensure_indexes().await?;
+    let users = MongoUsers {
+        repository,
+        outbox: Outbox::new(&store_client),
+    };
+    let retention = var(PROCESSED_MESSAGES_RETENTION_SECS)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PROCESSED_MESSAGES_RETENTION);
+    let processed = MongoProcessedMessages::new(&store_client, retention).await?;
+    let idempotent = IdempotentConsumer::new(
+        &format!("{APP_NAME}_{}", messenger::messages::CREATE_USER_COMMAND),
+        Arc::new(processed),
+    );
+    let options = SubscribeOptions::default()
+        // the queue predates retries, its dead-letter queue comes from a policy:
+        // rabbitmqctl set_policy --apply-to queues user_ms_dlx '^user_ms_CreateUserCommand$' \
+        //   '{"dead-letter-exchange":"","dead-letter-routing-key":"user_ms_CreateUserCommand.dlq"}'
+        .set_dead_letter_policy(true)
+        .set_retry(RetryPolicy::default())
+        .set_prefetch(2 * CREATE_USER_CONCURRENCY as u16);
+    let consumer = idempotent.skip_processed(
+        messenger
+            .subscribe_typed_with_options::<CreateUserCommand>(
+                messenger::messages::CREATE_USER_COMMAND,
+                &options,
+            )
+            .await?
+            .boxed(),
+    );
+
+    // commands for the same email are handled one at a time so they never
+    // race on the same user
+    HandlerRunner::new(CREATE_USER_CONCURRENCY)
+        .ordered_by(|envelope: &Envelope<CreateUserCommand>| envelope.payload().email.clone())
+        .run(consumer, |envelope| async {
+            if let Err(e) = handle_create_user_command(&users, &idempotent, envelope).await {
+                tracing::error!("could not settle create user command: {e}");
             }
-        };
+        })
+        .await;
+    Ok(())
+}
 
-        let _ = delivery.ack(messenger::BasicAckOptions::default()).await?;
+async fn handle_create_user_command<U, P>(
+    users: &U,
+    idempotent: &IdempotentConsumer<P>,
+    envelope: Result<Envelope<CreateUserCommand>, MessengerError>,
+) -> anyhow::Result<()>
+where
+    U: UserStore,
+    P: ProcessedMessages + 'static,
+{
+    let envelope = match envelope {
+        Ok(envelope) => envelope,
+        Err(MessengerError::Decode(e)) => {
+            tracing::error!("payload could not be parsed: {}", e.source());
+            e.reject(false).await?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    tracing::info!(
+        "received create user command from {} at {} (attempt {})",
+        envelope.sender(),
+        envelope.creation_date(),
+        envelope.attempt()
+    );
+    let created = create_user(
+        users,
+        idempotent.consumer(),
+        envelope.message(),
+        envelope.payload(),
+    );
+    match created.await {
+        Ok(()) => envelope.ack().await?,
+        // retrying cannot help, the nickname or email is taken
+        Err(e) if matches!(e.downcast_ref(), Some(StoreError::DuplicateKey { .. })) => {
+            tracing::warn!("user not created, dead-lettering the command: {e}");
+            envelope.reject(false).await?;
+        }
+        Err(e) => {
+            tracing::error!("could not create user: {e}");
+            let outcome = envelope.retry_later().await?;
+            tracing::warn!("create user command handed back: {:?}", outcome);
+        }
     }
     Ok(())
 }
-#[derive(
-    Debug, Default, WithJsonProcessor, WithMetadata, domain::Serialize, domain::Deserialize,
-)]
-struct User {
-    #[serde(rename = "_id")]
-    id: Id,
-    #[serde(rename = "metadata")]
-    domain_metadata: Metadata,
-    nickname: String,
-    password: String,
-    profile: Profile,
-    roles: Vec<String>,
+
+/// Saves the user together with its `UserCreatedEvent`, which the outbox
+/// relay publishes once the transaction is committed, and the command as
+/// processed by `consumer`.
+async fn create_user<U>(
+    users: &U,
+    consumer: &str,
+    cause: &Message,
+    command: &CreateUserCommand,
+) -> anyhow::Result<()>
+where
+    U: UserStore,
+{
+    let user = User::from_create_command(command);
+    let event = OutboxEntry::new(
+        APP_NAME,
+        messenger::messages::USER_CREATED_EVENT,
+        &UserCreatedEvent {
+            domain_metadata: Metadata::new_with_default(&user.id),
+            email: String::from(user.profile.email_address()),
+            nickname: user.nickname.clone(),
+        },
+        MessageOptions::caused_by(cause),
+    )?;
+    users.insert(&user, &[event], consumer, cause.id()).await?;
+    tracing::info!(
+        "user {} created (correlation {})",
+        user.id.as_str(),
+        cause.correlation_id().as_str()
+    );
+    Ok(())
+}
+
+#[async_trait::async_trait]
+trait UserStore: Send + Sync {
+    async fn insert(
+        &self,
+        user: &User,
+        events: &[OutboxEntry],
+        consumer: &str,
+        message_id: &Id,
+    ) -> Result<(), StoreError>;
+}
+
+struct MongoUsers {
+    repository: MongoRepository<User>,
+    outbox: Outbox,
 }
 
-impl User {
-    fn from_create_command(command: CreateUserCommand) -> User {
-        let user_id = Id::default();
-        let metadata = Metadata::new_with_default(&user_id);
-        User {
-            profile: Profile::new_with_default(&command.email),
-            id: user_id,
-            domain_metadata: metadata,
-            nickname: command.nickname,
-            password: command.password,
-            roles: vec![USER_CREATED_DEFAULT_ROLE.to_string()],
+#[async_trait::async_trait]
+impl UserStore for MongoUsers {
+    async fn insert(
+        &self,
+        user: &User,
+        events: &[OutboxEntry],
+        consumer: &str,
+        message_id: &Id,
+    ) -> Result<(), StoreError> {
+        self.outbox
+            .save_processed(&self.repository, user, events, consumer, message_id)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{handle_create_user_command, UserStore};
+    use domain::{CreateUserCommand, Id, Metadata, Profile};
+    use futures_util::StreamExt;
+    use messenger::messages::{CREATE_USER_COMMAND, USER_CREATED_EVENT};
+    use messenger::{
+        IdempotentConsumer, InMemoryBus, InMemoryProcessedMessages, Message, MessageBus,
+        MessageOptions, ProcessedMessages, RetryPolicy, SubscribeOptions,
+    };
+    use outbox::OutboxEntry;
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use store::{Filter, InMemoryRepository, Repository, StoreError};
+    use user::{User, APP_NAME};
+
+    struct Users {
+        saved: Mutex<Vec<(String, Vec<OutboxEntry>)>>,
+        processed: Arc<InMemoryProcessedMessages>,
+        unavailable: bool,
+        taken: bool,
+    }
+
+    impl Default for Users {
+        fn default() -> Self {
+            Users {
+                saved: Default::default(),
+                processed: Arc::new(InMemoryProcessedMessages::new(Duration::from_secs(60))),
+                unavailable: false,
+                taken: false,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl UserStore for Users {
+        async fn insert(
+            &self,
+            user: &User,
+            events: &[OutboxEntry],
+            consumer: &str,
+            message_id: &Id,
+        ) -> Result<(), StoreError> {
+            if self.unavailable {
+                return Err(StoreError::Io(ErrorKind::ConnectionRefused.into()));
+            }
+            if self.taken {
+                return Err(StoreError::DuplicateKey {
+                    index: Some(String::from("nickname_1")),
+                    source: None,
+                });
+            }
+            self.saved
+                .lock()
+                .unwrap()
+                .push((user.nickname.clone(), events.to_vec()));
+            self.processed.record(consumer, message_id).await.unwrap();
+            Ok(())
+        }
+    }
+
+    fn idempotent(users: &Users) -> IdempotentConsumer<InMemoryProcessedMessages> {
+        IdempotentConsumer::new("user_ms_CreateUserCommand", Arc::clone(&users.processed))
+    }
+
+    fn command() -> CreateUserCommand {
+        CreateUserCommand {
+            domain_metadata: Metadata::default(),
+            nickname: String::from("nordine"),
+            password: String::from("kikoo"),
+            confirm_password: String::from("kikoo"),
+            email: String::from("kikoo@lol.com"),
         }
     }
+
+    #[tokio::test]
+    async fn test_create_user() {
+        let bus = InMemoryBus::new(APP_NAME);
+        let gateway = bus.with_application("gateway");
+        let users = Users::default();
+        let idempotent = idempotent(&users);
+        let options = SubscribeOptions::default().set_retry(RetryPolicy::default());
+        let mut commands = bus
+            .subscribe_typed_with_options::<CreateUserCommand>(CREATE_USER_COMMAND, &options)
+            .await
+            .unwrap();
+
+        let sent = gateway
+            .publish(CREATE_USER_COMMAND, &command())
+            .await
+            .unwrap();
+        let envelope = commands.next().await.unwrap();
+        handle_create_user_command(&users, &idempotent, envelope)
+            .await
+            .unwrap();
+
+        let saved = users.saved.lock().unwrap();
+        let (nickname, events) = &saved[0];
+        assert_eq!("nordine", nickname);
+        assert_eq!(1, events.len());
+        assert_eq!(USER_CREATED_EVENT, events[0].routing_key());
+        let event = events[0].message().unwrap();
+        assert_eq!(&Some(sent.message_id().clone()), event.causation_id());
+        assert_eq!(0, bus.unacked_count("user_ms_CreateUserCommand"));
+    }
+
+    #[tokio::test]
+    async fn test_create_user_retried_then_dead_lettered() {
+        let bus = InMemoryBus::new(APP_NAME);
+        let users = Users {
+            unavailable: true,
+            ..Default::default()
+        };
+        let idempotent = idempotent(&users);
+        let options =
+            SubscribeOptions::default().set_retry(RetryPolicy::default().set_max_attempts(2));
+        let mut commands = bus
+            .subscribe_typed_with_options::<CreateUserCommand>(CREATE_USER_COMMAND, &options)
+            .await
+            .unwrap();
+
+        bus.publish(CREATE_USER_COMMAND, &command()).await.unwrap();
+        for attempt in 1..=2 {
+            let envelope = commands.next().await.unwrap();
+            assert_eq!(attempt, envelope.as_ref().unwrap().attempt());
+            handle_create_user_command(&users, &idempotent, envelope)
+                .await
+                .unwrap();
+        }
+        assert_eq!(1, bus.ready_count("user_ms_CreateUserCommand.dlq"));
+        assert_eq!(0, bus.ready_count("user_ms_CreateUserCommand"));
+    }
+
+    #[tokio::test]
+    async fn test_taken_nickname_not_retried() {
+        let bus = InMemoryBus::new(APP_NAME);
+        let users = Users {
+            taken: true,
+            ..Default::default()
+        };
+        let idempotent = idempotent(&users);
+        let options = SubscribeOptions::default().set_retry(RetryPolicy::default());
+        let mut commands = bus
+            .subscribe_typed_with_options::<CreateUserCommand>(CREATE_USER_COMMAND, &options)
+            .await
+            .unwrap();
+
+        bus.publish(CREATE_USER_COMMAND, &command()).await.unwrap();
+        let envelope = commands.next().await.unwrap();
+        handle_create_user_command(&users, &idempotent, envelope)
+            .await
+            .unwrap();
+        assert_eq!(1, bus.ready_count("user_ms_CreateUserCommand.dlq"));
+        assert_eq!(0, bus.ready_count("user_ms_CreateUserCommand"));
+        assert_eq!(0, bus.unacked_count("user_ms_CreateUserCommand"));
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_command_processed_once() {
+        let bus = InMemoryBus::new(APP_NAME);
+        let gateway = bus.with_application("gateway");
+        let users = Users::default();
+        let idempotent = idempotent(&users);
+        let mut commands = idempotent.skip_processed(
+            bus.subscribe_typed::<CreateUserCommand>(CREATE_USER_COMMAND)
+                .await
+                .unwrap(),
+        );
+
+        let first =
+            Message::from_payload("gateway", &command(), MessageOptions::default()).unwrap();
+        gateway
+            .publish_message(CREATE_USER_COMMAND, &first)
+            .await
+            .unwrap();
+        let envelope = commands.next().await.unwrap();
+        handle_create_user_command(&users, &idempotent, envelope)
+            .await
+            .unwrap();
+
+        // same message published again, then another command
+        gateway
+            .publish_message(CREATE_USER_COMMAND, &first)
+            .await
+            .unwrap();
+        let other =
+            Message::from_payload("gateway", &command(), MessageOptions::default()).unwrap();
+        gateway
+            .publish_message(CREATE_USER_COMMAND, &other)
+            .await
+            .unwrap();
+        let envelope = commands.next().await.unwrap();
+        assert_eq!(other.id(), envelope.as_ref().unwrap().id());
+        handle_create_user_command(&users, &idempotent, envelope)
+            .await
+            .unwrap();
+
+        assert_eq!(2, users.saved.lock().unwrap().len());
+        assert_eq!(0, bus.ready_count("user_ms_CreateUserCommand"));
+        assert_eq!(0, bus.unacked_count("user_ms_CreateUserCommand"));
+    }
+
+    #[tokio::test]
+    async fn test_user_persistence() {
+        let users = InMemoryRepository::new().set_indexes(User::indexes());
+        users.ensure_indexes().await.unwrap();
+        let user = User::from_create_command(&command());
+        users.insert_one(&user).await.unwrap();
+
+        let email = User::PROFILE.join(&Profile::EMAIL_ADDRESS);
+        let found = users
+            .find_one_by(Filter::eq(&email, "kikoo@lol.com"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, found.id);
+        assert_eq!(vec!["USER"], found.roles);
+
+        let same_email = CreateUserCommand {
+            nickname: String::from("other"),
+            ..command()
+        };
+        assert!(matches!(
+            users
+                .insert_one(&User::from_create_command(&same_email))
+                .await,
+            Err(StoreError::DuplicateKey { index: Some(index), .. })
+                if index == "profile.email_address_1"
+        ));
+    }
 }
@@ -0,0 +1,16 @@
+use store::{doc, Database, Document, StoreError};
+use user::USER_COLLECTION;
+
+/// Users created before roles existed have none.
+pub async fn run(db: Database) -> Result<(), StoreError> {
+    let updated = db
+        .collection::<Document>(USER_COLLECTION)
+        .update_many(
+            doc! {"$or": [{"roles": {"$exists": false}}, {"roles": {"$size": 0}}]},
+            doc! {"$set": {"roles": ["USER"]}},
+            None,
+        )
+        .await?;
+    tracing::info!("gave the USER role to {} users", updated.modified_count);
+    Ok(())
+}
@@ -0,0 +1,51 @@
+mod m001_default_user_role;
+
+use store::{Migration, MigrationCommand, Migrator, StoreClient};
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+use user::APP_NAME;
+
+const USAGE: &str = "usage: migrate-user list|apply|dry-run";
+
+#[tokio::main]
+async fn main() {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let command = match std::env::args().nth(1) {
+        Some(arg) => match arg.parse::<MigrationCommand>() {
+            Ok(command) => command,
+            Err(e) => exit(&format!("{e}\n{USAGE}")),
+        },
+        None => exit(USAGE),
+    };
+    let store_client = match StoreClient::new(APP_NAME.to_string()).await {
+        Ok(store_client) => store_client,
+        Err(e) => exit(&format!("could not create store for {APP_NAME}: {e}")),
+    };
+    let migrator = match Migrator::new(store_client.get_db(), migrations()) {
+        Ok(migrator) => migrator,
+        Err(e) => exit(&e.to_string()),
+    };
+    match migrator.run_command(command).await {
+        Ok(output) => println!("{output}"),
+        Err(e) => exit(&e.to_string()),
+    }
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1)
+}
+
+/// Never edit nor remove a migration once applied, add a new one instead.
+/// Each migration is a file whose source goes in its checksum, so editing it
+/// after it was applied is caught.
+fn migrations() -> Vec<Migration> {
+    vec![Migration::new(1, "default_user_role", |db| {
+        Box::pin(m001_default_user_role::run(db))
+    })
+    .set_source(include_str!("m001_default_user_role.rs"))]
+}
@@ -0,0 +1,88 @@
+use store::{
+    export_jsonl, import_jsonl, BulkOptions, ExportOptions, Filter, MongoRepository, Repository,
+    StoreClient,
+};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, BufReader};
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+use user::{User, APP_NAME, USER_COLLECTION};
+
+const USAGE: &str = "usage: user-data export|import [file]";
+
+/// Backs up the users to JSON Lines, or seeds them from a backup. Without a
+/// file, exports go to stdout and imports read stdin.
+#[tokio::main]
+async fn main() {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .with_writer(std::io::stderr)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let mut args = std::env::args().skip(1);
+    let (command, file) = (args.next(), args.next());
+    let store_client = match StoreClient::new(APP_NAME.to_string()).await {
+        Ok(store_client) => store_client,
+        Err(e) => exit(&format!("could not create store for {APP_NAME}: {e}")),
+    };
+    let users = MongoRepository::new(store_client.get_db().collection::<User>(USER_COLLECTION))
+        .set_indexes(User::indexes());
+    match command.as_deref() {
+        Some("export") => export(&users, file).await,
+        Some("import") => import(&users, file).await,
+        _ => exit(USAGE),
+    }
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1)
+}
+
+async fn export(users: &MongoRepository<User>, file: Option<String>) {
+    let mut writer: Box<dyn AsyncWrite + Unpin> = match file {
+        Some(file) => match File::create(&file).await {
+            Ok(file) => Box::new(file),
+            Err(e) => exit(&format!("could not create {file}: {e}")),
+        },
+        None => Box::new(tokio::io::stdout()),
+    };
+    let options = ExportOptions::default().set_include_deleted(true);
+    match export_jsonl(users, Filter::all(), options, &mut writer).await {
+        Ok(exported) => tracing::info!("exported {exported} users"),
+        Err(e) => exit(&format!("could not export users: {e}")),
+    }
+}
+
+/// Unordered, so that importing again only adds the users still missing,
+/// the others failing on their unique `_id`.
+async fn import(users: &MongoRepository<User>, file: Option<String>) {
+    if let Err(e) = users.ensure_indexes().await {
+        exit(&format!("could not create user indexes: {e}"));
+    }
+    let options = BulkOptions::default().set_ordered(false);
+    let imported = match file {
+        Some(file) => match File::open(&file).await {
+            Ok(file) => import_jsonl(users, BufReader::new(file), options).await,
+            Err(e) => exit(&format!("could not open {file}: {e}")),
+        },
+        None => import_jsonl(users, BufReader::new(tokio::io::stdin()), options).await,
+    };
+    match imported {
+        Ok(result) => {
+            for error in result.errors() {
+                tracing::warn!("user {} not imported: {}", error.index(), error.message());
+            }
+            for error in result.write_concern_errors() {
+                tracing::error!("imported users may not be durable: {}", error.message());
+            }
+            tracing::info!(
+                "imported {} users, {} not imported",
+                result.inserted(),
+                result.errors().len()
+            );
+        }
+        Err(e) => exit(&format!("could not import users: {e}")),
+    }
+}
@@ -0,0 +1,51 @@
+use domain::{
+    CreateUserCommand, FieldPath, FieldPaths, Id, Metadata, Profile, WithJsonProcessor,
+    WithMetadata,
+};
+use store::IndexSpec;
+
+pub const APP_NAME: &str = "user_ms";
+pub const USER_COLLECTION: &str = "user";
+pub const USER_CREATED_DEFAULT_ROLE: &str = "USER";
+
+#[derive(
+    Debug,
+    Default,
+    WithJsonProcessor,
+    WithMetadata,
+    FieldPaths,
+    domain::Serialize,
+    domain::Deserialize,
+)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: Id,
+    #[serde(rename = "metadata")]
+    pub domain_metadata: Metadata,
+    pub nickname: String,
+    pub password: String,
+    pub profile: Profile,
+    pub roles: Vec<String>,
+}
+
+impl User {
+    pub fn indexes() -> Vec<IndexSpec> {
+        vec![
+            IndexSpec::asc(User::NICKNAME).set_unique(true),
+            IndexSpec::asc(User::PROFILE.join(&Profile::EMAIL_ADDRESS)).set_unique(true),
+        ]
+    }
+
+    pub fn from_create_command(command: &CreateUserCommand) -> User {
+        let user_id = Id::default();
+        let metadata = Metadata::new_with_default(&user_id);
+        User {
+            profile: Profile::new_with_default(&command.email),
+            id: user_id,
+            domain_metadata: metadata,
+            nickname: command.nickname.clone(),
+            password: command.password.clone(),
+            roles: vec![USER_CREATED_DEFAULT_ROLE.to_string()],
+        }
+    }
+}
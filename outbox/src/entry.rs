@@ -0,0 +1,115 @@
+use domain::{Deserialize, Serialize, WithJsonProcessor};
+use messenger::{Message, MessageOptions, MessengerError};
+use store::DateTime;
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboxStatus {
+    Pending,
+    Sent,
+    /// Given up on by the relay, it is not published again.
+    Failed,
+}
+
+/// A message waiting in the outbox collection to be published by the relay.
+/// It is keyed by the id of the message so a consumer sees the same id
+/// however many times the relay had to publish it.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    #[serde(rename = "_id")]
+    id: String,
+    routing_key: String,
+    message: String,
+    status: OutboxStatus,
+    attempts: u32,
+    last_error: Option<String>,
+    created_at: DateTime,
+    locked_until: Option<DateTime>,
+    sent_at: Option<DateTime>,
+}
+
+impl OutboxEntry {
+    pub fn new<'a, P>(
+        sender: &str,
+        routing_key: &str,
+        payload: &P,
+        options: MessageOptions,
+    ) -> Result<OutboxEntry, MessengerError>
+    where
+        P: WithJsonProcessor<'a>,
+    {
+        let message = Message::from_payload(sender, payload, options)?;
+        Ok(OutboxEntry {
+            id: String::from(message.id().as_str()),
+            routing_key: String::from(routing_key),
+            message: message.to_json().map_err(MessengerError::Serialization)?,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            created_at: DateTime::now(),
+            locked_until: None,
+            sent_at: None,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+    pub fn message(&self) -> anyhow::Result<Message> {
+        Message::from_json(&self.message)
+    }
+    pub fn status(&self) -> &OutboxStatus {
+        &self.status
+    }
+    /// Number of times the relay claimed this entry.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+    pub fn last_error(&self) -> &Option<String> {
+        &self.last_error
+    }
+    pub fn created_at(&self) -> DateTime {
+        self.created_at
+    }
+    pub fn sent_at(&self) -> Option<DateTime> {
+        self.sent_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::{OutboxEntry, OutboxStatus};
+    use domain::{Metadata, UserCreatedEvent};
+    use messenger::{Message, MessageOptions};
+
+    #[test]
+    fn test_outbox_entry() {
+        let event = UserCreatedEvent {
+            domain_metadata: Metadata::default(),
+            email: String::from("kikoo@lol.com"),
+            nickname: String::from("nordine"),
+        };
+        let cause = Message::from_payload("gateway", &event, MessageOptions::default()).unwrap();
+        let entry = OutboxEntry::new(
+            "user_ms",
+            "UserCreatedEvent",
+            &event,
+            MessageOptions::caused_by(&cause),
+        )
+        .unwrap();
+        assert_eq!(&OutboxStatus::Pending, entry.status());
+        assert_eq!(0, entry.attempts());
+
+        let message = entry.message().unwrap();
+        assert_eq!(entry.id(), message.id().as_str());
+        assert_eq!("user_ms", message.sender());
+        assert_eq!(&Some(cause.id().clone()), message.causation_id());
+        assert_eq!(
+            &Some(String::from("UserCreatedEvent")),
+            message.message_type()
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use domain::{Deserialize, Id, Serialize};
+use messenger::ProcessedMessages;
+use std::time::Duration;
+use store::{
+    doc, ClientSession, Collection, DateTime, IndexModel, IndexOptions, ReplaceOptions,
+    StoreClient, StoreError,
+};
+
+pub const PROCESSED_MESSAGES_COLLECTION: &str = "processed_messages";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ProcessedMessage {
+    #[serde(rename = "_id")]
+    id: String,
+    consumer: String,
+    message_id: String,
+    processed_at: DateTime,
+}
+
+/// Processed message ids kept in Mongo, expired by a TTL index after `retention`.
+/// Handlers writing to Mongo record the message in the transaction of their
+/// writes with `Outbox::save_processed`, so a crash in between cannot have it
+/// handled twice.
+#[derive(Debug, Clone)]
+pub struct MongoProcessedMessages {
+    collection: Collection<ProcessedMessage>,
+    retention: Duration,
+}
+
+impl MongoProcessedMessages {
+    pub async fn new(
+        store_client: &StoreClient,
+        retention: Duration,
+    ) -> anyhow::Result<MongoProcessedMessages> {
+        let db = store_client.get_db();
+        let collection = db.collection::<ProcessedMessage>(PROCESSED_MESSAGES_COLLECTION);
+        let index = IndexModel::builder()
+            .keys(doc! {"processed_at": 1})
+            .options(IndexOptions::builder().expire_after(retention).build())
+            .build();
+        match collection
+            .create_index(index, None)
+            .await
+            .map_err(StoreError::from)
+        {
+            Ok(_) => {}
+            // the index exists with another retention
+            Err(e) if e.is_index_options_conflict() => {
+                tracing::info!("updating retention of {PROCESSED_MESSAGES_COLLECTION}: {e}");
+                db.run_command(
+                    doc! {
+                        "collMod": PROCESSED_MESSAGES_COLLECTION,
+                        "index": {
+                            "keyPattern": {"processed_at": 1},
+                            "expireAfterSeconds": retention.as_secs() as i64,
+                        },
+                    },
+                    None,
+                )
+                .await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(MongoProcessedMessages {
+            collection,
+            retention,
+        })
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+}
+
+fn key(consumer: &str, message_id: &Id) -> String {
+    format!("{consumer}:{}", message_id.as_str())
+}
+
+impl ProcessedMessage {
+    fn new(consumer: &str, message_id: &Id) -> ProcessedMessage {
+        ProcessedMessage {
+            id: key(consumer, message_id),
+            consumer: String::from(consumer),
+            message_id: String::from(message_id.as_str()),
+            processed_at: DateTime::now(),
+        }
+    }
+}
+
+/// Records within a transaction started on `session`, see `Outbox::save_processed`.
+pub(crate) async fn record_with_session(
+    collection: &Collection<ProcessedMessage>,
+    consumer: &str,
+    message_id: &Id,
+    session: &mut ClientSession,
+) -> Result<(), StoreError> {
+    let processed = ProcessedMessage::new(consumer, message_id);
+    collection
+        .replace_one_with_session(
+            doc! {"_id": &processed.id},
+            &processed,
+            ReplaceOptions::builder().upsert(true).build(),
+            session,
+        )
+        .await?;
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl ProcessedMessages for MongoProcessedMessages {
+    async fn contains(&self, consumer: &str, message_id: &Id) -> anyhow::Result<bool> {
+        // the TTL monitor only runs every minute
+        let oldest = DateTime::from_millis(
+            DateTime::now().timestamp_millis() - self.retention.as_millis() as i64,
+        );
+        let found = self
+            .collection
+            .find_one(
+                doc! {"_id": key(consumer, message_id), "processed_at": {"$gt": oldest}},
+                None,
+            )
+            .await?;
+        Ok(found.is_some())
+    }
+
+    async fn record(&self, consumer: &str, message_id: &Id) -> anyhow::Result<()> {
+        let processed = ProcessedMessage::new(consumer, message_id);
+        self.collection
+            .replace_one(
+                doc! {"_id": &processed.id},
+                &processed,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+mod entry;
+mod inbox;
+mod outbox;
+mod relay;
+
+pub use entry::{OutboxEntry, OutboxStatus};
+pub use inbox::{MongoProcessedMessages, PROCESSED_MESSAGES_COLLECTION};
+pub use outbox::{Outbox, OUTBOX_COLLECTION};
+pub use relay::OutboxRelay;
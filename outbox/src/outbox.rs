@@ -0,0 +1,195 @@
+use crate::entry::OutboxEntry;
+use crate::inbox::{self, ProcessedMessage, PROCESSED_MESSAGES_COLLECTION};
+use domain::{Id, Serialize};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use store::{doc, ClientSession, Collection, DateTime, SessionRepository, StoreClient, StoreError};
+use store::{FindOneAndUpdateOptions, ReturnDocument};
+
+pub const OUTBOX_COLLECTION: &str = "outbox";
+
+/// The outbox collection of an application database. Entries are written in
+/// the same transaction as the entity they are about, then published by an
+/// `OutboxRelay`.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    store_client: StoreClient,
+    collection: Collection<OutboxEntry>,
+    processed: Collection<ProcessedMessage>,
+}
+
+impl Outbox {
+    pub fn new(store_client: &StoreClient) -> Outbox {
+        Outbox {
+            store_client: store_client.clone(),
+            collection: store_client
+                .get_db()
+                .collection::<OutboxEntry>(OUTBOX_COLLECTION),
+            processed: store_client
+                .get_db()
+                .collection::<ProcessedMessage>(PROCESSED_MESSAGES_COLLECTION),
+        }
+    }
+
+    /// Inserts `entity` with `repository` and `entries` in the outbox in a
+    /// single transaction, see `StoreClient::transaction`. Needs a replica set.
+    pub async fn save<T, R>(
+        &self,
+        repository: &R,
+        entity: &T,
+        entries: &[OutboxEntry],
+    ) -> Result<(), StoreError>
+    where
+        T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+        R: SessionRepository<T> + Sync,
+    {
+        self.insert(repository, entity, entries, None).await
+    }
+
+    /// Like `save`, also recording in the transaction that `consumer`
+    /// processed `message_id`, see `MongoProcessedMessages`.
+    pub async fn save_processed<T, R>(
+        &self,
+        repository: &R,
+        entity: &T,
+        entries: &[OutboxEntry],
+        consumer: &str,
+        message_id: &Id,
+    ) -> Result<(), StoreError>
+    where
+        T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+        R: SessionRepository<T> + Sync,
+    {
+        self.insert(repository, entity, entries, Some((consumer, message_id)))
+            .await
+    }
+
+    async fn insert<T, R>(
+        &self,
+        repository: &R,
+        entity: &T,
+        entries: &[OutboxEntry],
+        processed: Option<(&str, &Id)>,
+    ) -> Result<(), StoreError>
+    where
+        T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+        R: SessionRepository<T> + Sync,
+    {
+        self.store_client
+            .transaction(|session| {
+                Box::pin(async move {
+                    repository.insert_one_with_session(entity, session).await?;
+                    self.add_with_session(entries, session).await?;
+                    match processed {
+                        Some((consumer, message_id)) => {
+                            inbox::record_with_session(
+                                &self.processed,
+                                consumer,
+                                message_id,
+                                session,
+                            )
+                            .await
+                        }
+                        None => Ok(()),
+                    }
+                })
+            })
+            .await
+    }
+
+    /// Adds `entries` within a transaction started on `session`.
+    pub async fn add_with_session(
+        &self,
+        entries: &[OutboxEntry],
+        session: &mut ClientSession,
+    ) -> Result<(), StoreError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.collection
+            .insert_many_with_session(entries, None, session)
+            .await?;
+        Ok(())
+    }
+
+    /// Locks the oldest pending entry for `lease` so no other relay picks it up.
+    pub async fn claim(&self, lease: Duration) -> anyhow::Result<Option<OutboxEntry>> {
+        let now = DateTime::now();
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! {"created_at": 1})
+            .return_document(ReturnDocument::After)
+            .build();
+        let entry = self
+            .collection
+            .find_one_and_update(
+                doc! {
+                    "status": "pending",
+                    "$or": [{"locked_until": null}, {"locked_until": {"$lte": now}}],
+                },
+                doc! {
+                    "$set": {"locked_until": after(now, lease)},
+                    "$inc": {"attempts": 1},
+                },
+                options,
+            )
+            .await?;
+        Ok(entry)
+    }
+
+    pub async fn mark_sent(&self, id: &str) -> anyhow::Result<()> {
+        self.collection
+            .update_one(
+                doc! {"_id": id},
+                doc! {"$set": {"status": "sent", "sent_at": DateTime::now(), "locked_until": null}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Records why publishing failed and leaves the entry alone for `retry_in`.
+    pub async fn mark_failed(
+        &self,
+        id: &str,
+        error: &str,
+        retry_in: Duration,
+    ) -> anyhow::Result<()> {
+        self.collection
+            .update_one(
+                doc! {"_id": id},
+                doc! {"$set": {"last_error": error, "locked_until": after(DateTime::now(), retry_in)}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Gives up on the entry, `claim` skips it from now on.
+    pub async fn mark_dead(&self, id: &str, error: &str) -> anyhow::Result<()> {
+        self.collection
+            .update_one(
+                doc! {"_id": id},
+                doc! {"$set": {"status": "failed", "last_error": error, "locked_until": null}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn pending_count(&self) -> anyhow::Result<u64> {
+        let count = self
+            .collection
+            .count_documents(doc! {"status": "pending"}, None)
+            .await?;
+        Ok(count)
+    }
+
+    pub async fn find_by_id(&self, id: &str) -> anyhow::Result<Option<OutboxEntry>> {
+        let entry = self.collection.find_one(doc! {"_id": id}, None).await?;
+        Ok(entry)
+    }
+}
+
+fn after(date: DateTime, duration: Duration) -> DateTime {
+    DateTime::from_millis(date.timestamp_millis() + duration.as_millis() as i64)
+}
@@ -0,0 +1,136 @@
+use crate::outbox::Outbox;
+use messenger::{MessageBus, ShutdownToken};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Publishes the pending entries of an `Outbox` and marks them sent once the
+/// broker confirmed them. Several relays can run against the same outbox, an
+/// entry is leased to one of them at a time.
+pub struct OutboxRelay<B> {
+    outbox: Outbox,
+    bus: Arc<B>,
+    poll_interval: Duration,
+    batch_size: usize,
+    lease: Duration,
+    retry_delay: Duration,
+    max_attempts: u32,
+}
+
+impl<B> OutboxRelay<B>
+where
+    B: MessageBus,
+{
+    pub fn new(outbox: Outbox, bus: Arc<B>) -> OutboxRelay<B> {
+        OutboxRelay {
+            outbox,
+            bus,
+            poll_interval: Duration::from_secs(1),
+            batch_size: 100,
+            lease: Duration::from_secs(30),
+            retry_delay: Duration::from_secs(5),
+            max_attempts: 10,
+        }
+    }
+    pub fn set_poll_interval(self, poll_interval: Duration) -> Self {
+        OutboxRelay {
+            poll_interval,
+            ..self
+        }
+    }
+    pub fn set_batch_size(self, batch_size: usize) -> Self {
+        OutboxRelay {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+    pub fn set_lease(self, lease: Duration) -> Self {
+        OutboxRelay { lease, ..self }
+    }
+    pub fn set_retry_delay(self, retry_delay: Duration) -> Self {
+        OutboxRelay {
+            retry_delay,
+            ..self
+        }
+    }
+    /// Entries still failing after `max_attempts` publishes are marked
+    /// `OutboxStatus::Failed` and left alone.
+    pub fn set_max_attempts(self, max_attempts: u32) -> Self {
+        OutboxRelay {
+            max_attempts: max_attempts.max(1),
+            ..self
+        }
+    }
+
+    /// Claims up to `batch_size` pending entries and returns how many were
+    /// sent. An entry that could not be published is retried after
+    /// `retry_delay`, or given up on when it cannot be decoded or ran out of
+    /// attempts.
+    pub async fn relay_pending(&self) -> anyhow::Result<usize> {
+        let mut sent = 0;
+        for _ in 0..self.batch_size {
+            let entry = match self.outbox.claim(self.lease).await? {
+                Some(entry) => entry,
+                None => break,
+            };
+            let message = match entry.message() {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::error!("giving up on outbox entry {}: {e}", entry.id());
+                    self.outbox.mark_dead(entry.id(), &e.to_string()).await?;
+                    continue;
+                }
+            };
+            match self
+                .bus
+                .publish_message(entry.routing_key(), &message)
+                .await
+            {
+                Ok(outcome) => {
+                    self.outbox.mark_sent(entry.id()).await?;
+                    tracing::debug!(
+                        "outbox entry {} sent to {} in {:?}",
+                        entry.id(),
+                        entry.routing_key(),
+                        outcome.confirmed_in()
+                    );
+                    sent += 1;
+                }
+                Err(e) if entry.attempts() >= self.max_attempts => {
+                    tracing::error!(
+                        "giving up on outbox entry {} after {} attempts: {e}",
+                        entry.id(),
+                        entry.attempts()
+                    );
+                    self.outbox.mark_dead(entry.id(), &e.to_string()).await?;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "could not publish outbox entry {} (attempt {}): {e}",
+                        entry.id(),
+                        entry.attempts()
+                    );
+                    self.outbox
+                        .mark_failed(entry.id(), &e.to_string(), self.retry_delay)
+                        .await?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Relays until `shutdown` is cancelled, waiting `poll_interval` whenever
+    /// there is nothing to send. A batch being published is finished first.
+    pub async fn run(&self, shutdown: &ShutdownToken) {
+        while !shutdown.is_cancelled() {
+            match self.relay_pending().await {
+                Ok(sent) if sent == self.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => tracing::error!("outbox relay failed: {e}"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.poll_interval) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+    }
+}
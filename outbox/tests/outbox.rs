@@ -0,0 +1,187 @@
+#[cfg(test)]
+mod test {
+    use domain::{Deserialize, Id, Metadata, Serialize, UserCreatedEvent, WithJsonProcessor};
+    use futures_util::stream::BoxStream;
+    use futures_util::StreamExt;
+    use messenger::{
+        Envelope, InMemoryBus, Message, MessageBus, MessageOptions, MessengerError,
+        ProcessedMessages, PublishOutcome, SubscribeOptions,
+    };
+    use outbox::{MongoProcessedMessages, Outbox, OutboxEntry, OutboxRelay, OutboxStatus};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use store::{MongoRepository, StoreClient};
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Account {
+        #[serde(rename = "_id")]
+        id: Id,
+        nickname: String,
+    }
+
+    /// Returns every message, as a broker does when no queue is bound.
+    struct UnroutableBus;
+
+    #[async_trait::async_trait]
+    impl MessageBus for UnroutableBus {
+        fn application_name(&self) -> &str {
+            "test"
+        }
+
+        async fn publish_message(
+            &self,
+            _routing_key: &str,
+            message: &Message,
+        ) -> Result<PublishOutcome, MessengerError> {
+            Err(MessengerError::Returned {
+                message_id: message.id().clone(),
+                reply_code: 312,
+                reply_text: String::from("NO_ROUTE"),
+            })
+        }
+
+        async fn subscribe_typed_with_options<T>(
+            &self,
+            _routing_key: &str,
+            _options: &SubscribeOptions,
+        ) -> anyhow::Result<BoxStream<'static, Result<Envelope<T>, MessengerError>>>
+        where
+            T: for<'a> WithJsonProcessor<'a, Output = T> + Send + 'static,
+        {
+            Err(anyhow::anyhow!("cannot subscribe"))
+        }
+    }
+
+    fn user_created(account: &Account) -> OutboxEntry {
+        OutboxEntry::new(
+            "test",
+            "UserCreatedEvent",
+            &UserCreatedEvent {
+                domain_metadata: Metadata::new_with_default(&account.id),
+                email: String::from("kikoo@lol.com"),
+                nickname: account.nickname.clone(),
+            },
+            MessageOptions::default(),
+        )
+        .unwrap()
+    }
+
+    // needs mongo running as a replica set, see docker/docker-compose.yml
+    #[tokio::test]
+    async fn test_save_and_relay() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let outbox = Outbox::new(&store_client);
+        let accounts =
+            MongoRepository::new(store_client.get_db().collection::<Account>("accounts"));
+
+        let bus = Arc::new(InMemoryBus::new("test"));
+        let mut events = bus
+            .subscribe_typed::<UserCreatedEvent>("UserCreatedEvent")
+            .await
+            .unwrap();
+
+        let account = Account {
+            nickname: String::from("nordine"),
+            ..Default::default()
+        };
+        let entry = user_created(&account);
+        outbox
+            .save(&accounts, &account, std::slice::from_ref(&entry))
+            .await
+            .unwrap();
+
+        let relay = OutboxRelay::new(outbox.clone(), Arc::clone(&bus));
+        assert!(relay.relay_pending().await.unwrap() >= 1);
+
+        let sent = outbox.find_by_id(entry.id()).await.unwrap().unwrap();
+        assert_eq!(&OutboxStatus::Sent, sent.status());
+        assert_eq!(1, sent.attempts());
+        let mut relayed = false;
+        while let Some(Ok(event)) = events.next().await {
+            event.ack().await.unwrap();
+            if event.id().as_str() == entry.id() {
+                relayed = true;
+                break;
+            }
+        }
+        assert!(relayed);
+    }
+
+    #[tokio::test]
+    async fn test_failing_entries_given_up() {
+        // its own database, so the relay does not claim the entries of other tests
+        let store_client = StoreClient::new(String::from("test_outbox_failures"))
+            .await
+            .unwrap();
+        let outbox = Outbox::new(&store_client);
+        let accounts =
+            MongoRepository::new(store_client.get_db().collection::<Account>("accounts"));
+        let account = Account {
+            nickname: String::from("nordine"),
+            ..Default::default()
+        };
+        let entries = [user_created(&account), user_created(&account)];
+        outbox.save(&accounts, &account, &entries).await.unwrap();
+
+        let relay = OutboxRelay::new(outbox.clone(), Arc::new(UnroutableBus))
+            .set_retry_delay(Duration::from_secs(3600))
+            .set_max_attempts(2);
+        assert_eq!(0, relay.relay_pending().await.unwrap());
+        // a failing entry does not hold back the next one
+        for entry in &entries {
+            let pending = outbox.find_by_id(entry.id()).await.unwrap().unwrap();
+            assert_eq!(&OutboxStatus::Pending, pending.status());
+            assert_eq!(1, pending.attempts());
+            assert!(pending.last_error().is_some());
+        }
+
+        let relay = relay.set_retry_delay(Duration::ZERO);
+        for entry in &entries {
+            outbox
+                .mark_failed(entry.id(), "", Duration::ZERO)
+                .await
+                .unwrap();
+        }
+        assert_eq!(0, relay.relay_pending().await.unwrap());
+        for entry in &entries {
+            let failed = outbox.find_by_id(entry.id()).await.unwrap().unwrap();
+            assert_eq!(&OutboxStatus::Failed, failed.status());
+            assert_eq!(2, failed.attempts());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_processed_messages() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let processed = MongoProcessedMessages::new(&store_client, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let id = Id::default();
+        assert!(!processed.contains("test_consumer", &id).await.unwrap());
+        processed.record("test_consumer", &id).await.unwrap();
+        processed.record("test_consumer", &id).await.unwrap();
+        assert!(processed.contains("test_consumer", &id).await.unwrap());
+        assert!(!processed.contains("other_consumer", &id).await.unwrap());
+
+        // recorded with the writes of the handler
+        let outbox = Outbox::new(&store_client);
+        let accounts = MongoRepository::new(store_client.get_db().collection("accounts"));
+        let handled = Id::default();
+        outbox
+            .save_processed(
+                &accounts,
+                &Account::default(),
+                &[],
+                "test_consumer",
+                &handled,
+            )
+            .await
+            .unwrap();
+        assert!(processed.contains("test_consumer", &handled).await.unwrap());
+
+        // changing the retention updates the TTL index
+        MongoProcessedMessages::new(&store_client, Duration::from_secs(60))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+use crate::update::Update;
+use crate::{Bson, Document};
+
+/// One write of a `Repository::bulk_write`.
+#[derive(PartialEq, Debug, Clone)]
+pub enum WriteOp<T> {
+    Insert(T),
+    /// Replaces the document `id`, inserting it when missing if `upsert`.
+    Replace {
+        id: String,
+        entity: T,
+        upsert: bool,
+    },
+    /// Boxed, an `Update` being much bigger than the other writes.
+    Update {
+        id: String,
+        update: Box<Update>,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+impl<T> WriteOp<T> {
+    pub(crate) fn kind(&self) -> WriteKind {
+        match self {
+            WriteOp::Insert(_) => WriteKind::Insert,
+            WriteOp::Replace { .. } | WriteOp::Update { .. } => WriteKind::Update,
+            WriteOp::Delete { .. } => WriteKind::Delete,
+        }
+    }
+}
+
+/// The server command a `WriteOp` is sent with.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub(crate) enum WriteKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Writes grouped by `runs`, with their index in the stream.
+pub(crate) type Runs<T> = Vec<(WriteKind, Vec<(usize, WriteOp<T>)>)>;
+
+/// Groups the writes of a batch, with their index, into the runs sent as one
+/// command each. Ordered writes keep their order, so only consecutive writes
+/// of the same kind go together.
+pub(crate) fn runs<T>(writes: Vec<(usize, WriteOp<T>)>, ordered: bool) -> Runs<T> {
+    let mut runs: Runs<T> = Vec::new();
+    for (index, write) in writes {
+        let kind = write.kind();
+        let run = if ordered {
+            runs.last_mut().filter(|(run_kind, _)| *run_kind == kind)
+        } else {
+            runs.iter_mut().find(|(run_kind, _)| *run_kind == kind)
+        };
+        match run {
+            Some((_, run)) => run.push((index, write)),
+            None => runs.push((kind, vec![(index, write)])),
+        }
+    }
+    runs
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BulkOptions {
+    ordered: bool,
+    batch_size: usize,
+}
+
+impl Default for BulkOptions {
+    fn default() -> Self {
+        BulkOptions {
+            ordered: true,
+            batch_size: 1000,
+        }
+    }
+}
+
+impl BulkOptions {
+    /// Ordered writes stop at the first failure. Unordered ones go on and
+    /// may be grouped differently, which is faster.
+    pub fn set_ordered(self, ordered: bool) -> Self {
+        BulkOptions { ordered, ..self }
+    }
+    /// Writes taken from the stream and sent at once. A command must stay
+    /// under 16MB, so lower it for big documents.
+    pub fn set_batch_size(self, batch_size: usize) -> Self {
+        BulkOptions {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+    pub fn ordered(&self) -> bool {
+        self.ordered
+    }
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+}
+
+/// A write that failed, `index` being its position in the stream.
+#[derive(PartialEq, Debug, Clone)]
+pub struct BulkWriteError {
+    index: usize,
+    code: Option<i32>,
+    message: String,
+}
+
+impl BulkWriteError {
+    pub(crate) fn new(index: usize, code: Option<i32>, message: &str) -> BulkWriteError {
+        BulkWriteError {
+            index,
+            code,
+            message: String::from(message),
+        }
+    }
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// The server error code, `None` for the errors of an
+    /// `InMemoryRepository`.
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// A command whose writes were applied on the primary but not acknowledged
+/// as the write concern requires, e.g. by enough replicas in time.
+#[derive(PartialEq, Debug, Clone)]
+pub struct WriteConcernError {
+    code: Option<i32>,
+    message: String,
+}
+
+impl WriteConcernError {
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Outcome of a `Repository::bulk_write`. The writes that failed are in
+/// `errors`; when ordered, the ones after the first failure were not run.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct BulkWriteResult {
+    inserted: u64,
+    matched: u64,
+    modified: u64,
+    upserted: u64,
+    deleted: u64,
+    errors: Vec<BulkWriteError>,
+    write_concern_errors: Vec<WriteConcernError>,
+}
+
+impl BulkWriteResult {
+    pub fn inserted(&self) -> u64 {
+        self.inserted
+    }
+    /// Replaced or updated documents that were found, changed or not.
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+    pub fn upserted(&self) -> u64 {
+        self.upserted
+    }
+    /// Deleted or, with soft delete, marked deleted.
+    pub fn deleted(&self) -> u64 {
+        self.deleted
+    }
+    pub fn errors(&self) -> &Vec<BulkWriteError> {
+        &self.errors
+    }
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+    /// One per command whose writes may not be durable, they still count as
+    /// done in the other fields.
+    pub fn write_concern_errors(&self) -> &Vec<WriteConcernError> {
+        &self.write_concern_errors
+    }
+
+    pub(crate) fn add_error(&mut self, error: BulkWriteError) {
+        self.errors.push(error);
+    }
+    pub(crate) fn add(&mut self, kind: WriteKind, matched: u64, modified: u64) {
+        match kind {
+            WriteKind::Insert => self.inserted += matched,
+            WriteKind::Update => {
+                self.matched += matched;
+                self.modified += modified;
+            }
+            WriteKind::Delete => self.deleted += matched,
+        }
+    }
+    pub(crate) fn add_upserted(&mut self, upserted: u64) {
+        self.upserted += upserted;
+    }
+
+    /// Adds the counts and errors of `other`, whose indexes start at `offset`.
+    pub(crate) fn merge(&mut self, other: BulkWriteResult, offset: usize) {
+        self.inserted += other.inserted;
+        self.matched += other.matched;
+        self.modified += other.modified;
+        self.upserted += other.upserted;
+        self.deleted += other.deleted;
+        self.errors
+            .extend(other.errors.into_iter().map(|error| BulkWriteError {
+                index: error.index + offset,
+                ..error
+            }));
+        self.write_concern_errors.extend(other.write_concern_errors);
+    }
+
+    /// Reads the reply of an `insert`, `update` or `delete` command sent with
+    /// the writes at `indexes`.
+    pub(crate) fn add_reply(&mut self, kind: WriteKind, indexes: &[usize], reply: &Document) {
+        let count = |field: &str| match reply.get(field) {
+            Some(Bson::Int32(n)) => *n as u64,
+            Some(Bson::Int64(n)) => *n as u64,
+            _ => 0,
+        };
+        let upserted = reply.get_array("upserted").map(Vec::len).unwrap_or(0) as u64;
+        self.add(
+            kind,
+            count("n").saturating_sub(upserted),
+            count("nModified"),
+        );
+        self.upserted += upserted;
+        if let Ok(errors) = reply.get_array("writeErrors") {
+            for error in errors.iter().filter_map(Bson::as_document) {
+                let position = match error.get("index") {
+                    Some(Bson::Int32(n)) => *n as usize,
+                    Some(Bson::Int64(n)) => *n as usize,
+                    _ => 0,
+                };
+                self.errors.push(BulkWriteError {
+                    index: indexes.get(position).copied().unwrap_or_default(),
+                    code: error.get_i32("code").ok(),
+                    message: String::from(error.get_str("errmsg").unwrap_or_default()),
+                });
+            }
+        }
+        if let Ok(error) = reply.get_document("writeConcernError") {
+            self.write_concern_errors.push(WriteConcernError {
+                code: error.get_i32("code").ok(),
+                message: String::from(error.get_str("errmsg").unwrap_or_default()),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bulk::{runs, BulkWriteError, BulkWriteResult, Runs, WriteKind, WriteOp};
+    use crate::{doc, Update};
+
+    fn writes() -> Vec<(usize, WriteOp<&'static str>)> {
+        vec![
+            WriteOp::Insert("a"),
+            WriteOp::Insert("b"),
+            WriteOp::Delete {
+                id: String::from("a"),
+            },
+            WriteOp::Update {
+                id: String::from("b"),
+                update: Box::new(Update::new().set("name", "c")),
+            },
+            WriteOp::Insert("d"),
+        ]
+        .into_iter()
+        .enumerate()
+        .collect()
+    }
+
+    fn shape(runs: Runs<&'static str>) -> Vec<(WriteKind, Vec<usize>)> {
+        runs.into_iter()
+            .map(|(kind, run)| (kind, run.into_iter().map(|(index, _)| index).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_runs() {
+        assert_eq!(
+            vec![
+                (WriteKind::Insert, vec![0, 1]),
+                (WriteKind::Delete, vec![2]),
+                (WriteKind::Update, vec![3]),
+                (WriteKind::Insert, vec![4]),
+            ],
+            shape(runs(writes(), true))
+        );
+        assert_eq!(
+            vec![
+                (WriteKind::Insert, vec![0, 1, 4]),
+                (WriteKind::Delete, vec![2]),
+                (WriteKind::Update, vec![3]),
+            ],
+            shape(runs(writes(), false))
+        );
+    }
+
+    #[test]
+    fn test_reply() {
+        let mut result = BulkWriteResult::default();
+        result.add_reply(
+            WriteKind::Update,
+            &[3, 5, 8],
+            &doc! {
+                "n": 2,
+                "nModified": 1,
+                "upserted": [{"index": 2, "_id": "x"}],
+                "writeErrors": [{"index": 1, "code": 11000, "errmsg": "E11000 duplicate key"}],
+                "ok": 1.0,
+            },
+        );
+        let mut other = BulkWriteResult::default();
+        other.add_reply(
+            WriteKind::Delete,
+            &[0],
+            &doc! {
+                "n": 1,
+                "writeConcernError": {"code": 64, "errmsg": "waiting for replication timed out"},
+                "ok": 1.0,
+            },
+        );
+        result.merge(other, 10);
+        assert_eq!(1, result.matched());
+        assert_eq!(1, result.modified());
+        assert_eq!(1, result.upserted());
+        assert_eq!(1, result.deleted());
+        assert_eq!(
+            &vec![BulkWriteError::new(5, Some(11000), "E11000 duplicate key")],
+            result.errors()
+        );
+        assert_eq!(1, result.write_concern_errors().len());
+        assert_eq!(Some(64), result.write_concern_errors()[0].code());
+    }
+}
@@ -1,23 +1,53 @@
 use tracing::info;
 
 use crate::doc;
+use crate::error::StoreError;
 
-use crate::{Client, ClientOptions, Database};
+use crate::{Client, ClientOptions, ClientSession, Database};
+use futures_util::future::BoxFuture;
 use std::env::var;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
 
 const MONGO_HOST: &str = "MONGO_HOST";
 const MONGO_PORT: &str = "MONGO_PORT";
 const MONGO_USERNAME: &str = "MONGO_USERNAME";
 const MONGO_PASSWORD: &str = "MONGO_PASSWORD";
 const MONGO_ADMIN_DATABASE: &str = "MONGO_ADMIN_DATABASE";
+/// Transient failures are retried for at most this long, as the drivers do.
+const TRANSACTION_RETRY_TIMEOUT: Duration = Duration::from_secs(120);
+/// Session of a transaction run by `StoreClient::transaction`, passed where a
+/// `ClientSession` is expected. Its lifetime lets the transaction borrow from
+/// the caller.
 #[derive(Debug)]
+pub struct TransactionSession<'a> {
+    session: ClientSession,
+    _borrows: PhantomData<&'a ()>,
+}
+
+impl Deref for TransactionSession<'_> {
+    type Target = ClientSession;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
+impl DerefMut for TransactionSession<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.session
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct StoreClient {
     client: Client,
     application_db: String,
 }
 
 impl StoreClient {
-    pub async fn new(application_name: String) -> Result<StoreClient, Box<dyn std::error::Error>> {
+    pub async fn new(application_name: String) -> Result<StoreClient, StoreError> {
         let client = StoreClient::create_client(application_name.clone()).await?;
 
         Ok(StoreClient {
@@ -35,8 +65,66 @@ impl StoreClient {
         client.database(&self.application_db)
     }
 
+    /// Runs `f` in a transaction and commits it, or aborts it if `f` fails.
+    /// `f` runs again when the transaction hits a transient error, like a
+    /// write conflict, so it must only have effects through the session.
+    /// Needs a replica set.
+    ///
+    /// ```ignore
+    /// store_client
+    ///     .transaction(|session| Box::pin(async {
+    ///         users.replace_by_id_with_session(id, &user, ReturnDocument::After, session).await?;
+    ///         audit.insert_one_with_session(&record, session).await
+    ///     }))
+    ///     .await?;
+    /// ```
+    pub async fn transaction<'a, R, F>(&self, mut f: F) -> Result<R, StoreError>
+    where
+        F: for<'s> FnMut(&'s mut TransactionSession<'a>) -> BoxFuture<'s, Result<R, StoreError>>,
+    {
+        let mut session = TransactionSession {
+            session: self.client.start_session(None).await?,
+            _borrows: PhantomData,
+        };
+        let deadline = Instant::now() + TRANSACTION_RETRY_TIMEOUT;
+        'transaction: loop {
+            session.start_transaction(None).await?;
+            let value = match f(&mut session).await {
+                Ok(value) => value,
+                Err(e) => {
+                    // fails when the transaction is already aborted, which is fine
+                    let _ = session.abort_transaction().await;
+                    if e.is_transient_transaction_error() && Instant::now() < deadline {
+                        tracing::warn!("retrying transaction: {e}");
+                        continue 'transaction;
+                    }
+                    return Err(e);
+                }
+            };
+            loop {
+                let e = match session.commit_transaction().await {
+                    Ok(()) => return Ok(value),
+                    Err(e) => StoreError::from(e),
+                };
+                if Instant::now() >= deadline {
+                    return Err(e);
+                }
+                if e.is_unknown_commit_result() {
+                    tracing::warn!("retrying commit: {e}");
+                } else if e.is_transient_transaction_error() {
+                    tracing::warn!("retrying transaction: {e}");
+                    continue 'transaction;
+                } else {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Every failure here means the database cannot be used, they are all
+    /// reported as `StoreError::Connection`.
     #[tracing::instrument]
-    async fn create_client(application_name: String) -> Result<Client, Box<dyn std::error::Error>> {
+    async fn create_client(application_name: String) -> Result<Client, StoreError> {
         let mongo_host = var(MONGO_HOST).unwrap_or_else(|_| String::from("127.0.0.1"));
         let mongo_port = var(MONGO_PORT).unwrap_or_else(|_| String::from("27017"));
         let mongo_username = var(MONGO_USERNAME).unwrap_or_else(|_| String::from("root"));
@@ -45,13 +133,15 @@ impl StoreClient {
         let mut client_options = ClientOptions::parse(format!(
             "mongodb://{mongo_username}:{mongo_password}@{mongo_host}:{mongo_port}"
         ))
-        .await?;
+        .await
+        .map_err(StoreError::Connection)?;
         client_options.app_name = Some(application_name);
-        let client = Client::with_options(client_options)?;
+        let client = Client::with_options(client_options).map_err(StoreError::Connection)?;
         let _ = client
             .database(&mongo_admin_db)
             .run_command(doc! {"ping": 1}, None)
-            .await?;
+            .await
+            .map_err(StoreError::Connection)?;
         info!("Successfully connected");
         Ok(client)
     }
@@ -0,0 +1,216 @@
+use mongodb::error::{BulkWriteFailure, Error, ErrorKind, WriteFailure};
+use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use std::fmt::{Display, Formatter};
+
+const DUPLICATE_KEY: i32 = 11000;
+const NAMESPACE_NOT_FOUND: i32 = 26;
+const INDEX_OPTIONS_CONFLICT: i32 = 85;
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound {
+        id: String,
+    },
+    /// A unique index already holds the key, `index` is its name when the
+    /// server reported it. There is no `source` when it comes from an
+    /// `InMemoryRepository`.
+    DuplicateKey {
+        index: Option<String>,
+        source: Option<Error>,
+    },
+    /// The document was changed since it was read at `expected` version.
+    VersionConflict {
+        id: String,
+        expected: u32,
+    },
+    /// A request built with values the store cannot run, like a page 0.
+    InvalidArgument(String),
+    Connection(Error),
+    Serialization(Box<dyn std::error::Error + Send + Sync>),
+    Query(Error),
+    /// Reading or writing a file, like a JSON Lines export.
+    Io(std::io::Error),
+}
+
+impl StoreError {
+    /// The driver error behind this one, if any.
+    fn driver_error(&self) -> Option<&Error> {
+        match self {
+            StoreError::DuplicateKey { source, .. } => source.as_ref(),
+            StoreError::Connection(e) | StoreError::Query(e) => Some(e),
+            StoreError::Serialization(e) => e.downcast_ref::<Error>(),
+            _ => None,
+        }
+    }
+
+    /// The transaction failed but running it again may succeed.
+    pub fn is_transient_transaction_error(&self) -> bool {
+        self.driver_error()
+            .is_some_and(|e| e.contains_label(TRANSIENT_TRANSACTION_ERROR))
+    }
+
+    /// The commit may or may not have been applied, committing again is safe.
+    pub fn is_unknown_commit_result(&self) -> bool {
+        self.driver_error()
+            .is_some_and(|e| e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT))
+    }
+
+    /// An index with the same keys exists with other options, like another
+    /// TTL.
+    pub fn is_index_options_conflict(&self) -> bool {
+        self.driver_error().is_some_and(|e| {
+            matches!(e.kind.as_ref(), ErrorKind::Command(e) if e.code == INDEX_OPTIONS_CONFLICT)
+        })
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::NotFound { id } => write!(f, "document {id} not found"),
+            StoreError::DuplicateKey {
+                index: Some(index), ..
+            } => write!(f, "duplicate key on index {index}"),
+            StoreError::DuplicateKey {
+                index: None,
+                source: Some(source),
+            } => {
+                write!(f, "duplicate key: {source}")
+            }
+            StoreError::DuplicateKey {
+                index: None,
+                source: None,
+            } => write!(f, "duplicate key"),
+            StoreError::VersionConflict { id, expected } => write!(
+                f,
+                "document {id} is no longer at version {expected}, it was changed concurrently"
+            ),
+            StoreError::InvalidArgument(message) => write!(f, "invalid argument: {message}"),
+            StoreError::Connection(e) => write!(f, "could not reach the database: {e}"),
+            StoreError::Serialization(e) => write!(f, "could not (de)serialize document: {e}"),
+            StoreError::Query(e) => write!(f, "query failed: {e}"),
+            StoreError::Io(e) => write!(f, "could not read or write: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::DuplicateKey {
+                source: Some(source),
+                ..
+            } => Some(source),
+            StoreError::Connection(e) => Some(e),
+            StoreError::Serialization(e) => Some(e.as_ref()),
+            StoreError::Query(e) => Some(e),
+            StoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for StoreError {
+    fn from(e: Error) -> Self {
+        match e.kind.as_ref() {
+            ErrorKind::BsonSerialization(_) | ErrorKind::BsonDeserialization(_) => {
+                StoreError::Serialization(Box::new(e))
+            }
+            ErrorKind::Io(_)
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::DnsResolve { .. }
+            | ErrorKind::Authentication { .. }
+            | ErrorKind::InvalidTlsConfig { .. } => StoreError::Connection(e),
+            _ => match duplicate_key_message(&e) {
+                Some(message) => StoreError::DuplicateKey {
+                    index: index_name(&message),
+                    source: Some(e),
+                },
+                None => StoreError::Query(e),
+            },
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<mongodb::bson::ser::Error> for StoreError {
+    fn from(e: mongodb::bson::ser::Error) -> Self {
+        StoreError::Serialization(Box::new(e))
+    }
+}
+
+impl From<mongodb::bson::de::Error> for StoreError {
+    fn from(e: mongodb::bson::de::Error) -> Self {
+        StoreError::Serialization(Box::new(e))
+    }
+}
+
+fn duplicate_key_message(e: &Error) -> Option<String> {
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY => {
+            Some(e.message.clone())
+        }
+        ErrorKind::BulkWrite(BulkWriteFailure {
+            write_errors: Some(errors),
+            ..
+        }) => errors
+            .iter()
+            .find(|e| e.code == DUPLICATE_KEY)
+            .map(|e| e.message.clone()),
+        ErrorKind::Command(e) if e.code == DUPLICATE_KEY => Some(e.message.clone()),
+        _ => None,
+    }
+}
+
+/// The collection does not exist yet.
+pub(crate) fn is_namespace_not_found(e: &Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Command(e) if e.code == NAMESPACE_NOT_FOUND)
+}
+
+/// Extracts `email_1` from `E11000 duplicate key error collection: app.user
+/// index: email_1 dup key: { email: "kikoo@lol.com" }`.
+fn index_name(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("index: ")?;
+    rest.split_whitespace().next().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use crate::error::{index_name, StoreError};
+    use mongodb::error::{CommandError, Error, ErrorKind};
+
+    fn command_error(code: i32) -> StoreError {
+        let error: CommandError =
+            mongodb::bson::from_document(doc! {"code": code, "codeName": "", "errmsg": ""})
+                .unwrap();
+        StoreError::from(Error::from(ErrorKind::Command(error)))
+    }
+
+    #[test]
+    fn test_index_options_conflict() {
+        assert!(command_error(85).is_index_options_conflict());
+        assert!(!command_error(13).is_index_options_conflict());
+    }
+
+    #[test]
+    fn test_index_name() {
+        assert_eq!(
+            Some(String::from("email_1")),
+            index_name(
+                r#"E11000 duplicate key error collection: user_ms.user index: email_1 dup key: { email: "kikoo@lol.com" }"#
+            )
+        );
+        assert_eq!(
+            Some(String::from("_id_")),
+            index_name("E11000 duplicate key error index: _id_ dup key: { : 1 }")
+        );
+        assert_eq!(None, index_name("E11000 duplicate key error"));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{doc, Bson, Document};
+use mongodb::bson::Regex;
+use std::ops::Not;
+
+/// Query filter, built from typed operators instead of raw documents.
+/// Fields are anything that is a `str`, `FieldPath` constants included:
+/// `Filter::eq(User::PROFILE.join(&Profile::EMAIL_ADDRESS), email)`.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Filter(Document);
+
+impl Filter {
+    /// Matches every document.
+    pub fn all() -> Filter {
+        Filter::default()
+    }
+    fn field(field: impl AsRef<str>, condition: impl Into<Bson>) -> Filter {
+        let mut filter = Document::new();
+        filter.insert(field.as_ref(), condition.into());
+        Filter(filter)
+    }
+    fn operator(field: impl AsRef<str>, operator: &str, value: impl Into<Bson>) -> Filter {
+        let mut condition = Document::new();
+        condition.insert(operator, value.into());
+        Filter::field(field, condition)
+    }
+
+    pub fn eq(field: impl AsRef<str>, value: impl Into<Bson>) -> Filter {
+        Filter::field(field, value)
+    }
+    pub fn ne(field: impl AsRef<str>, value: impl Into<Bson>) -> Filter {
+        Filter::operator(field, "$ne", value)
+    }
+    pub fn gt(field: impl AsRef<str>, value: impl Into<Bson>) -> Filter {
+        Filter::operator(field, "$gt", value)
+    }
+    pub fn gte(field: impl AsRef<str>, value: impl Into<Bson>) -> Filter {
+        Filter::operator(field, "$gte", value)
+    }
+    pub fn lt(field: impl AsRef<str>, value: impl Into<Bson>) -> Filter {
+        Filter::operator(field, "$lt", value)
+    }
+    pub fn lte(field: impl AsRef<str>, value: impl Into<Bson>) -> Filter {
+        Filter::operator(field, "$lte", value)
+    }
+    /// `from <= field <= to`.
+    pub fn between(field: impl AsRef<str>, from: impl Into<Bson>, to: impl Into<Bson>) -> Filter {
+        Filter::field(field, doc! {"$gte": from.into(), "$lte": to.into()})
+    }
+    pub fn is_in<V: Into<Bson>>(
+        field: impl AsRef<str>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Filter {
+        Filter::operator(field, "$in", array(values))
+    }
+    pub fn not_in<V: Into<Bson>>(
+        field: impl AsRef<str>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Filter {
+        Filter::operator(field, "$nin", array(values))
+    }
+    /// `options` are regex flags like `i` for case insensitive.
+    pub fn regex(field: impl AsRef<str>, pattern: &str, options: &str) -> Filter {
+        // the server wants the options in alphabetical order
+        let mut options: Vec<char> = options.chars().collect();
+        options.sort_unstable();
+        let regex = Regex {
+            pattern: String::from(pattern),
+            options: options.into_iter().collect(),
+        };
+        Filter::field(field, regex)
+    }
+    pub fn exists(field: impl AsRef<str>, exists: bool) -> Filter {
+        Filter::operator(field, "$exists", exists)
+    }
+
+    /// Both filters must match.
+    pub fn and(self, other: Filter) -> Filter {
+        Filter::combine("$and", self, other)
+    }
+    /// Any of the filters must match.
+    pub fn or(self, other: Filter) -> Filter {
+        Filter::combine("$or", self, other)
+    }
+    fn combine(operator: &str, left: Filter, right: Filter) -> Filter {
+        if left.is_empty() {
+            return right;
+        }
+        if right.is_empty() {
+            return left;
+        }
+        let mut operands = Vec::new();
+        for filter in [left, right] {
+            match filter.combined(operator) {
+                Some(nested) => operands.extend(nested),
+                None => operands.push(Bson::Document(filter.0)),
+            }
+        }
+        Filter::field(operator, operands)
+    }
+    /// Operands of a filter that is only an `operator` combination, so that
+    /// chaining `and` gives one flat `$and`.
+    fn combined(&self, operator: &str) -> Option<Vec<Bson>> {
+        match self.0.get_array(operator) {
+            Ok(operands) if self.0.len() == 1 => Some(operands.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn to_document(&self) -> Document {
+        self.0.clone()
+    }
+    pub fn into_document(self) -> Document {
+        self.0
+    }
+}
+
+/// `!filter` matches the documents `filter` does not.
+impl Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter::field("$nor", vec![Bson::Document(self.0)])
+    }
+}
+
+impl From<Filter> for Document {
+    fn from(filter: Filter) -> Self {
+        filter.0
+    }
+}
+
+impl From<Filter> for Option<Document> {
+    fn from(filter: Filter) -> Self {
+        Some(filter.0)
+    }
+}
+
+fn array<V: Into<Bson>>(values: impl IntoIterator<Item = V>) -> Vec<Bson> {
+    values.into_iter().map(Into::into).collect()
+}
+
+/// Sort order, fields are sorted on in the order they are added.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Sort(Document);
+
+impl Sort {
+    pub fn new() -> Sort {
+        Sort::default()
+    }
+    pub fn asc(mut self, field: impl AsRef<str>) -> Self {
+        self.0.insert(field.as_ref(), 1);
+        self
+    }
+    pub fn desc(mut self, field: impl AsRef<str>) -> Self {
+        self.0.insert(field.as_ref(), -1);
+        self
+    }
+    pub fn to_document(&self) -> Document {
+        self.0.clone()
+    }
+}
+
+impl From<Sort> for Document {
+    fn from(sort: Sort) -> Self {
+        sort.0
+    }
+}
+
+/// Fields to return. The server does not allow mixing included and
+/// excluded fields, except for excluding `_id`.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Projection(Document);
+
+impl Projection {
+    pub fn new() -> Projection {
+        Projection::default()
+    }
+    pub fn include(mut self, field: impl AsRef<str>) -> Self {
+        self.0.insert(field.as_ref(), 1);
+        self
+    }
+    pub fn exclude(mut self, field: impl AsRef<str>) -> Self {
+        self.0.insert(field.as_ref(), 0);
+        self
+    }
+    pub fn to_document(&self) -> Document {
+        self.0.clone()
+    }
+}
+
+impl From<Projection> for Document {
+    fn from(projection: Projection) -> Self {
+        projection.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, Projection, Sort};
+    use crate::{doc, Bson};
+    use domain::{Address, FieldPath, Profile, User};
+    use mongodb::bson::Regex;
+
+    #[test]
+    fn test_filter() {
+        let email = User::PROFILE.join(&Profile::EMAIL_ADDRESS);
+        let country = User::PROFILE
+            .join(&Profile::ADDRESS)
+            .join(&Address::COUNTRY);
+        let filter = Filter::eq(&email, "nordine@keke.com")
+            .and(Filter::is_in(User::ROLES, ["admin", "user"]))
+            .and(Filter::between("age", 18, 65))
+            .and(Filter::exists(&country, true).or(Filter::ne(&country, "Belgium")))
+            .and(!Filter::regex(User::NICKNAME, "^bot", "xi"));
+        assert_eq!(
+            doc! {"$and": [
+                {"profile.email_address": "nordine@keke.com"},
+                {"roles": {"$in": ["admin", "user"]}},
+                {"age": {"$gte": 18, "$lte": 65}},
+                {"$or": [
+                    {"profile.address.country": {"$exists": true}},
+                    {"profile.address.country": {"$ne": "Belgium"}},
+                ]},
+                {"$nor": [{"nickname": Bson::RegularExpression(Regex {
+                    pattern: String::from("^bot"),
+                    options: String::from("ix"),
+                })}]},
+            ]},
+            filter.into_document()
+        );
+        assert_eq!(
+            doc! {"roles": {"$nin": ["guest"]}},
+            Filter::all()
+                .and(Filter::not_in("roles", ["guest"]))
+                .to_document()
+        );
+        assert!(Filter::all().or(Filter::all()).is_empty());
+    }
+
+    #[test]
+    fn test_sort_and_projection() {
+        let sort = Sort::new()
+            .desc(User::DOMAIN_METADATA.join(&domain::Metadata::CREATION_DATE))
+            .asc(FieldPath::new("_id"));
+        assert_eq!(
+            doc! {"domain_metadata.creation_date": -1, "_id": 1},
+            sort.to_document()
+        );
+        let projection = Projection::new()
+            .include(User::NICKNAME)
+            .include(User::PROFILE)
+            .exclude("_id");
+        assert_eq!(
+            doc! {"nickname": 1, "profile": 1, "_id": 0},
+            projection.to_document()
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use crate::filter::{Filter, Sort};
+use crate::{Bson, Document, IndexModel, IndexOptions};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// Index declared for a collection, created by `Repository::ensure_indexes`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct IndexSpec {
+    keys: Document,
+    name: Option<String>,
+    unique: bool,
+    expire_after: Option<Duration>,
+    partial: Option<Document>,
+}
+
+impl IndexSpec {
+    /// Index on the fields of `keys` in their order, compound when there
+    /// are more than one.
+    pub fn new(keys: Sort) -> IndexSpec {
+        IndexSpec {
+            keys: keys.into(),
+            name: None,
+            unique: false,
+            expire_after: None,
+            partial: None,
+        }
+    }
+    pub fn asc(field: impl AsRef<str>) -> IndexSpec {
+        IndexSpec::new(Sort::new().asc(field))
+    }
+    /// Text index over `fields`, a collection has at most one.
+    pub fn text<F: AsRef<str>>(fields: impl IntoIterator<Item = F>) -> IndexSpec {
+        let mut keys = Document::new();
+        for field in fields {
+            keys.insert(field.as_ref(), "text");
+        }
+        IndexSpec {
+            keys,
+            ..IndexSpec::new(Sort::new())
+        }
+    }
+    /// Defaults to the name the server would give, like `email_1`.
+    pub fn set_name(self, name: &str) -> Self {
+        IndexSpec {
+            name: Some(String::from(name)),
+            ..self
+        }
+    }
+    pub fn set_unique(self, unique: bool) -> Self {
+        IndexSpec { unique, ..self }
+    }
+    /// Makes it a TTL index: documents are removed `expire_after` the date
+    /// held by the indexed field.
+    pub fn set_expire_after(self, expire_after: Duration) -> Self {
+        IndexSpec {
+            expire_after: Some(expire_after),
+            ..self
+        }
+    }
+    /// Only indexes the documents matching `filter`.
+    pub fn set_partial(self, filter: Filter) -> Self {
+        IndexSpec {
+            partial: Some(filter.into()),
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.keys
+                .iter()
+                .map(|(field, kind)| match kind {
+                    // not through `Bson`'s Display, which quotes strings
+                    Bson::String(kind) => format!("{field}_{kind}"),
+                    kind => format!("{field}_{kind}"),
+                })
+                .collect::<Vec<_>>()
+                .join("_")
+        })
+    }
+    pub fn keys(&self) -> &Document {
+        &self.keys
+    }
+    pub fn unique(&self) -> bool {
+        self.unique
+    }
+    pub fn expire_after(&self) -> Option<Duration> {
+        self.expire_after
+    }
+    pub fn partial(&self) -> &Option<Document> {
+        &self.partial
+    }
+    fn is_text(&self) -> bool {
+        self.keys.values().any(|kind| kind.as_str() == Some("text"))
+    }
+
+    pub(crate) fn to_model(&self) -> IndexModel {
+        let options = IndexOptions::builder()
+            .name(self.name())
+            .unique(self.unique.then_some(true))
+            .expire_after(self.expire_after)
+            .partial_filter_expression(self.partial.clone())
+            .build();
+        IndexModel::builder()
+            .keys(self.keys.clone())
+            .options(options)
+            .build()
+    }
+
+    /// How `existing` differs from this declaration, empty when it matches.
+    fn differences(&self, existing: &IndexModel) -> Vec<String> {
+        let options = existing.options.clone().unwrap_or_default();
+        let mut differences = Vec::new();
+        let name = options.name.unwrap_or_default();
+        if name != self.name() {
+            differences.push(format!("named {name} instead of {}", self.name()));
+        }
+        if !self.same_keys(existing) {
+            differences.push(format!(
+                "keys are {} instead of {}",
+                existing.keys, self.keys
+            ));
+        }
+        if options.unique.unwrap_or(false) != self.unique {
+            differences.push(format!("unique is {}", !self.unique));
+        }
+        if options.expire_after != self.expire_after {
+            differences.push(format!(
+                "expires after {:?} instead of {:?}",
+                options.expire_after, self.expire_after
+            ));
+        }
+        if options.partial_filter_expression != self.partial {
+            differences.push(format!(
+                "partial filter is {:?} instead of {:?}",
+                options.partial_filter_expression, self.partial
+            ));
+        }
+        differences
+    }
+
+    fn same_keys(&self, existing: &IndexModel) -> bool {
+        if self.is_text() {
+            // the server lists text indexes as `{_fts: "text", _ftsx: 1}`
+            // with the fields in their weights
+            let weights = existing
+                .options
+                .as_ref()
+                .and_then(|options| options.weights.as_ref());
+            let fields = |keys: &Document| keys.keys().cloned().collect::<BTreeSet<_>>();
+            return weights.map(fields) == Some(fields(&self.keys));
+        }
+        self.keys.len() == existing.keys.len()
+            && self.keys.iter().zip(existing.keys.iter()).all(
+                |((field, kind), (other_field, other_kind))| {
+                    field == other_field && same_kind(kind, other_kind)
+                },
+            )
+    }
+}
+
+/// Key directions may come back as doubles or longs.
+fn same_kind(kind: &Bson, other: &Bson) -> bool {
+    let number = |kind: &Bson| match kind {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    };
+    match (number(kind), number(other)) {
+        (Some(kind), Some(other)) => kind == other,
+        _ => kind == other,
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum IndexDrift {
+    /// Declared but defined differently on the collection, left as it is:
+    /// drop it to have it created again as declared.
+    Changed {
+        name: String,
+        differences: Vec<String>,
+    },
+    /// On the collection but not declared.
+    Undeclared { name: String },
+}
+
+/// Outcome of `Repository::ensure_indexes`.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct IndexReport {
+    created: Vec<String>,
+    unchanged: Vec<String>,
+    drift: Vec<IndexDrift>,
+}
+
+impl IndexReport {
+    /// Compares the `declared` indexes to the `existing` ones, returning the
+    /// report and the indexes to create.
+    pub(crate) fn compare(
+        declared: &[IndexSpec],
+        existing: &[IndexModel],
+    ) -> (IndexReport, Vec<IndexSpec>) {
+        let mut report = IndexReport::default();
+        let mut missing = Vec::new();
+        let mut matched = BTreeSet::new();
+        for spec in declared {
+            let found = existing
+                .iter()
+                .position(|index| index_name(index) == spec.name())
+                .or_else(|| existing.iter().position(|index| spec.same_keys(index)));
+            match found {
+                None => {
+                    report.created.push(spec.name());
+                    missing.push(spec.clone());
+                }
+                Some(position) => {
+                    matched.insert(position);
+                    let differences = spec.differences(&existing[position]);
+                    if differences.is_empty() {
+                        report.unchanged.push(spec.name());
+                    } else {
+                        report.drift.push(IndexDrift::Changed {
+                            name: spec.name(),
+                            differences,
+                        });
+                    }
+                }
+            }
+        }
+        for (position, index) in existing.iter().enumerate() {
+            let name = index_name(index);
+            if !matched.contains(&position) && name != "_id_" {
+                report.drift.push(IndexDrift::Undeclared { name });
+            }
+        }
+        (report, missing)
+    }
+
+    pub fn created(&self) -> &Vec<String> {
+        &self.created
+    }
+    pub fn unchanged(&self) -> &Vec<String> {
+        &self.unchanged
+    }
+    pub fn drift(&self) -> &Vec<IndexDrift> {
+        &self.drift
+    }
+    pub fn has_drift(&self) -> bool {
+        !self.drift.is_empty()
+    }
+}
+
+fn index_name(index: &IndexModel) -> String {
+    index
+        .options
+        .as_ref()
+        .and_then(|options| options.name.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::{IndexDrift, IndexReport, IndexSpec};
+    use crate::{doc, Filter, IndexModel, IndexOptions, Sort};
+    use std::time::Duration;
+
+    fn existing(keys: mongodb::bson::Document, options: IndexOptions) -> IndexModel {
+        IndexModel::builder().keys(keys).options(options).build()
+    }
+
+    #[test]
+    fn test_index_spec() {
+        let compound = IndexSpec::new(Sort::new().asc("nickname").desc("created_at"));
+        assert_eq!("nickname_1_created_at_-1", compound.name());
+        assert_eq!(
+            "bio_text_title_text",
+            IndexSpec::text(["bio", "title"]).name()
+        );
+        let ttl = IndexSpec::asc("processed_at")
+            .set_name("expiry")
+            .set_expire_after(Duration::from_secs(60))
+            .set_partial(Filter::exists("processed_at", true));
+        let options = ttl.to_model().options.unwrap();
+        assert_eq!(Some(String::from("expiry")), options.name);
+        assert_eq!(None, options.unique);
+        assert_eq!(Some(Duration::from_secs(60)), options.expire_after);
+        assert_eq!(
+            Some(doc! {"processed_at": {"$exists": true}}),
+            options.partial_filter_expression
+        );
+    }
+
+    #[test]
+    fn test_index_report() {
+        let declared = vec![
+            IndexSpec::asc("nickname").set_unique(true),
+            IndexSpec::asc("profile.email_address").set_unique(true),
+            IndexSpec::text(["bio"]),
+            IndexSpec::asc("created_at"),
+        ];
+        let existing = vec![
+            existing(
+                doc! {"_id": 1},
+                IndexOptions::builder().name(String::from("_id_")).build(),
+            ),
+            existing(
+                doc! {"nickname": 1.0},
+                IndexOptions::builder()
+                    .name(String::from("nickname_1"))
+                    .unique(true)
+                    .build(),
+            ),
+            existing(
+                doc! {"profile.email_address": 1},
+                IndexOptions::builder()
+                    .name(String::from("profile.email_address_1"))
+                    .build(),
+            ),
+            existing(
+                doc! {"_fts": "text", "_ftsx": 1},
+                IndexOptions::builder()
+                    .name(String::from("bio_text"))
+                    .weights(doc! {"bio": 1})
+                    .build(),
+            ),
+            existing(
+                doc! {"roles": 1},
+                IndexOptions::builder()
+                    .name(String::from("roles_1"))
+                    .build(),
+            ),
+        ];
+        let (report, missing) = IndexReport::compare(&declared, &existing);
+        assert_eq!(&vec![String::from("created_at_1")], report.created());
+        assert_eq!(vec![declared[3].clone()], missing);
+        assert_eq!(
+            &vec![String::from("nickname_1"), String::from("bio_text")],
+            report.unchanged()
+        );
+        assert_eq!(
+            &vec![
+                IndexDrift::Changed {
+                    name: String::from("profile.email_address_1"),
+                    differences: vec![String::from("unique is false")],
+                },
+                IndexDrift::Undeclared {
+                    name: String::from("roles_1")
+                },
+            ],
+            report.drift()
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use crate::bulk::{BulkOptions, BulkWriteResult, WriteOp};
+use crate::error::StoreError;
+use crate::filter::{Filter, Sort};
+use crate::repository::Repository;
+use domain::{Serialize, WithJsonProcessor};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    include_deleted: bool,
+}
+
+impl ExportOptions {
+    /// Exports the soft deleted entities too, as a backup must to keep them
+    /// recoverable. Importing them keeps them deleted.
+    pub fn set_include_deleted(self, include_deleted: bool) -> Self {
+        ExportOptions { include_deleted }
+    }
+    pub fn include_deleted(&self) -> bool {
+        self.include_deleted
+    }
+}
+
+/// Writes the entities matching `filter` to `writer` as JSON Lines, one
+/// entity per line in `_id` order, returning how many there were.
+pub async fn export_jsonl<T, R, W>(
+    repository: &R,
+    filter: Filter,
+    options: ExportOptions,
+    writer: &mut W,
+) -> Result<u64, StoreError>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+    T: for<'a> WithJsonProcessor<'a>,
+    R: Repository<T> + Sync + ?Sized,
+    W: AsyncWrite + Unpin,
+{
+    let sort = Some(Sort::new().asc("_id"));
+    let mut entities = if options.include_deleted() {
+        repository.find_by_with_deleted(filter, sort).await?
+    } else {
+        repository.find_by(filter, sort).await?
+    };
+    let mut exported = 0;
+    while let Some(entity) = entities.try_next().await? {
+        let mut line = entity
+            .to_json()
+            .map_err(|e| StoreError::Serialization(e.into()))?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+        exported += 1;
+    }
+    writer.flush().await?;
+    Ok(exported)
+}
+
+/// Inserts the entities read from `reader` as JSON Lines, `bulk_write`
+/// batch by batch. Blank lines are skipped, so error indexes count the
+/// entities rather than the lines. An unordered import goes on past the
+/// entities already stored, reporting them as duplicate keys, so running it
+/// again adds the missing ones. A line that is not an entity stops the
+/// import, the batches before it being written.
+pub async fn import_jsonl<T, R, B>(
+    repository: &R,
+    reader: B,
+    options: BulkOptions,
+) -> Result<BulkWriteResult, StoreError>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+    T: for<'a> WithJsonProcessor<'a, Output = T>,
+    R: Repository<T> + ?Sized,
+    B: AsyncBufRead + Unpin,
+{
+    let mut lines = reader.lines();
+    let mut result = BulkWriteResult::default();
+    let mut batch = Vec::new();
+    let mut offset = 0;
+    let mut number = 0;
+    loop {
+        let line = lines.next_line().await?;
+        let done = line.is_none();
+        if let Some(line) = line {
+            number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entity = T::from_json(&line).map_err(|e| {
+                StoreError::Serialization(format!("line {number} is not valid: {e}").into())
+            })?;
+            batch.push(WriteOp::Insert(entity));
+        }
+        if batch.len() == options.batch_size() || (done && !batch.is_empty()) {
+            let size = batch.len();
+            let writes = stream::iter(std::mem::take(&mut batch)).boxed();
+            result.merge(repository.bulk_write(writes, options).await?, offset);
+            offset += size;
+            if options.ordered() && result.has_errors() {
+                break;
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jsonl::{export_jsonl, import_jsonl, ExportOptions};
+    use crate::{BulkOptions, Filter, InMemoryRepository, Repository, StoreError};
+    use domain::{Deserialize, Id, Serialize, WithJsonProcessor};
+    use futures_util::TryStreamExt;
+
+    #[derive(Debug, Default, Clone, PartialEq, WithJsonProcessor, Serialize, Deserialize)]
+    struct Book {
+        #[serde(rename = "_id")]
+        id: Id,
+        title: String,
+    }
+
+    fn book(title: &str) -> Book {
+        Book {
+            title: String::from(title),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let source = InMemoryRepository::new();
+        let books = vec![
+            book("East of Eden"),
+            book("Cannery Row"),
+            book("Tortilla Flat"),
+        ];
+        for book in &books {
+            source.insert_one(book).await.unwrap();
+        }
+        let mut exported = Vec::new();
+        assert_eq!(
+            3,
+            export_jsonl(
+                &source,
+                Filter::all(),
+                ExportOptions::default(),
+                &mut exported
+            )
+            .await
+            .unwrap()
+        );
+        assert_eq!(3, exported.iter().filter(|b| **b == b'\n').count());
+
+        let target = InMemoryRepository::new();
+        target.insert_one(&books[1]).await.unwrap();
+        let mut lines = exported.clone();
+        lines.extend_from_slice(b"\n");
+        let options = BulkOptions::default().set_ordered(false).set_batch_size(2);
+        let imported = import_jsonl(&target, lines.as_slice(), options)
+            .await
+            .unwrap();
+        assert_eq!(2, imported.inserted());
+        assert_eq!(1, imported.errors().len());
+        let mut stored: Vec<Book> = target
+            .find_all()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        stored.sort_by(|a, b| a.id.as_str().cmp(b.id.as_str()));
+        let mut expected = books.clone();
+        expected.sort_by(|a, b| a.id.as_str().cmp(b.id.as_str()));
+        assert_eq!(expected, stored);
+
+        let invalid = b"{\"_id\": \"1\", \"title\": \"Sweet Thursday\"}\nnot json\n";
+        assert!(matches!(
+            import_jsonl(&InMemoryRepository::<Book>::new(), &invalid[..], options).await,
+            Err(StoreError::Serialization(e)) if e.to_string().starts_with("line 2")
+        ));
+    }
+}
@@ -1,12 +1,41 @@
+mod bulk;
 mod client;
+mod error;
+mod filter;
+mod index;
+mod jsonl;
+mod memory;
+mod migration;
+mod page;
 mod repository;
+mod update;
+mod watch;
 
-pub use client::StoreClient;
-pub use mongodb::bson::{doc, oid::ObjectId, to_document, Document};
-pub use mongodb::{options::ClientOptions, Client, Collection, Database};
+pub use bulk::{BulkOptions, BulkWriteError, BulkWriteResult, WriteConcernError, WriteOp};
+pub use client::{StoreClient, TransactionSession};
+pub use error::StoreError;
+pub use filter::{Filter, Projection, Sort};
+pub use index::{IndexDrift, IndexReport, IndexSpec};
+pub use jsonl::{export_jsonl, import_jsonl, ExportOptions};
+pub use memory::InMemoryRepository;
+pub use migration::{
+    AppliedMigration, Migration, MigrationCommand, MigrationError, MigrationState, MigrationStatus,
+    Migrator, MIGRATIONS_COLLECTION,
+};
+pub use mongodb::bson::{
+    doc, from_document, oid::ObjectId, to_bson, to_document, Bson, DateTime, Document,
+};
+pub use mongodb::{options::ClientOptions, Client, ClientSession, Collection, Database};
 pub use mongodb::{
-    options::FindOptions, results::DeleteResult, results::InsertManyResult,
-    results::InsertOneResult, results::UpdateResult, Cursor,
+    options::FindOneAndReplaceOptions, options::FindOneAndUpdateOptions, options::FindOptions,
+    options::ReplaceOptions, options::ReturnDocument, results::DeleteResult,
+    results::InsertManyResult, results::InsertOneResult, results::UpdateResult, Cursor,
 };
-pub use repository::{MongoRepository, Page, Repository};
+pub use mongodb::{options::IndexOptions, IndexModel};
+pub use page::{Keyset, KeysetPage, KeysetResult, Page, PageResult, MAX_PAGE_SIZE};
+pub use repository::{EntityStream, MongoRepository, Repository, SessionRepository};
+pub use update::Update;
 pub use uuid::Uuid;
+pub use watch::{
+    Change, ChangeEvent, ChangeStream, ResumeToken, ResumeTokens, RESUME_TOKENS_COLLECTION,
+};
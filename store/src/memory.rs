@@ -0,0 +1,1224 @@
+use crate::bulk::{BulkOptions, BulkWriteError, BulkWriteResult, WriteKind, WriteOp};
+use crate::error::StoreError;
+use crate::filter::{Filter, Sort};
+use crate::index::{IndexReport, IndexSpec};
+use crate::page::{KeysetPage, KeysetResult, Page, PageResult};
+use crate::repository::{EntityStream, Repository};
+use crate::update::Update;
+use crate::watch::{Change, ChangeEvent, ChangeStream, ResumeToken};
+use crate::{doc, from_document, to_document, Bson, Document, ObjectId, ReturnDocument};
+use domain::{Serialize, WithMetadata};
+use futures_util::stream;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+/// In-process stand-in for a `MongoRepository`, meant for tests.
+///
+/// Entities are kept as the documents Mongo would store and queried with
+/// the operators `Filter` builds: comparisons, `$in`, `$nin`, `$exists`,
+/// regexes, `$and`, `$or` and `$nor`, on nested paths and arrays. Once
+/// ensured, unique indexes are enforced; the other indexes are only
+/// recorded. Clones share the same documents.
+///
+/// Every change is kept for `watch`, whose resume tokens are positions in
+/// that history. Updates list the top-level fields that changed rather than
+/// the paths the server would.
+pub struct InMemoryRepository<T> {
+    state: Arc<Mutex<State>>,
+    indexes: Vec<IndexSpec>,
+    entity: PhantomData<fn() -> T>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    documents: Vec<Document>,
+    indexes: Vec<IndexSpec>,
+    changes: Vec<ChangeEvent<Document>>,
+    changed: Arc<Notify>,
+}
+
+impl<T> InMemoryRepository<T> {
+    pub fn new() -> Self {
+        InMemoryRepository {
+            state: Default::default(),
+            indexes: Vec::new(),
+            entity: PhantomData,
+        }
+    }
+    pub fn set_indexes(self, indexes: Vec<IndexSpec>) -> Self {
+        InMemoryRepository { indexes, ..self }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl<T> Default for InMemoryRepository<T> {
+    fn default() -> Self {
+        InMemoryRepository::new()
+    }
+}
+
+impl<T> Clone for InMemoryRepository<T> {
+    fn clone(&self) -> Self {
+        InMemoryRepository {
+            state: Arc::clone(&self.state),
+            indexes: self.indexes.clone(),
+            entity: PhantomData,
+        }
+    }
+}
+
+impl State {
+    fn position(&self, id: &str) -> Option<usize> {
+        let id = Bson::String(String::from(id));
+        self.documents
+            .iter()
+            .position(|document| document.get("_id") == Some(&id))
+    }
+
+    fn matching(&self, query: &Document) -> Result<Vec<Document>, StoreError> {
+        let mut documents = Vec::new();
+        for document in &self.documents {
+            if matches(document, query)? {
+                documents.push(document.clone());
+            }
+        }
+        Ok(documents)
+    }
+
+    /// Fails if `document` would break a unique index, not counting the
+    /// document at `replacing`.
+    fn check_unique(
+        &self,
+        document: &Document,
+        replacing: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let others = self
+            .documents
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| Some(*position) != replacing)
+            .map(|(_, other)| other);
+        for other in others {
+            if same(
+                document.get("_id").unwrap_or(&Bson::Null),
+                other.get("_id").unwrap_or(&Bson::Null),
+            ) {
+                return Err(duplicate_key("_id_"));
+            }
+            let unique = self
+                .indexes
+                .iter()
+                .filter(|index| index.unique() && index.partial().is_none());
+            for index in unique {
+                let key = |document: &Document| {
+                    index
+                        .keys()
+                        .keys()
+                        .map(|field| first(document, field))
+                        .collect::<Vec<_>>()
+                };
+                let (key, other_key) = (key(document), key(other));
+                if key.iter().zip(other_key.iter()).all(|(a, b)| same(a, b)) {
+                    return Err(duplicate_key(&index.name()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, document: Document) -> Result<Bson, StoreError> {
+        self.check_unique(&document, None)?;
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        self.record(ChangeEvent::Inserted {
+            id: id.clone(),
+            document: document.clone(),
+        });
+        self.documents.push(document);
+        Ok(id)
+    }
+
+    fn replace(&mut self, position: usize, document: Document) -> Result<(), StoreError> {
+        self.check_unique(&document, Some(position))?;
+        self.record(ChangeEvent::Replaced {
+            id: document.get("_id").cloned().unwrap_or(Bson::Null),
+            document: document.clone(),
+        });
+        self.documents[position] = document;
+        Ok(())
+    }
+
+    fn update(&mut self, position: usize, document: Document) -> Result<(), StoreError> {
+        self.check_unique(&document, Some(position))?;
+        let before = &self.documents[position];
+        let updated_fields = document
+            .iter()
+            .filter(|(field, value)| before.get(field) != Some(value))
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        let removed_fields = before
+            .keys()
+            .filter(|field| !document.contains_key(field))
+            .cloned()
+            .collect();
+        self.record(ChangeEvent::Updated {
+            id: document.get("_id").cloned().unwrap_or(Bson::Null),
+            updated_fields,
+            removed_fields,
+            document: Some(document.clone()),
+        });
+        self.documents[position] = document;
+        Ok(())
+    }
+
+    fn remove(&mut self, position: usize) -> Document {
+        let document = self.documents.remove(position);
+        self.record(ChangeEvent::Deleted {
+            id: document.get("_id").cloned().unwrap_or(Bson::Null),
+        });
+        document
+    }
+
+    /// Runs one write of a bulk, returning its kind and the number of
+    /// documents matched, modified and upserted.
+    fn write<T: Serialize>(
+        &mut self,
+        write: WriteOp<T>,
+    ) -> Result<(WriteKind, u64, u64, u64), StoreError> {
+        let kind = write.kind();
+        match write {
+            WriteOp::Insert(entity) => {
+                self.insert(stored(&entity, None)?)?;
+                Ok((kind, 1, 0, 0))
+            }
+            WriteOp::Replace { id, entity, upsert } => {
+                let document = stored(&entity, Some(&id))?;
+                match self.position(&id) {
+                    Some(position) => {
+                        let modified = self.documents[position] != document;
+                        self.replace(position, document)?;
+                        Ok((kind, 1, modified as u64, 0))
+                    }
+                    None if upsert => {
+                        self.insert(document)?;
+                        Ok((kind, 0, 0, 1))
+                    }
+                    None => Ok((kind, 0, 0, 0)),
+                }
+            }
+            WriteOp::Update { id, update } => match self.position(&id) {
+                Some(position) => {
+                    let mut document = self.documents[position].clone();
+                    apply(&mut document, &update.to_document())?;
+                    let modified = self.documents[position] != document;
+                    if modified {
+                        self.update(position, document)?;
+                    }
+                    Ok((kind, 1, modified as u64, 0))
+                }
+                None => Ok((kind, 0, 0, 0)),
+            },
+            WriteOp::Delete { id } => match self.position(&id) {
+                Some(position) => {
+                    self.remove(position);
+                    Ok((kind, 1, 0, 0))
+                }
+                None => Ok((kind, 0, 0, 0)),
+            },
+        }
+    }
+
+    fn record(&mut self, change: ChangeEvent<Document>) {
+        self.changes.push(change);
+        self.changed.notify_waiters();
+    }
+}
+
+/// The token of the change at `position` in the history.
+fn resume_token(position: usize) -> Result<ResumeToken, StoreError> {
+    Ok(mongodb::bson::from_bson(Bson::from(
+        doc! {"_data": position as i64},
+    ))?)
+}
+
+/// Where the history continues after the change of `token`.
+fn resume_position(token: &ResumeToken, changes: usize) -> Result<usize, StoreError> {
+    let token = mongodb::bson::to_bson(token)?;
+    match token
+        .as_document()
+        .and_then(|token| token.get_i64("_data").ok())
+    {
+        Some(position) if 0 <= position && (position as usize) < changes => {
+            Ok(position as usize + 1)
+        }
+        _ => Err(StoreError::InvalidArgument(format!(
+            "{token} is not a resume token of this repository"
+        ))),
+    }
+}
+
+fn duplicate_key(index: &str) -> StoreError {
+    StoreError::DuplicateKey {
+        index: Some(String::from(index)),
+        source: None,
+    }
+}
+
+/// The document stored for `entity`, with an `_id` like the server adds.
+fn stored<T: Serialize>(entity: &T, id: Option<&str>) -> Result<Document, StoreError> {
+    let document = to_document(entity)?;
+    if document.contains_key("_id") {
+        return Ok(document);
+    }
+    let mut with_id = Document::new();
+    match id {
+        Some(id) => with_id.insert("_id", id),
+        None => with_id.insert("_id", ObjectId::new()),
+    };
+    with_id.extend(document);
+    Ok(with_id)
+}
+
+fn decode<T: DeserializeOwned>(document: Document) -> Result<T, StoreError> {
+    Ok(from_document(document)?)
+}
+
+fn decode_all<T: DeserializeOwned>(documents: Vec<Document>) -> Result<Vec<T>, StoreError> {
+    documents.into_iter().map(decode).collect()
+}
+
+fn returned<T: DeserializeOwned>(
+    before: Document,
+    after: &Document,
+    returning: &ReturnDocument,
+) -> Result<T, StoreError> {
+    if matches!(returning, ReturnDocument::Before) {
+        decode(before)
+    } else {
+        decode(after.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Repository<T> for InMemoryRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    fn indexes(&self) -> &[IndexSpec] {
+        &self.indexes
+    }
+    async fn ensure_indexes(&self) -> Result<IndexReport, StoreError> {
+        let mut state = self.state();
+        let existing: Vec<_> = state.indexes.iter().map(IndexSpec::to_model).collect();
+        let (report, missing) = IndexReport::compare(&self.indexes, &existing);
+        state.indexes.extend(missing);
+        Ok(report)
+    }
+    async fn find_by(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+    ) -> Result<EntityStream<T>, StoreError> {
+        let mut documents = self.state().matching(&filter.into_document())?;
+        if let Some(sort) = sort {
+            sort_documents(&mut documents, &sort.into());
+        }
+        let entities: Vec<Result<T, StoreError>> = documents.into_iter().map(decode).collect();
+        Ok(stream::iter(entities).boxed())
+    }
+    async fn find_one_by(&self, filter: Filter) -> Result<Option<T>, StoreError> {
+        let documents = self.state().matching(&filter.into_document())?;
+        documents.into_iter().next().map(decode).transpose()
+    }
+    async fn count_by(&self, filter: Filter) -> Result<u64, StoreError> {
+        Ok(self.state().matching(&filter.into_document())?.len() as u64)
+    }
+    async fn find_page(
+        &self,
+        query: Option<Document>,
+        page: Page,
+    ) -> Result<PageResult<T>, StoreError> {
+        page.validate()?;
+        let mut documents = self.state().matching(&query.unwrap_or_default())?;
+        let total = documents.len() as u64;
+        if let Some(sort) = page.sort() {
+            sort_documents(&mut documents, sort);
+        }
+        let documents = documents
+            .into_iter()
+            .skip(page.skip() as usize)
+            .take(page.limit() as usize)
+            .collect();
+        Ok(PageResult::new(decode_all(documents)?, total, &page))
+    }
+    async fn find_keyset(
+        &self,
+        query: Option<Document>,
+        page: KeysetPage,
+    ) -> Result<KeysetResult<T>, StoreError> {
+        let mut documents = self
+            .state()
+            .matching(&page.filter(query.unwrap_or_default()))?;
+        sort_documents(&mut documents, &page.sort());
+        let next = if documents.len() as i64 > page.limit() {
+            documents.truncate(page.limit() as usize);
+            documents
+                .last()
+                .map(|last| page.keyset_of(last))
+                .transpose()?
+        } else {
+            None
+        };
+        Ok(KeysetResult::new(decode_all(documents)?, next))
+    }
+    async fn delete_many(&self, query: Option<Document>) -> Result<u64, StoreError> {
+        let query = query.unwrap_or_default();
+        let mut state = self.state();
+        let mut deleted = Vec::new();
+        for (position, document) in state.documents.iter().enumerate() {
+            if matches(document, &query)? {
+                deleted.push(position);
+            }
+        }
+        for position in deleted.iter().rev() {
+            state.remove(*position);
+        }
+        Ok(deleted.len() as u64)
+    }
+    async fn insert_many(&self, data: &[T]) -> Result<Vec<Bson>, StoreError> {
+        let mut state = self.state();
+        let mut ids = Vec::new();
+        for entity in data {
+            ids.push(state.insert(stored(entity, None)?)?);
+        }
+        Ok(ids)
+    }
+    async fn insert_one(&self, data: &T) -> Result<Bson, StoreError> {
+        self.state().insert(stored(data, None)?)
+    }
+    async fn find_by_id(&self, id: &str) -> Result<Option<T>, StoreError> {
+        let state = self.state();
+        let document = state.position(id).map(|p| state.documents[p].clone());
+        document.map(decode).transpose()
+    }
+    async fn delete_by_id(&self, id: String) -> Result<Option<T>, StoreError> {
+        let mut state = self.state();
+        let document = state.position(&id).map(|p| state.remove(p));
+        document.map(decode).transpose()
+    }
+    async fn replace_by_id(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+    ) -> Result<T, StoreError> {
+        let mut state = self.state();
+        let position = state.position(id).ok_or_else(|| StoreError::NotFound {
+            id: String::from(id),
+        })?;
+        let document = stored(entity, Some(id))?;
+        let before = state.documents[position].clone();
+        state.replace(position, document)?;
+        returned(before, &state.documents[position], &returning)
+    }
+    async fn update_fields_by_id(
+        &self,
+        id: &str,
+        update: Update,
+        returning: ReturnDocument,
+    ) -> Result<T, StoreError> {
+        let mut state = self.state();
+        let position = state.position(id).ok_or_else(|| StoreError::NotFound {
+            id: String::from(id),
+        })?;
+        let before = state.documents[position].clone();
+        let mut document = before.clone();
+        apply(&mut document, &update.to_document())?;
+        state.update(position, document)?;
+        returned(before, &state.documents[position], &returning)
+    }
+    async fn upsert_by_id(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+    ) -> Result<Option<T>, StoreError> {
+        let mut state = self.state();
+        let document = stored(entity, Some(id))?;
+        match state.position(id) {
+            Some(position) => {
+                let before = state.documents[position].clone();
+                state.replace(position, document)?;
+                returned(before, &state.documents[position], &returning).map(Some)
+            }
+            None if matches!(returning, ReturnDocument::Before) => {
+                state.insert(document)?;
+                Ok(None)
+            }
+            None => {
+                state.insert(document.clone())?;
+                decode(document).map(Some)
+            }
+        }
+    }
+    async fn save_versioned(&self, entity: &mut T) -> Result<(), StoreError>
+    where
+        T: WithMetadata,
+    {
+        let previous = entity.domain_metadata().clone();
+        let id = previous.id().as_str();
+        let expected = previous.version().map(Bson::from).unwrap_or(Bson::Null);
+        let mut state = self.state();
+        let position = state.position(id).ok_or_else(|| StoreError::NotFound {
+            id: String::from(id),
+        })?;
+        let version = first(
+            &state.documents[position],
+            &format!("{}.version", T::metadata_field()),
+        );
+        if !same(&version, &expected) {
+            return Err(StoreError::VersionConflict {
+                id: String::from(id),
+                expected: previous.version().unwrap_or_default(),
+            });
+        }
+        entity.domain_metadata_mut().update_metadata();
+        let saved = stored(&*entity, Some(id)).and_then(|d| state.replace(position, d));
+        if saved.is_err() {
+            *entity.domain_metadata_mut() = previous;
+        }
+        saved
+    }
+    async fn watch(
+        &self,
+        resume_after: Option<ResumeToken>,
+    ) -> Result<ChangeStream<T>, StoreError> {
+        let start = {
+            let state = self.state();
+            match resume_after {
+                Some(token) => resume_position(&token, state.changes.len())?,
+                None => state.changes.len(),
+            }
+        };
+        let state = Arc::clone(&self.state);
+        let changes = stream::unfold(start, move |position| {
+            let state = Arc::clone(&state);
+            async move {
+                loop {
+                    let changed = Arc::clone(&state.lock().unwrap().changed);
+                    // created before looking so that a change made in
+                    // between still wakes it up
+                    let notified = changed.notified();
+                    let next = state.lock().unwrap().changes.get(position).cloned();
+                    if let Some(event) = next {
+                        let change = resume_token(position)
+                            .and_then(|token| Ok(Change::new(token, event.try_map(decode)?)));
+                        return Some((change, position + 1));
+                    }
+                    notified.await;
+                }
+            }
+        });
+        Ok(changes.boxed())
+    }
+    async fn bulk_write(
+        &self,
+        mut writes: BoxStream<'_, WriteOp<T>>,
+        options: BulkOptions,
+    ) -> Result<BulkWriteResult, StoreError> {
+        let mut result = BulkWriteResult::default();
+        let mut index = 0;
+        while let Some(write) = writes.next().await {
+            match self.state().write(write) {
+                Ok((kind, matched, modified, upserted)) => {
+                    result.add(kind, matched, modified);
+                    result.add_upserted(upserted);
+                }
+                Err(e) => {
+                    result.add_error(BulkWriteError::new(index, None, &e.to_string()));
+                    if options.ordered() {
+                        break;
+                    }
+                }
+            }
+            index += 1;
+        }
+        Ok(result)
+    }
+}
+
+/// Whether `document` matches `query`.
+fn matches(document: &Document, query: &Document) -> Result<bool, StoreError> {
+    for (key, condition) in query {
+        let matched = match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let mut results = Vec::new();
+                for operand in operands(key, condition)? {
+                    results.push(matches(document, operand)?);
+                }
+                match key.as_str() {
+                    "$and" => results.iter().all(|r| *r),
+                    "$or" => results.iter().any(|r| *r),
+                    _ => !results.iter().any(|r| *r),
+                }
+            }
+            operator if operator.starts_with('$') => return Err(unsupported(operator)),
+            field => matches_condition(&lookup(document, field), condition)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn operands<'a>(operator: &str, condition: &'a Bson) -> Result<Vec<&'a Document>, StoreError> {
+    let invalid = || StoreError::InvalidArgument(format!("{operator} needs an array of queries"));
+    match condition {
+        Bson::Array(operands) => operands
+            .iter()
+            .map(|operand| operand.as_document().ok_or_else(invalid))
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+fn unsupported(operator: &str) -> StoreError {
+    StoreError::InvalidArgument(format!("{operator} is not supported by InMemoryRepository"))
+}
+
+/// Whether the `values` found at a path satisfy `condition`.
+fn matches_condition(values: &[Bson], condition: &Bson) -> Result<bool, StoreError> {
+    let operators = match condition {
+        Bson::Document(operators) if operators.keys().any(|k| k.starts_with('$')) => operators,
+        Bson::RegularExpression(regex) => {
+            let regex = compile(&regex.pattern, &regex.options)?;
+            return Ok(candidates(values)
+                .iter()
+                .any(|value| value.as_str().is_some_and(|s| regex.is_match(s))));
+        }
+        value => return Ok(equals_any(values, value)),
+    };
+    for (operator, operand) in operators {
+        let matched = match operator.as_str() {
+            "$eq" => equals_any(values, operand),
+            "$ne" => !equals_any(values, operand),
+            "$gt" => compares(values, operand, |o| o == Ordering::Greater),
+            "$gte" => compares(values, operand, |o| o != Ordering::Less),
+            "$lt" => compares(values, operand, |o| o == Ordering::Less),
+            "$lte" => compares(values, operand, |o| o != Ordering::Greater),
+            "$in" | "$nin" => {
+                let found = match operand {
+                    Bson::Array(options) => options.iter().any(|o| equals_any(values, o)),
+                    _ => {
+                        return Err(StoreError::InvalidArgument(format!(
+                            "{operator} needs an array"
+                        )))
+                    }
+                };
+                found == (operator == "$in")
+            }
+            "$exists" => values.is_empty() != operand.as_bool().unwrap_or(true),
+            operator => return Err(unsupported(operator)),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn compile(pattern: &str, options: &str) -> Result<Regex, StoreError> {
+    let flags: String = options.chars().filter(|c| "imsx".contains(*c)).collect();
+    let pattern = if flags.is_empty() {
+        String::from(pattern)
+    } else {
+        format!("(?{flags}){pattern}")
+    };
+    Regex::new(&pattern).map_err(|e| StoreError::InvalidArgument(e.to_string()))
+}
+
+/// The values to test at a path: the values themselves and, for arrays,
+/// their elements. A missing field counts as `null`.
+fn candidates(values: &[Bson]) -> Vec<Bson> {
+    if values.is_empty() {
+        return vec![Bson::Null];
+    }
+    let mut candidates = Vec::new();
+    for value in values {
+        candidates.push(value.clone());
+        if let Bson::Array(elements) = value {
+            candidates.extend(elements.iter().cloned());
+        }
+    }
+    candidates
+}
+
+fn equals_any(values: &[Bson], expected: &Bson) -> bool {
+    candidates(values).iter().any(|value| same(value, expected))
+}
+
+fn compares(values: &[Bson], operand: &Bson, accept: impl Fn(Ordering) -> bool) -> bool {
+    values
+        .iter()
+        .flat_map(|value| candidates(std::slice::from_ref(value)))
+        .any(|value| compare(&value, operand).is_some_and(&accept))
+}
+
+/// The values at a dotted `path`, going through arrays of documents.
+fn lookup(document: &Document, path: &str) -> Vec<Bson> {
+    let segments: Vec<&str> = path.split('.').collect();
+    let mut found = Vec::new();
+    collect(&Bson::Document(document.clone()), &segments, &mut found);
+    found
+}
+
+fn collect(value: &Bson, path: &[&str], found: &mut Vec<Bson>) {
+    match (path.split_first(), value) {
+        (None, value) => found.push(value.clone()),
+        (Some((head, rest)), Bson::Document(document)) => {
+            if let Some(value) = document.get(*head) {
+                collect(value, rest, found);
+            }
+        }
+        (Some(_), Bson::Array(elements)) => {
+            for element in elements {
+                if let Bson::Document(_) = element {
+                    collect(element, path, found);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The first value at `path`, `null` when missing.
+fn first(document: &Document, path: &str) -> Bson {
+    lookup(document, path)
+        .into_iter()
+        .next()
+        .unwrap_or(Bson::Null)
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Equality, with numbers equal whatever their type.
+fn same(a: &Bson, b: &Bson) -> bool {
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Order of two values of the same kind, `None` when they cannot be
+/// compared, which makes range operators not match like on the server.
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (number(a), number(b)) {
+        return a.partial_cmp(&b);
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// Rank of the kind of a value in the server's sort order.
+fn sort_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::Null | Bson::Undefined => 0,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 1,
+        Bson::String(_) | Bson::Symbol(_) => 2,
+        Bson::Document(_) => 3,
+        Bson::Array(_) => 4,
+        Bson::Binary(_) => 5,
+        Bson::ObjectId(_) => 6,
+        Bson::Boolean(_) => 7,
+        Bson::DateTime(_) => 8,
+        Bson::Timestamp(_) => 9,
+        _ => 10,
+    }
+}
+
+fn sort_documents(documents: &mut [Document], sort: &Document) {
+    documents.sort_by(|a, b| {
+        for (field, direction) in sort {
+            let (a, b) = (first(a, field), first(b, field));
+            let order = sort_rank(&a)
+                .cmp(&sort_rank(&b))
+                .then_with(|| compare(&a, &b).unwrap_or(Ordering::Equal));
+            let order = if number(direction).is_some_and(|d| d < 0.0) {
+                order.reverse()
+            } else {
+                order
+            };
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+/// Applies the operators of an `Update` to `document`.
+fn apply(document: &mut Document, update: &Document) -> Result<(), StoreError> {
+    for (operator, fields) in update {
+        let fields = fields
+            .as_document()
+            .ok_or_else(|| StoreError::InvalidArgument(format!("{operator} needs a document")))?;
+        for (path, value) in fields {
+            let (parent, field) = parent(document, path)?;
+            match operator.as_str() {
+                "$set" => {
+                    parent.insert(field, value.clone());
+                }
+                "$unset" => {
+                    parent.remove(field);
+                }
+                "$push" => match parent.get_mut(field) {
+                    Some(Bson::Array(elements)) => elements.push(value.clone()),
+                    None => {
+                        parent.insert(field, vec![value.clone()]);
+                    }
+                    Some(_) => {
+                        return Err(StoreError::InvalidArgument(format!(
+                            "{path} is not an array"
+                        )))
+                    }
+                },
+                "$pull" => {
+                    if let Some(Bson::Array(elements)) = parent.get_mut(field) {
+                        elements.retain(|element| !same(element, value));
+                    }
+                }
+                operator => return Err(unsupported(operator)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The document holding the last segment of `path`, created if missing.
+fn parent<'a, 'p>(
+    document: &'a mut Document,
+    path: &'p str,
+) -> Result<(&'a mut Document, &'p str), StoreError> {
+    match path.split_once('.') {
+        None => Ok((document, path)),
+        Some((head, rest)) => {
+            if !document.contains_key(head) {
+                document.insert(head, Document::new());
+            }
+            match document.get_mut(head) {
+                Some(Bson::Document(inner)) => parent(inner, rest),
+                _ => Err(StoreError::InvalidArgument(format!(
+                    "{head} is not a document in {path}"
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::InMemoryRepository;
+    use crate::{
+        doc, Bson, BulkOptions, BulkWriteError, ChangeEvent, Filter, IndexSpec, KeysetPage, Page,
+        Repository, ReturnDocument, Sort, StoreError, Update, WriteOp,
+    };
+    use domain::{Deserialize, Id, Metadata, Serialize, WithMetadata};
+    use futures_util::{stream, StreamExt, TryStreamExt};
+
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    struct Book {
+        #[serde(rename = "_id")]
+        id: Id,
+        title: String,
+        author: Author,
+        year: i32,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    struct Author {
+        name: String,
+        country: Option<String>,
+    }
+
+    fn book(title: &str, author: &str, year: i32, tags: &[&str]) -> Book {
+        Book {
+            title: String::from(title),
+            author: Author {
+                name: String::from(author),
+                country: None,
+            },
+            year,
+            tags: tags.iter().map(|t| String::from(*t)).collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn library() -> InMemoryRepository<Book> {
+        let repository = InMemoryRepository::new();
+        repository
+            .insert_many(&[
+                book("The Grapes of Wrath", "John Steinbeck", 1939, &["classic"]),
+                book(
+                    "East of Eden",
+                    "John Steinbeck",
+                    1952,
+                    &["classic", "family"],
+                ),
+                book("To Kill a Mockingbird", "Harper Lee", 1960, &["classic"]),
+                book("Go Set a Watchman", "Harper Lee", 2015, &[]),
+            ])
+            .await
+            .unwrap();
+        repository
+    }
+
+    async fn titles(repository: &InMemoryRepository<Book>, filter: Filter) -> Vec<String> {
+        let sort = Sort::new().asc("year");
+        let books: Vec<Book> = repository
+            .find_by(filter, Some(sort))
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        books.into_iter().map(|b| b.title).collect()
+    }
+
+    #[tokio::test]
+    async fn test_filters() {
+        let repository = library().await;
+        assert_eq!(4, repository.count().await.unwrap());
+        assert_eq!(
+            vec!["East of Eden", "To Kill a Mockingbird"],
+            titles(&repository, Filter::between("year", 1950, 2000)).await
+        );
+        assert_eq!(
+            vec!["East of Eden"],
+            titles(&repository, Filter::eq("tags", "family")).await
+        );
+        assert_eq!(
+            vec!["Go Set a Watchman"],
+            titles(
+                &repository,
+                Filter::eq("author.name", "Harper Lee").and(!Filter::eq("tags", "classic"))
+            )
+            .await
+        );
+        assert_eq!(
+            vec!["The Grapes of Wrath", "Go Set a Watchman"],
+            titles(
+                &repository,
+                Filter::lt("year", 1940).or(Filter::regex("title", "^go", "i"))
+            )
+            .await
+        );
+        assert_eq!(
+            vec!["The Grapes of Wrath", "East of Eden"],
+            titles(
+                &repository,
+                Filter::is_in("author.name", ["John Steinbeck"])
+            )
+            .await
+        );
+        assert_eq!(
+            4,
+            repository
+                .count_by(Filter::eq("author.country", Bson::Null))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            0,
+            repository
+                .count_by(Filter::exists("author.birth_date", true))
+                .await
+                .unwrap()
+        );
+        assert!(matches!(
+            repository
+                .count_by(Filter::eq("year", doc! {"$mod": [2, 0]}))
+                .await,
+            Err(StoreError::InvalidArgument(_))
+        ));
+        assert_eq!(
+            2,
+            repository
+                .delete_many(Some(doc! {"author.name": "Harper Lee"}))
+                .await
+                .unwrap()
+        );
+        assert_eq!(2, repository.count().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pages() {
+        let repository = library().await;
+        let page = Page::new(2, 3).unwrap().set_sort(doc! {"year": -1});
+        let result = repository.find_page(None, page).await.unwrap();
+        assert_eq!(4, result.total());
+        assert_eq!(2, result.total_pages());
+        assert_eq!("The Grapes of Wrath", result.items()[0].title);
+
+        let mut page = KeysetPage::by_field("author.name", 3).unwrap();
+        let mut seen = Vec::new();
+        loop {
+            let result = repository.find_keyset(None, page.clone()).await.unwrap();
+            seen.extend(result.items().iter().map(|b| b.author.name.clone()));
+            match result.next() {
+                Some(next) => page = page.set_after(next.clone()),
+                None => break,
+            }
+        }
+        assert_eq!(
+            vec![
+                "Harper Lee",
+                "Harper Lee",
+                "John Steinbeck",
+                "John Steinbeck"
+            ],
+            seen
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writes() {
+        let repository =
+            InMemoryRepository::new().set_indexes(vec![IndexSpec::asc("title").set_unique(true)]);
+        assert_eq!(
+            1,
+            repository.ensure_indexes().await.unwrap().created().len()
+        );
+        let grapes = book("The Grapes of Wrath", "Steinbeck", 1939, &[]);
+        repository.insert_one(&grapes).await.unwrap();
+        assert!(matches!(
+            repository.insert_one(&grapes).await,
+            Err(StoreError::DuplicateKey { index: Some(index), .. }) if index == "_id_"
+        ));
+        let copy = book("The Grapes of Wrath", "Someone", 2000, &[]);
+        assert!(matches!(
+            repository.insert_one(&copy).await,
+            Err(StoreError::DuplicateKey { index: Some(index), .. }) if index == "title_1"
+        ));
+
+        let before = repository
+            .update_fields_by_id(
+                &grapes.id,
+                Update::new()
+                    .set("author.country", "USA")
+                    .push("tags", "classic"),
+                ReturnDocument::Before,
+            )
+            .await
+            .unwrap();
+        assert_eq!(grapes, before);
+        let stored = repository.find_by_id(&grapes.id).await.unwrap().unwrap();
+        assert_eq!(Some(String::from("USA")), stored.author.country);
+        assert_eq!(vec!["classic"], stored.tags);
+        let after = repository
+            .update_fields_by_id(
+                &grapes.id,
+                Update::new()
+                    .unset("author.country")
+                    .pull("tags", "classic"),
+                ReturnDocument::After,
+            )
+            .await
+            .unwrap();
+        assert_eq!(grapes, after);
+
+        let upserted = book("East of Eden", "Steinbeck", 1952, &[]);
+        let inserted = repository
+            .upsert_by_id(&upserted.id, &upserted, ReturnDocument::Before)
+            .await
+            .unwrap();
+        assert!(inserted.is_none());
+        let renamed = Book {
+            title: String::from("East of Eden (1952)"),
+            ..upserted.clone()
+        };
+        let after = repository
+            .replace_by_id(&upserted.id, &renamed, ReturnDocument::After)
+            .await
+            .unwrap();
+        assert_eq!(renamed, after);
+        assert!(matches!(
+            repository
+                .replace_by_id("missing", &renamed, ReturnDocument::After)
+                .await,
+            Err(StoreError::NotFound { .. })
+        ));
+        let deleted = repository
+            .delete_by_id(renamed.id.to_string())
+            .await
+            .unwrap();
+        assert_eq!(Some(renamed), deleted);
+    }
+
+    #[derive(Debug, Serialize, Deserialize, WithMetadata)]
+    struct Account {
+        #[serde(rename = "_id")]
+        id: Id,
+        #[serde(rename = "metadata")]
+        domain_metadata: Metadata,
+        balance: i64,
+    }
+
+    #[tokio::test]
+    async fn test_save_versioned() {
+        let repository = InMemoryRepository::new();
+        let id = Id::default();
+        let mut account = Account {
+            domain_metadata: Metadata::new_with_default(&id),
+            id,
+            balance: 10,
+        };
+        repository.insert_one(&account).await.unwrap();
+        let mut concurrent = repository.find_by_id(&account.id).await.unwrap().unwrap();
+
+        account.balance = 20;
+        repository.save_versioned(&mut account).await.unwrap();
+        assert_eq!(&Some(2), account.domain_metadata().version());
+
+        concurrent.balance = 5;
+        assert!(matches!(
+            repository.save_versioned(&mut concurrent).await,
+            Err(StoreError::VersionConflict { expected: 1, .. })
+        ));
+        assert_eq!(&Some(1), concurrent.domain_metadata().version());
+        let stored = repository.find_by_id(&account.id).await.unwrap().unwrap();
+        assert_eq!(20, stored.balance);
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let repository = library().await;
+        let mut changes = repository.watch(None).await.unwrap();
+        let book = book("Of Mice and Men", "John Steinbeck", 1936, &[]);
+        let id = book.id.to_string();
+        repository.insert_one(&book).await.unwrap();
+        repository
+            .update_fields_by_id(&id, Update::new().set("year", 1937), ReturnDocument::After)
+            .await
+            .unwrap();
+        repository.delete_by_id(id.clone()).await.unwrap();
+
+        let inserted = changes.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            &ChangeEvent::Inserted {
+                id: Bson::from(id.as_str()),
+                document: book.clone(),
+            },
+            inserted.event()
+        );
+        let updated = changes.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            &ChangeEvent::Updated {
+                id: Bson::from(id.as_str()),
+                updated_fields: doc! {"year": 1937},
+                removed_fields: Vec::new(),
+                document: Some(Book {
+                    year: 1937,
+                    ..book.clone()
+                }),
+            },
+            updated.event()
+        );
+        let deleted = changes.try_next().await.unwrap().unwrap();
+        assert_eq!(
+            &ChangeEvent::Deleted {
+                id: Bson::from(id.as_str())
+            },
+            deleted.event()
+        );
+
+        let mut resumed = repository
+            .watch(Some(inserted.resume_token().clone()))
+            .await
+            .unwrap();
+        assert_eq!(updated, resumed.try_next().await.unwrap().unwrap());
+
+        let waiting = tokio::spawn(async move { changes.next().await });
+        repository.delete_many(None).await.unwrap();
+        let next = waiting.await.unwrap().unwrap().unwrap();
+        assert!(matches!(next.event(), ChangeEvent::Deleted { .. }));
+
+        let foreign = mongodb::bson::from_bson(Bson::from(doc! {"_data": "8263"})).unwrap();
+        assert!(matches!(
+            InMemoryRepository::<Book>::new().watch(Some(foreign)).await,
+            Err(StoreError::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bulk_write() {
+        let repository = library().await;
+        let east = book("East of Eden", "John Steinbeck", 1952, &[]);
+        let writes = || {
+            vec![
+                WriteOp::Insert(east.clone()),
+                WriteOp::Insert(east.clone()),
+                WriteOp::Update {
+                    id: east.id.to_string(),
+                    update: Box::new(Update::new().set("year", 1953)),
+                },
+                WriteOp::Delete {
+                    id: String::from("missing"),
+                },
+            ]
+        };
+
+        let ordered = repository
+            .bulk_write(stream::iter(writes()).boxed(), BulkOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, ordered.inserted());
+        assert_eq!(0, ordered.matched());
+        assert_eq!(1, ordered.errors().len());
+        assert_eq!(1, ordered.errors()[0].index());
+
+        let unordered = repository
+            .bulk_write(
+                stream::iter(writes()).boxed(),
+                BulkOptions::default().set_ordered(false),
+            )
+            .await
+            .unwrap();
+        assert_eq!(0, unordered.inserted());
+        assert_eq!(1, unordered.modified());
+        assert_eq!(0, unordered.deleted());
+        assert_eq!(
+            vec![0, 1],
+            unordered
+                .errors()
+                .iter()
+                .map(BulkWriteError::index)
+                .collect::<Vec<_>>()
+        );
+        let stored = repository.find_by_id(&east.id).await.unwrap().unwrap();
+        assert_eq!(1953, stored.year);
+    }
+}
@@ -0,0 +1,590 @@
+use crate::{doc, DateTime, Document, FindOneAndUpdateOptions, StoreError};
+use futures_util::future::BoxFuture;
+use futures_util::TryStreamExt;
+use mongodb::options::FindOptions;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Collection holding a record per applied migration and the lock.
+pub const MIGRATIONS_COLLECTION: &str = "_migrations";
+const LOCK_ID: &str = "lock";
+const DEFAULT_LOCK_LEASE: Duration = Duration::from_secs(10 * 60);
+
+type MigrationFn =
+    Arc<dyn Fn(Database) -> BoxFuture<'static, Result<(), StoreError>> + Send + Sync>;
+
+/// A change to the database, run once and in `version` order by a `Migrator`.
+#[derive(Clone)]
+pub struct Migration {
+    version: u32,
+    name: String,
+    checksum: String,
+    run: MigrationFn,
+}
+
+impl Migration {
+    /// `run` gets the database the migrator was created with:
+    /// `Migration::new(1, "default_roles", |db| Box::pin(async move { .. }))`.
+    pub fn new<F>(version: u32, name: &str, run: F) -> Migration
+    where
+        F: Fn(Database) -> BoxFuture<'static, Result<(), StoreError>> + Send + Sync + 'static,
+    {
+        Migration {
+            version,
+            name: String::from(name),
+            checksum: checksum(version, name, ""),
+            run: Arc::new(run),
+        }
+    }
+    /// Includes `source`, like the migration's `include_str!`, in the
+    /// checksum so that editing a migration after it was applied is caught.
+    /// Without it only the version and name are.
+    pub fn set_source(self, source: &str) -> Self {
+        Migration {
+            checksum: checksum(self.version, &self.name, source),
+            ..self
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn checksum(&self) -> &str {
+        &self.checksum
+    }
+}
+
+impl Debug for Migration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Migration")
+            .field("version", &self.version)
+            .field("name", &self.name)
+            .field("checksum", &self.checksum)
+            .finish()
+    }
+}
+
+/// FNV-1a, unlike `DefaultHasher` it is stable across Rust releases.
+fn checksum(version: u32, name: &str, source: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let input = format!("{version}\0{name}\0{source}");
+    for byte in input.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{hash:016x}")
+}
+
+/// Record of an applied migration in the `_migrations` collection.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct AppliedMigration {
+    #[serde(rename = "_id")]
+    version: i64,
+    name: String,
+    checksum: String,
+    applied_at: DateTime,
+    duration_ms: i64,
+}
+
+impl AppliedMigration {
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn checksum(&self) -> &str {
+        &self.checksum
+    }
+    pub fn applied_at(&self) -> DateTime {
+        self.applied_at
+    }
+    pub fn duration_ms(&self) -> i64 {
+        self.duration_ms
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MigrationState {
+    Pending,
+    Applied {
+        applied_at: DateTime,
+    },
+    /// Applied, but the migration changed since: its checksum is not the
+    /// recorded one.
+    Modified {
+        applied_checksum: String,
+    },
+    /// Recorded as applied but no longer declared.
+    Unknown,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MigrationStatus {
+    version: u32,
+    name: String,
+    state: MigrationState,
+}
+
+impl MigrationStatus {
+    /// Status of each declared migration, then of the unknown applied ones.
+    fn compare(migrations: &[Migration], applied: &[AppliedMigration]) -> Vec<MigrationStatus> {
+        let mut statuses: Vec<MigrationStatus> = migrations
+            .iter()
+            .map(|migration| {
+                let record = applied
+                    .iter()
+                    .find(|record| record.version == migration.version as i64);
+                let state = match record {
+                    None => MigrationState::Pending,
+                    Some(record) if record.checksum != migration.checksum => {
+                        MigrationState::Modified {
+                            applied_checksum: record.checksum.clone(),
+                        }
+                    }
+                    Some(record) => MigrationState::Applied {
+                        applied_at: record.applied_at,
+                    },
+                };
+                MigrationStatus {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                    state,
+                }
+            })
+            .collect();
+        let declared: BTreeSet<i64> = migrations.iter().map(|m| m.version as i64).collect();
+        statuses.extend(
+            applied
+                .iter()
+                .filter(|record| !declared.contains(&record.version))
+                .map(|record| MigrationStatus {
+                    version: record.version as u32,
+                    name: record.name.clone(),
+                    state: MigrationState::Unknown,
+                }),
+        );
+        statuses
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn state(&self) -> &MigrationState {
+        &self.state
+    }
+}
+
+impl Display for MigrationStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = match &self.state {
+            MigrationState::Pending => String::from("pending"),
+            MigrationState::Applied { applied_at } => format!("applied {applied_at}"),
+            MigrationState::Modified { applied_checksum } => {
+                format!("modified since applied with checksum {applied_checksum}")
+            }
+            MigrationState::Unknown => String::from("applied but unknown"),
+        };
+        write!(f, "{:04} {} {state}", self.version, self.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Two migrations share a version.
+    DuplicateVersion(u32),
+    /// An applied migration was changed, fix it or write a new migration
+    /// instead.
+    ChecksumMismatch {
+        version: u32,
+        name: String,
+    },
+    /// Another instance is running the migrations.
+    Locked {
+        owner: String,
+    },
+    /// The migration failed, it is not recorded and the ones after it did
+    /// not run.
+    Failed {
+        version: u32,
+        name: String,
+        source: StoreError,
+    },
+    UnknownCommand(String),
+    Store(StoreError),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::DuplicateVersion(version) => {
+                write!(f, "more than one migration has version {version}")
+            }
+            MigrationError::ChecksumMismatch { version, name } => write!(
+                f,
+                "migration {version:04} {name} was changed after it was applied"
+            ),
+            MigrationError::Locked { owner } => {
+                write!(f, "migrations are being run by {owner}")
+            }
+            MigrationError::Failed {
+                version,
+                name,
+                source,
+            } => write!(f, "migration {version:04} {name} failed: {source}"),
+            MigrationError::UnknownCommand(command) => write!(
+                f,
+                "unknown command {command}, expected list, apply or dry-run"
+            ),
+            MigrationError::Store(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MigrationError::Failed { source, .. } => Some(source),
+            MigrationError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<StoreError> for MigrationError {
+    fn from(e: StoreError) -> Self {
+        MigrationError::Store(e)
+    }
+}
+
+impl From<mongodb::error::Error> for MigrationError {
+    fn from(e: mongodb::error::Error) -> Self {
+        MigrationError::Store(e.into())
+    }
+}
+
+/// What a migration binary was asked to do.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MigrationCommand {
+    /// Prints the status of every migration.
+    List,
+    Apply,
+    /// Prints the migrations `Apply` would run.
+    DryRun,
+}
+
+impl FromStr for MigrationCommand {
+    type Err = MigrationError;
+
+    fn from_str(command: &str) -> Result<Self, Self::Err> {
+        match command {
+            "list" => Ok(MigrationCommand::List),
+            "apply" => Ok(MigrationCommand::Apply),
+            "dry-run" => Ok(MigrationCommand::DryRun),
+            command => Err(MigrationError::UnknownCommand(String::from(command))),
+        }
+    }
+}
+
+/// Runs the pending migrations of a database, recording them in the
+/// `_migrations` collection. A lock document there makes sure a single
+/// instance runs them at a time.
+pub struct Migrator {
+    database: Database,
+    migrations: Vec<Migration>,
+    owner: String,
+    lock_lease: Duration,
+}
+
+impl Migrator {
+    pub fn new(database: Database, migrations: Vec<Migration>) -> Result<Migrator, MigrationError> {
+        Ok(Migrator {
+            database,
+            migrations: ordered(migrations)?,
+            owner: Uuid::new_v4().to_string(),
+            lock_lease: DEFAULT_LOCK_LEASE,
+        })
+    }
+    /// How long the lock is held at most, after which another instance may
+    /// take it over. It must outlast running all the migrations, it is ten
+    /// minutes by default.
+    pub fn set_lock_lease(self, lock_lease: Duration) -> Self {
+        Migrator { lock_lease, ..self }
+    }
+
+    pub fn migrations(&self) -> &Vec<Migration> {
+        &self.migrations
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        self.database.collection(MIGRATIONS_COLLECTION)
+    }
+
+    pub async fn applied(&self) -> Result<Vec<AppliedMigration>, MigrationError> {
+        let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+        let applied = self
+            .collection()
+            .clone_with_type::<AppliedMigration>()
+            .find(doc! {"_id": {"$ne": LOCK_ID}}, options)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(applied)
+    }
+
+    pub async fn status(&self) -> Result<Vec<MigrationStatus>, MigrationError> {
+        Ok(MigrationStatus::compare(
+            &self.migrations,
+            &self.applied().await?,
+        ))
+    }
+
+    /// The migrations `apply` would run, in order.
+    pub async fn plan(&self) -> Result<Vec<&Migration>, MigrationError> {
+        let statuses = self.status().await?;
+        pending(&self.migrations, &statuses)
+    }
+
+    /// Runs the pending migrations in order, returning their versions. Stops
+    /// at the first failure, the migrations applied before it stay recorded.
+    pub async fn apply(&self) -> Result<Vec<u32>, MigrationError> {
+        self.lock().await?;
+        let applied = self.apply_locked().await;
+        if let Err(e) = self.unlock().await {
+            tracing::warn!("could not release the migrations lock, it expires on its own: {e}");
+        }
+        applied
+    }
+
+    async fn apply_locked(&self) -> Result<Vec<u32>, MigrationError> {
+        let mut applied = Vec::new();
+        for migration in self.plan().await? {
+            tracing::info!(
+                "applying migration {:04} {}",
+                migration.version,
+                migration.name
+            );
+            let started = Instant::now();
+            (migration.run)(self.database.clone())
+                .await
+                .map_err(|source| MigrationError::Failed {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                    source,
+                })?;
+            let record = AppliedMigration {
+                version: migration.version as i64,
+                name: migration.name.clone(),
+                checksum: migration.checksum.clone(),
+                applied_at: DateTime::now(),
+                duration_ms: started.elapsed().as_millis() as i64,
+            };
+            self.collection()
+                .clone_with_type::<AppliedMigration>()
+                .insert_one(&record, None)
+                .await?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+
+    /// Takes the lock if it is free or its lease expired.
+    async fn lock(&self) -> Result<(), MigrationError> {
+        let now = DateTime::now();
+        let locked_until =
+            DateTime::from_millis(now.timestamp_millis() + self.lock_lease.as_millis() as i64);
+        let options = FindOneAndUpdateOptions::builder().upsert(true).build();
+        let taken = self
+            .collection()
+            .find_one_and_update(
+                doc! {"_id": LOCK_ID, "locked_until": {"$lt": now}},
+                doc! {"$set": {"owner": &self.owner, "locked_until": locked_until}},
+                options,
+            )
+            .await;
+        match taken.map_err(StoreError::from) {
+            Ok(_) => Ok(()),
+            // the lock exists and is not expired, so upserting it again failed
+            Err(StoreError::DuplicateKey { .. }) => {
+                let lock = self
+                    .collection()
+                    .find_one(doc! {"_id": LOCK_ID}, None)
+                    .await?;
+                let owner = lock
+                    .and_then(|lock| lock.get_str("owner").ok().map(String::from))
+                    .unwrap_or_default();
+                Err(MigrationError::Locked { owner })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn unlock(&self) -> Result<(), MigrationError> {
+        self.collection()
+            .delete_one(doc! {"_id": LOCK_ID, "owner": &self.owner}, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Runs `command` for a migration binary, returning what to print.
+    pub async fn run_command(&self, command: MigrationCommand) -> Result<String, MigrationError> {
+        let lines: Vec<String> = match command {
+            MigrationCommand::List => self.status().await?.iter().map(|s| s.to_string()).collect(),
+            MigrationCommand::DryRun => self
+                .plan()
+                .await?
+                .iter()
+                .map(|m| format!("would apply {:04} {}", m.version, m.name))
+                .collect(),
+            MigrationCommand::Apply => self
+                .apply()
+                .await?
+                .iter()
+                .map(|version| format!("applied {version:04}"))
+                .collect(),
+        };
+        if lines.is_empty() {
+            return Ok(String::from("nothing to do"));
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+fn ordered(mut migrations: Vec<Migration>) -> Result<Vec<Migration>, MigrationError> {
+    migrations.sort_by_key(|migration| migration.version);
+    match migrations
+        .windows(2)
+        .find(|pair| pair[0].version == pair[1].version)
+    {
+        Some(pair) => Err(MigrationError::DuplicateVersion(pair[0].version)),
+        None => Ok(migrations),
+    }
+}
+
+/// The pending migrations, refusing to go on when an applied one changed.
+fn pending<'m>(
+    migrations: &'m [Migration],
+    statuses: &[MigrationStatus],
+) -> Result<Vec<&'m Migration>, MigrationError> {
+    let mut pending = Vec::new();
+    for (migration, status) in migrations.iter().zip(statuses) {
+        match status.state {
+            MigrationState::Pending => pending.push(migration),
+            MigrationState::Modified { .. } => {
+                return Err(MigrationError::ChecksumMismatch {
+                    version: migration.version,
+                    name: migration.name.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migration::{
+        checksum, ordered, pending, AppliedMigration, Migration, MigrationCommand, MigrationError,
+        MigrationState, MigrationStatus,
+    };
+    use crate::DateTime;
+
+    fn migration(version: u32, name: &str) -> Migration {
+        Migration::new(version, name, |_| Box::pin(async { Ok(()) }))
+    }
+
+    fn applied(migration: &Migration) -> AppliedMigration {
+        AppliedMigration {
+            version: migration.version as i64,
+            name: migration.name.clone(),
+            checksum: migration.checksum.clone(),
+            applied_at: DateTime::from_millis(0),
+            duration_ms: 3,
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!("cf6c91519c9e44b9", checksum(1, "default_roles", ""));
+        let migration = migration(1, "default_roles");
+        let edited = migration.clone().set_source("db.user.updateMany()");
+        assert_eq!(checksum(1, "default_roles", ""), migration.checksum());
+        assert_ne!(migration.checksum(), edited.checksum());
+        assert_ne!(checksum(2, "default_roles", ""), migration.checksum());
+    }
+
+    #[test]
+    fn test_status() {
+        let migrations = vec![
+            migration(1, "default_roles"),
+            migration(2, "lowercase_emails"),
+            migration(3, "drop_legacy_fields"),
+        ];
+        let mut records = vec![applied(&migrations[0]), applied(&migrations[1])];
+        records.push(AppliedMigration {
+            version: 7,
+            name: String::from("removed"),
+            ..applied(&migrations[0])
+        });
+        let statuses = MigrationStatus::compare(&migrations, &records);
+        let states: Vec<&MigrationState> = statuses.iter().map(|s| s.state()).collect();
+        let applied_at = DateTime::from_millis(0);
+        assert_eq!(
+            vec![
+                &MigrationState::Applied { applied_at },
+                &MigrationState::Applied { applied_at },
+                &MigrationState::Pending,
+                &MigrationState::Unknown,
+            ],
+            states
+        );
+        assert_eq!("0003 drop_legacy_fields pending", statuses[2].to_string());
+        let plan = pending(&migrations, &statuses).unwrap();
+        assert_eq!(
+            vec![3],
+            plan.iter().map(|m| m.version()).collect::<Vec<_>>()
+        );
+
+        records[1].checksum = String::from("0000000000000000");
+        let statuses = MigrationStatus::compare(&migrations, &records);
+        assert!(matches!(
+            pending(&migrations, &statuses),
+            Err(MigrationError::ChecksumMismatch { version: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_ordered() {
+        let migrations = ordered(vec![migration(2, "b"), migration(1, "a")]).unwrap();
+        assert_eq!(
+            vec!["a", "b"],
+            migrations.iter().map(|m| m.name()).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            ordered(vec![migration(1, "a"), migration(1, "b")]),
+            Err(MigrationError::DuplicateVersion(1))
+        ));
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!(MigrationCommand::DryRun, "dry-run".parse().unwrap());
+        assert!(matches!(
+            "rollback".parse::<MigrationCommand>(),
+            Err(MigrationError::UnknownCommand(_))
+        ));
+    }
+}
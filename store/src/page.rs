@@ -0,0 +1,324 @@
+use crate::error::StoreError;
+use crate::{doc, Bson, Document};
+use domain::{Deserialize, Serialize};
+
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Offset page, `page` starts at 1.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Page {
+    page: i64,
+    limit: i64,
+    sort: Option<Document>,
+}
+
+impl Page {
+    pub fn new(page: i64, limit: i64) -> Result<Page, StoreError> {
+        let page = Page {
+            page,
+            limit,
+            sort: None,
+        };
+        page.validate()?;
+        Ok(page)
+    }
+    pub fn set_sort(self, sort: Document) -> Self {
+        Page {
+            sort: Some(sort),
+            ..self
+        }
+    }
+
+    pub fn page(&self) -> i64 {
+        self.page
+    }
+    pub fn limit(&self) -> i64 {
+        self.limit
+    }
+    pub fn sort(&self) -> &Option<Document> {
+        &self.sort
+    }
+
+    /// Deserialized pages skip `new`, so they are checked again before use.
+    pub(crate) fn validate(&self) -> Result<(), StoreError> {
+        if self.page < 1 {
+            return Err(StoreError::InvalidArgument(format!(
+                "page must start at 1, got {}",
+                self.page
+            )));
+        }
+        validate_limit(self.limit)?;
+        if self.limit.checked_mul(self.page - 1).is_none() {
+            return Err(StoreError::InvalidArgument(format!(
+                "page {} is out of range",
+                self.page
+            )));
+        }
+        Ok(())
+    }
+
+    /// Only called on validated pages, for which it cannot overflow.
+    pub(crate) fn skip(&self) -> u64 {
+        (self.limit * (self.page - 1)) as u64
+    }
+}
+
+fn validate_limit(limit: i64) -> Result<(), StoreError> {
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(StoreError::InvalidArgument(format!(
+            "page size must be between 1 and {MAX_PAGE_SIZE}, got {limit}"
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct PageResult<T> {
+    items: Vec<T>,
+    /// Number of documents matching the query, across all pages.
+    total: u64,
+    page: i64,
+    page_size: i64,
+    total_pages: u64,
+}
+
+impl<T> PageResult<T> {
+    pub(crate) fn new(items: Vec<T>, total: u64, page: &Page) -> Self {
+        let page_size = page.limit;
+        PageResult {
+            items,
+            total,
+            page: page.page,
+            page_size,
+            total_pages: total.div_ceil(page_size as u64),
+        }
+    }
+
+    pub fn items(&self) -> &Vec<T> {
+        &self.items
+    }
+    pub fn into_items(self) -> Vec<T> {
+        self.items
+    }
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+    pub fn page(&self) -> i64 {
+        self.page
+    }
+    pub fn page_size(&self) -> i64 {
+        self.page_size
+    }
+    pub fn total_pages(&self) -> u64 {
+        self.total_pages
+    }
+    pub fn has_next(&self) -> bool {
+        (self.page as u64) < self.total_pages
+    }
+}
+
+/// Where a keyset page stopped: the `_id` of its last document and, when
+/// sorted on another field, the value of that field. Hand it back to
+/// `KeysetPage::set_after` to get the following page.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Keyset {
+    id: Bson,
+    value: Option<Bson>,
+}
+
+impl Keyset {
+    pub fn id(&self) -> &Bson {
+        &self.id
+    }
+    pub fn value(&self) -> &Option<Bson> {
+        &self.value
+    }
+}
+
+/// Keyset (cursor) page: instead of skipping documents it resumes after the
+/// last one seen, which stays fast on large collections. Documents are
+/// ordered by `field` then `_id`, so `field` does not need to be unique but
+/// should be present in every document.
+#[derive(PartialEq, Debug, Clone)]
+pub struct KeysetPage {
+    field: String,
+    descending: bool,
+    limit: i64,
+    after: Option<Keyset>,
+}
+
+impl KeysetPage {
+    pub fn by_id(limit: i64) -> Result<KeysetPage, StoreError> {
+        KeysetPage::by_field("_id", limit)
+    }
+    pub fn by_field(field: &str, limit: i64) -> Result<KeysetPage, StoreError> {
+        validate_limit(limit)?;
+        Ok(KeysetPage {
+            field: String::from(field),
+            descending: false,
+            limit,
+            after: None,
+        })
+    }
+    pub fn set_descending(self, descending: bool) -> Self {
+        KeysetPage { descending, ..self }
+    }
+    pub fn set_after(self, after: Keyset) -> Self {
+        KeysetPage {
+            after: Some(after),
+            ..self
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+    pub fn descending(&self) -> bool {
+        self.descending
+    }
+    pub fn limit(&self) -> i64 {
+        self.limit
+    }
+    pub fn after(&self) -> &Option<Keyset> {
+        &self.after
+    }
+
+    fn by_id_only(&self) -> bool {
+        self.field == "_id"
+    }
+
+    pub(crate) fn sort(&self) -> Document {
+        let direction = if self.descending { -1 } else { 1 };
+        if self.by_id_only() {
+            doc! {"_id": direction}
+        } else {
+            doc! {self.field.as_str(): direction, "_id": direction}
+        }
+    }
+
+    /// Narrows `query` to the documents after `after`.
+    pub(crate) fn filter(&self, query: Document) -> Document {
+        let after = match &self.after {
+            Some(after) => after,
+            None => return query,
+        };
+        let operator = if self.descending { "$lt" } else { "$gt" };
+        let position = match (&after.value, self.by_id_only()) {
+            (Some(value), false) => doc! {"$or": [
+                {self.field.as_str(): {operator: value.clone()}},
+                {self.field.as_str(): value.clone(), "_id": {operator: after.id.clone()}},
+            ]},
+            _ => doc! {"_id": {operator: after.id.clone()}},
+        };
+        if query.is_empty() {
+            position
+        } else {
+            doc! {"$and": [query, position]}
+        }
+    }
+
+    pub(crate) fn keyset_of(&self, document: &Document) -> Result<Keyset, StoreError> {
+        let id = document
+            .get("_id")
+            .cloned()
+            .ok_or_else(|| StoreError::InvalidArgument(String::from("document has no _id")))?;
+        let value = if self.by_id_only() {
+            None
+        } else {
+            Some(field_value(document, &self.field).unwrap_or(Bson::Null))
+        };
+        Ok(Keyset { id, value })
+    }
+}
+
+/// Looks up a dotted path like `profile.last_name`.
+fn field_value(document: &Document, path: &str) -> Option<Bson> {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    match (document.get(head), rest) {
+        (Some(Bson::Document(inner)), Some(rest)) => field_value(inner, rest),
+        (Some(value), None) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct KeysetResult<T> {
+    items: Vec<T>,
+    /// `None` on the last page.
+    next: Option<Keyset>,
+}
+
+impl<T> KeysetResult<T> {
+    pub(crate) fn new(items: Vec<T>, next: Option<Keyset>) -> Self {
+        KeysetResult { items, next }
+    }
+
+    pub fn items(&self) -> &Vec<T> {
+        &self.items
+    }
+    pub fn into_items(self) -> Vec<T> {
+        self.items
+    }
+    pub fn next(&self) -> &Option<Keyset> {
+        &self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::page::{KeysetPage, Page, PageResult};
+    use crate::{doc, StoreError};
+
+    #[test]
+    fn test_page() {
+        let page = Page::new(3, 20).unwrap().set_sort(doc! {"email": 1});
+        assert_eq!(40, page.skip());
+        assert_eq!(&Some(doc! {"email": 1}), page.sort());
+        for (number, limit) in [(0, 20), (1, 0), (1, 1001), (i64::MAX, 2)] {
+            assert!(matches!(
+                Page::new(number, limit),
+                Err(StoreError::InvalidArgument(_))
+            ));
+        }
+
+        let result = PageResult::new(vec![1, 2], 42, &Page::new(3, 20).unwrap());
+        assert_eq!(3, result.total_pages());
+        assert!(!result.has_next());
+        let empty: PageResult<i32> = PageResult::new(vec![], 0, &Page::new(1, 20).unwrap());
+        assert_eq!(0, empty.total_pages());
+
+        let far: Page =
+            serde_json::from_str(r#"{"page": 9223372036854775807, "limit": 1000}"#).unwrap();
+        assert!(far.validate().is_err());
+    }
+
+    #[test]
+    fn test_keyset_page() {
+        let first = KeysetPage::by_field("profile.last_name", 10).unwrap();
+        assert_eq!(doc! {"active": true}, first.filter(doc! {"active": true}));
+        assert_eq!(doc! {"profile.last_name": 1, "_id": 1}, first.sort());
+
+        let keyset = first
+            .keyset_of(&doc! {"_id": "b", "profile": {"last_name": "Doe"}})
+            .unwrap();
+        let next = first.set_after(keyset);
+        assert_eq!(
+            doc! {"$and": [{"active": true}, {"$or": [
+                {"profile.last_name": {"$gt": "Doe"}},
+                {"profile.last_name": "Doe", "_id": {"$gt": "b"}},
+            ]}]},
+            next.filter(doc! {"active": true})
+        );
+
+        let by_id = KeysetPage::by_id(10).unwrap().set_descending(true);
+        let keyset = by_id.keyset_of(&doc! {"_id": "b"}).unwrap();
+        assert_eq!(&None, keyset.value());
+        assert_eq!(
+            doc! {"_id": {"$lt": "b"}},
+            by_id.set_after(keyset).filter(doc! {})
+        );
+    }
+}
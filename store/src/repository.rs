@@ -1,122 +1,1033 @@
-use crate::{doc, to_document, Collection, Document};
-use crate::{Cursor, DeleteResult, FindOptions, InsertManyResult, InsertOneResult};
-use domain::{Deserialize, Serialize};
+use crate::bulk::{runs, BulkOptions, BulkWriteResult, WriteKind, WriteOp};
+use crate::error::{is_namespace_not_found, StoreError};
+use crate::filter::{Filter, Sort};
+use crate::index::{IndexReport, IndexSpec};
+use crate::page::{KeysetPage, KeysetResult, Page, PageResult};
+use crate::update::Update;
+use crate::watch::{Change, ChangeStream, ResumeToken};
+use crate::{
+    doc, from_document, to_document, Bson, ClientSession, Collection, DateTime, Document,
+    ReturnDocument,
+};
+use crate::{FindOneAndReplaceOptions, FindOneAndUpdateOptions};
+use crate::{FindOptions, IndexModel, InsertManyResult, InsertOneResult};
+use domain::{FieldPath, Metadata, OffsetDateTime, Serialize, WithMetadata};
+use futures_util::future;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use mongodb::options::{ChangeStreamOptions, FullDocumentType};
 use serde::de::DeserializeOwned;
-#[derive(Serialize, Deserialize)]
-pub struct Page {
-    page: i64,
-    limit: i64,
-    sort: Option<Document>,
-}
-pub struct MongoRepository<T: Serialize + DeserializeOwned + Unpin + Send + Sync> {
+use std::time::Duration;
+
+/// Field next to the metadata `deleted_date` holding the deletion time as a
+/// BSON date, which the server can compare and index unlike the domain date.
+const DELETED_AT: &str = "deleted_at";
+
+/// Entities read one at a time, as a query returns them.
+pub type EntityStream<T> = BoxStream<'static, Result<T, StoreError>>;
+
+pub struct MongoRepository<T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static> {
     collection: Collection<T>,
+    indexes: Vec<IndexSpec>,
+    /// Path of the metadata `deleted_date` when deletes are soft.
+    deleted_date: Option<FieldPath>,
 }
 
 impl<T> MongoRepository<T>
 where
-    T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
 {
     pub fn new(collection: Collection<T>) -> Self {
-        MongoRepository { collection }
+        MongoRepository {
+            collection,
+            indexes: Vec::new(),
+            deleted_date: None,
+        }
+    }
+    pub fn set_indexes(self, indexes: Vec<IndexSpec>) -> Self {
+        MongoRepository { indexes, ..self }.with_purge_index()
+    }
+    /// Makes deletes set the `deleted_date` of the metadata instead of
+    /// removing the documents, which reads and updates then skip. They can be
+    /// restored until `purge_deleted` removes them. Upserts still replace
+    /// deleted documents, restoring them. The index `purge_deleted` needs is
+    /// added to the declared ones.
+    pub fn set_soft_delete(self, soft_delete: bool) -> Self
+    where
+        T: WithMetadata,
+    {
+        let deleted_date =
+            soft_delete.then(|| FieldPath::new(T::metadata_field()).join(&Metadata::DELETED_DATE));
+        MongoRepository {
+            deleted_date,
+            ..self
+        }
+        .with_purge_index()
+    }
+
+    fn with_purge_index(mut self) -> Self {
+        if let Some(deleted_date) = &self.deleted_date {
+            let deleted_at = deleted_at(deleted_date);
+            let index = IndexSpec::asc(&deleted_at).set_partial(Filter::exists(&deleted_at, true));
+            if !self.indexes.iter().any(|i| i.name() == index.name()) {
+                self.indexes.push(index);
+            }
+        }
+        self
+    }
+
+    fn live(&self, filter: impl Into<Document>) -> Document {
+        live(self.deleted_date.as_ref(), filter.into())
+    }
+
+    fn soft_deleted(&self) -> Result<&FieldPath, StoreError> {
+        self.deleted_date
+            .as_ref()
+            .ok_or_else(|| StoreError::InvalidArgument(String::from("soft delete is not enabled")))
+    }
+
+    /// Undoes the soft delete of `id`, returning the restored entity or `None`
+    /// if there is no deleted document `id`.
+    pub async fn restore_by_id(&self, id: &str) -> Result<Option<T>, StoreError> {
+        let deleted_date = self.soft_deleted()?;
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let res = self
+            .collection
+            .find_one_and_update(
+                doc! {"_id": id, deleted_date.as_str(): {"$ne": null}},
+                doc! {"$unset": {deleted_date.as_str(): "", deleted_at(deleted_date): ""}},
+                options,
+            )
+            .await?;
+        Ok(res)
+    }
+
+    /// The soft deleted documents matching `filter`.
+    pub async fn find_deleted(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+    ) -> Result<EntityStream<T>, StoreError> {
+        let deleted_date = self.soft_deleted()?.as_str();
+        let filter = Filter::ne(deleted_date, Bson::Null).and(filter);
+        let options = FindOptions::builder()
+            .sort(sort.map(Document::from))
+            .build();
+        let cursor = self.collection.find(filter, options).await?;
+        Ok(cursor.map_err(StoreError::from).boxed())
+    }
+
+    /// Removes the documents soft deleted more than `retention` ago, returning
+    /// how many there were.
+    pub async fn purge_deleted(&self, retention: Duration) -> Result<u64, StoreError> {
+        let deleted_at = deleted_at(self.soft_deleted()?);
+        let cutoff = DateTime::from_millis(
+            DateTime::now().timestamp_millis() - retention.as_millis() as i64,
+        );
+        let res = self
+            .collection
+            .delete_many(doc! {deleted_at: {"$lte": cutoff}}, None)
+            .await?;
+        Ok(res.deleted_count)
+    }
+
+    /// Purges every `interval`, until the task running it is dropped.
+    pub async fn run_purge(&self, retention: Duration, interval: Duration) {
+        loop {
+            match self.purge_deleted(retention).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!(
+                    "purged {purged} deleted documents from {}",
+                    self.collection.name()
+                ),
+                Err(e) => tracing::error!(
+                    "could not purge deleted documents from {}: {e}",
+                    self.collection.name()
+                ),
+            }
+            tokio::time::sleep(interval).await;
+        }
     }
 }
 
-impl<T> Repository<T> for MongoRepository<T>
-where
-    T: Serialize + DeserializeOwned + Unpin + Send + Sync,
-{
-    fn get_collection(&self) -> &Collection<T> {
-        &self.collection
+/// `filter` restricted to the documents that are not soft deleted, when
+/// `deleted_date` is set.
+fn live(deleted_date: Option<&FieldPath>, filter: Document) -> Document {
+    match deleted_date {
+        None => filter,
+        Some(deleted_date) if filter.is_empty() => doc! {deleted_date.as_str(): null},
+        Some(deleted_date) => doc! {"$and": [filter, {deleted_date.as_str(): null}]},
+    }
+}
+
+/// Path of the `DELETED_AT` next to `deleted_date`.
+fn deleted_at(deleted_date: &FieldPath) -> String {
+    match deleted_date.as_str().rsplit_once('.') {
+        Some((parent, _)) => format!("{parent}.{DELETED_AT}"),
+        None => String::from(DELETED_AT),
+    }
+}
+
+/// The update soft deleting a document.
+fn soft_delete(deleted_date: &FieldPath) -> Result<Document, StoreError> {
+    let now = OffsetDateTime::now_utc();
+    Ok(doc! {"$set": {
+        deleted_date.as_str(): mongodb::bson::to_bson(&now)?,
+        deleted_at(deleted_date): date_time(now),
+    }})
+}
+
+fn date_time(date: OffsetDateTime) -> DateTime {
+    DateTime::from_millis((date.unix_timestamp_nanos() / 1_000_000) as i64)
+}
+
+/// Sets the `DELETED_AT` of a deleted `document` from its `deleted_date`.
+/// Entities only have the latter, so a deleted one that is inserted, e.g.
+/// from a backup, would otherwise never be purged.
+fn set_deleted_at(deleted_date: &FieldPath, document: &mut Document) -> Result<(), StoreError> {
+    let mut parents: Vec<&str> = deleted_date.as_str().split('.').collect();
+    let field = parents.pop().unwrap_or_default();
+    let mut parent = document;
+    for segment in parents {
+        parent = match parent.get_document_mut(segment) {
+            Ok(inner) => inner,
+            Err(_) => return Ok(()),
+        };
     }
+    let date = match parent.get(field) {
+        None | Some(Bson::Null) => return Ok(()),
+        Some(date) => mongodb::bson::from_bson::<OffsetDateTime>(date.clone())?,
+    };
+    parent.insert(DELETED_AT, date_time(date));
+    Ok(())
 }
 
+/// Operations on the entities of a collection, whatever holds them: Mongo
+/// with `MongoRepository` or memory with `InMemoryRepository`.
 #[async_trait::async_trait]
-pub trait Repository<T: Serialize + DeserializeOwned + Unpin + Send + Sync> {
-    fn get_collection(&self) -> &Collection<T>;
-    async fn find_all(&self) -> Result<Cursor<T>, Box<dyn std::error::Error>> {
-        let collection = self.get_collection();
-        let cursor = collection.find(doc! {}, None).await?;
-        Ok(cursor)
+pub trait Repository<T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static> {
+    /// Indexes the collection should have.
+    fn indexes(&self) -> &[IndexSpec] {
+        &[]
     }
-    async fn count(&self) -> Result<u64, Box<dyn std::error::Error>> {
-        let collection = self.get_collection();
-        let count = collection.count_documents(doc! {}, None).await?;
-        Ok(count)
+    /// Creates the declared indexes that are missing, so it can run at every
+    /// startup. Existing indexes are never dropped or rebuilt: the ones that
+    /// differ from the declaration or are not declared are reported as drift.
+    async fn ensure_indexes(&self) -> Result<IndexReport, StoreError>;
+    async fn find_all(&self) -> Result<EntityStream<T>, StoreError> {
+        self.find_by(Filter::all(), None).await
     }
+    async fn count(&self) -> Result<u64, StoreError> {
+        self.count_by(Filter::all()).await
+    }
+    async fn find_by(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+    ) -> Result<EntityStream<T>, StoreError>;
+    /// Like `find_by`, also returning the soft deleted entities.
+    async fn find_by_with_deleted(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+    ) -> Result<EntityStream<T>, StoreError> {
+        self.find_by(filter, sort).await
+    }
+    async fn find_one_by(&self, filter: Filter) -> Result<Option<T>, StoreError>;
+    async fn count_by(&self, filter: Filter) -> Result<u64, StoreError>;
+    /// Returns the `page` of the documents matching `query`, empty past the last page.
     async fn find_page(
         &self,
         query: Option<Document>,
         page: Page,
-    ) -> Result<Option<Cursor<T>>, Box<dyn std::error::Error>> {
+    ) -> Result<PageResult<T>, StoreError>;
+    /// Returns the documents matching `query` that come after `page.after()`.
+    async fn find_keyset(
+        &self,
+        query: Option<Document>,
+        page: KeysetPage,
+    ) -> Result<KeysetResult<T>, StoreError>;
+    /// Returns the number of documents deleted, or soft deleted.
+    async fn delete_many(&self, query: Option<Document>) -> Result<u64, StoreError>;
+    /// Returns the `_id`s of the inserted documents, in order.
+    async fn insert_many(&self, data: &[T]) -> Result<Vec<Bson>, StoreError>;
+    /// Returns the `_id` of the inserted document.
+    async fn insert_one(&self, data: &T) -> Result<Bson, StoreError>;
+    async fn find_by_id(&self, id: &str) -> Result<Option<T>, StoreError>;
+    async fn delete_by_id(&self, id: String) -> Result<Option<T>, StoreError>;
+
+    /// Replaces the document `id` with `entity` and returns it as it is now,
+    /// or `None` if there is no such document.
+    async fn update(&self, id: String, entity: &T) -> Result<Option<T>, StoreError> {
+        match self.replace_by_id(&id, entity, ReturnDocument::After).await {
+            Ok(res) => Ok(Some(res)),
+            Err(StoreError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    /// Replaces the whole document `id` with `entity`.
+    async fn replace_by_id(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+    ) -> Result<T, StoreError>;
+    /// Applies `update` to the document `id`, leaving the other fields as they are.
+    async fn update_fields_by_id(
+        &self,
+        id: &str,
+        update: Update,
+        returning: ReturnDocument,
+    ) -> Result<T, StoreError>;
+    /// Replaces the document `id` with `entity`, inserting it if missing.
+    /// Returning `ReturnDocument::Before` gives `None` when it was inserted.
+    async fn upsert_by_id(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+    ) -> Result<Option<T>, StoreError>;
+    /// Replaces the stored `entity` only if it is still at the version it
+    /// was read at, bumping its version and `updated_date` in the same write.
+    /// The `_id` of the document must be the id of its metadata. On error
+    /// `entity` is left as it was.
+    async fn save_versioned(&self, entity: &mut T) -> Result<(), StoreError>
+    where
+        T: WithMetadata;
+    /// Changes to the collection from now on, or from right after the change
+    /// `resume_after` is the token of. The stream ends when the collection
+    /// is dropped or renamed. Mongo only has change streams on replica sets.
+    async fn watch(&self, resume_after: Option<ResumeToken>)
+        -> Result<ChangeStream<T>, StoreError>;
+    /// Runs the writes of `writes` batch by batch, so that the stream is
+    /// never held in memory. Failed writes are reported in the result.
+    async fn bulk_write(
+        &self,
+        writes: BoxStream<'_, WriteOp<T>>,
+        options: BulkOptions,
+    ) -> Result<BulkWriteResult, StoreError>;
+}
+
+#[async_trait::async_trait]
+impl<T> Repository<T> for MongoRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    fn indexes(&self) -> &[IndexSpec] {
+        &self.indexes
+    }
+    async fn ensure_indexes(&self) -> Result<IndexReport, StoreError> {
         let collection = self.get_collection();
-        let query = if let Some(q) = query {
-            q
-        } else {
-            doc! {}
+        let existing: Vec<IndexModel> = match collection.list_indexes(None).await {
+            Ok(cursor) => cursor.try_collect().await?,
+            Err(e) if is_namespace_not_found(&e) => Vec::new(),
+            Err(e) => return Err(e.into()),
         };
-        let count = self.count().await? as i64;
-        let skip = page.limit * (page.page - 1); // start at page 1
-        if count <= skip {
-            return Ok(None);
+        let (report, missing) = IndexReport::compare(self.indexes(), &existing);
+        if !missing.is_empty() {
+            let models = missing.iter().map(IndexSpec::to_model);
+            collection.create_indexes(models, None).await?;
         }
+        for drift in report.drift() {
+            tracing::warn!("index drift on {}: {drift:?}", collection.name());
+        }
+        Ok(report)
+    }
+    async fn find_by(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+    ) -> Result<EntityStream<T>, StoreError> {
         let options = FindOptions::builder()
-            .skip(Some(skip as u64))
-            .sort(page.sort)
-            .limit(Some(page.limit))
+            .sort(sort.map(Document::from))
             .build();
-        let cursor = collection.find(query, Some(options)).await?;
-        Ok(Some(cursor))
+        let cursor = self
+            .get_collection()
+            .find(self.live(filter), options)
+            .await?;
+        Ok(cursor.map_err(StoreError::from).boxed())
+    }
+    async fn find_by_with_deleted(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+    ) -> Result<EntityStream<T>, StoreError> {
+        let options = FindOptions::builder()
+            .sort(sort.map(Document::from))
+            .build();
+        let cursor = self
+            .get_collection()
+            .find(filter.into_document(), options)
+            .await?;
+        Ok(cursor.map_err(StoreError::from).boxed())
+    }
+    async fn find_one_by(&self, filter: Filter) -> Result<Option<T>, StoreError> {
+        let res = self
+            .get_collection()
+            .find_one(self.live(filter), None)
+            .await?;
+        Ok(res)
+    }
+    async fn count_by(&self, filter: Filter) -> Result<u64, StoreError> {
+        let count = self
+            .get_collection()
+            .count_documents(self.live(filter), None)
+            .await?;
+        Ok(count)
+    }
+    async fn find_page(
+        &self,
+        query: Option<Document>,
+        page: Page,
+    ) -> Result<PageResult<T>, StoreError> {
+        page.validate()?;
+        let collection = self.get_collection();
+        let query = self.live(query.unwrap_or_default());
+        let total = collection.count_documents(query.clone(), None).await?;
+        let items = if total > page.skip() {
+            let options = FindOptions::builder()
+                .skip(Some(page.skip()))
+                .sort(page.sort().clone())
+                .limit(Some(page.limit()))
+                .build();
+            collection
+                .find(query, Some(options))
+                .await?
+                .try_collect()
+                .await?
+        } else {
+            Vec::new()
+        };
+        Ok(PageResult::new(items, total, &page))
     }
 
-    async fn delete_many(
+    async fn find_keyset(
         &self,
         query: Option<Document>,
-    ) -> Result<DeleteResult, Box<dyn std::error::Error>> {
-        let query = if let Some(q) = query {
-            q
+        page: KeysetPage,
+    ) -> Result<KeysetResult<T>, StoreError> {
+        // one more than the limit tells whether there is a next page
+        let options = FindOptions::builder()
+            .sort(page.sort())
+            .limit(Some(page.limit() + 1))
+            .build();
+        let mut documents: Vec<Document> = self
+            .get_collection()
+            .clone_with_type::<Document>()
+            .find(
+                self.live(page.filter(query.unwrap_or_default())),
+                Some(options),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        let next = if documents.len() as i64 > page.limit() {
+            documents.truncate(page.limit() as usize);
+            documents
+                .last()
+                .map(|last| page.keyset_of(last))
+                .transpose()?
         } else {
-            doc! {}
+            None
         };
+        let items = documents
+            .into_iter()
+            .map(from_document)
+            .collect::<Result<Vec<T>, _>>()?;
+        Ok(KeysetResult::new(items, next))
+    }
+
+    async fn delete_many(&self, query: Option<Document>) -> Result<u64, StoreError> {
+        let query = self.live(query.unwrap_or_default());
+        if let Some(deleted_date) = &self.deleted_date {
+            let res = self
+                .get_collection()
+                .update_many(query, soft_delete(deleted_date)?, None)
+                .await?;
+            return Ok(res.modified_count);
+        }
         let res = self.get_collection().delete_many(query, None).await?;
-        Ok(res)
+        Ok(res.deleted_count)
     }
 
-    async fn insert_many(
-        &self,
-        data: &Vec<T>,
-    ) -> Result<InsertManyResult, Box<dyn std::error::Error>> {
+    async fn insert_many(&self, data: &[T]) -> Result<Vec<Bson>, StoreError> {
         let res = self.get_collection().insert_many(data, None).await?;
-        Ok(res)
+        let mut ids: Vec<(usize, Bson)> = res.inserted_ids.into_iter().collect();
+        ids.sort_by_key(|(index, _)| *index);
+        Ok(ids.into_iter().map(|(_, id)| id).collect())
     }
 
-    async fn insert_one(&self, data: &T) -> anyhow::Result<InsertOneResult> {
+    async fn insert_one(&self, data: &T) -> Result<Bson, StoreError> {
         let res = self.get_collection().insert_one(data, None).await?;
-        Ok(res)
+        Ok(res.inserted_id)
     }
 
-    async fn find_by_id(&self, id: &str) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    async fn find_by_id(&self, id: &str) -> Result<Option<T>, StoreError> {
         let collection = self.get_collection();
-        let res = collection.find_one(doc! {"_id": id}, None).await?;
+        let res = collection
+            .find_one(self.live(doc! {"_id": id}), None)
+            .await?;
         Ok(res)
     }
-    async fn delete_by_id(&self, id: String) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    async fn delete_by_id(&self, id: String) -> Result<Option<T>, StoreError> {
         let collection = self.get_collection();
-        let res = collection
-            .find_one_and_delete(doc! {"_id": id}, None)
+        let filter = self.live(doc! {"_id": id});
+        let res = match &self.deleted_date {
+            Some(deleted_date) => {
+                let options = FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build();
+                collection
+                    .find_one_and_update(filter, soft_delete(deleted_date)?, options)
+                    .await?
+            }
+            None => collection.find_one_and_delete(filter, None).await?,
+        };
+        Ok(res)
+    }
+
+    async fn replace_by_id(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+    ) -> Result<T, StoreError> {
+        let options = FindOneAndReplaceOptions::builder()
+            .return_document(returning)
+            .build();
+        self.get_collection()
+            .find_one_and_replace(self.live(doc! {"_id": id}), entity, options)
+            .await?
+            .ok_or_else(|| StoreError::NotFound {
+                id: String::from(id),
+            })
+    }
+
+    async fn update_fields_by_id(
+        &self,
+        id: &str,
+        update: Update,
+        returning: ReturnDocument,
+    ) -> Result<T, StoreError> {
+        let not_found = || StoreError::NotFound {
+            id: String::from(id),
+        };
+        if update.is_empty() {
+            return self.find_by_id(id).await?.ok_or_else(not_found);
+        }
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(returning)
+            .build();
+        self.get_collection()
+            .find_one_and_update(self.live(doc! {"_id": id}), update.to_document(), options)
+            .await?
+            .ok_or_else(not_found)
+    }
+
+    async fn upsert_by_id(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+    ) -> Result<Option<T>, StoreError> {
+        let options = FindOneAndReplaceOptions::builder()
+            .return_document(returning)
+            .upsert(true)
+            .build();
+        let res = self
+            .get_collection()
+            .find_one_and_replace(doc! {"_id": id}, entity, options)
             .await?;
         Ok(res)
     }
 
-    async fn update(
+    async fn save_versioned(&self, entity: &mut T) -> Result<(), StoreError>
+    where
+        T: WithMetadata,
+    {
+        save_versioned(
+            self.get_collection(),
+            self.deleted_date.as_ref(),
+            entity,
+            None,
+        )
+        .await
+    }
+    async fn watch(
+        &self,
+        resume_after: Option<ResumeToken>,
+    ) -> Result<ChangeStream<T>, StoreError> {
+        // updates come with the entity looked up when the event is read
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .resume_after(resume_after)
+            .build();
+        let events = self.get_collection().watch(None, options).await?;
+        Ok(events
+            .map_err(StoreError::from)
+            .try_filter_map(|event| future::ready(Change::from_event(event)))
+            .boxed())
+    }
+    async fn bulk_write(
+        &self,
+        writes: BoxStream<'_, WriteOp<T>>,
+        options: BulkOptions,
+    ) -> Result<BulkWriteResult, StoreError> {
+        let collection = self.get_collection();
+        let database = collection.client().database(&collection.namespace().db);
+        let mut result = BulkWriteResult::default();
+        let mut batches = writes.chunks(options.batch_size());
+        let mut offset = 0;
+        while let Some(batch) = batches.next().await {
+            let size = batch.len();
+            let batch = batch
+                .into_iter()
+                .enumerate()
+                .map(|(index, write)| (offset + index, write))
+                .collect();
+            for (kind, run) in runs(batch, options.ordered()) {
+                let (indexes, command) = self.write_command(kind, run, options.ordered())?;
+                if indexes.is_empty() {
+                    continue;
+                }
+                let reply = database.run_command(command, None).await?;
+                result.add_reply(kind, &indexes, &reply);
+                if options.ordered() && result.has_errors() {
+                    return Ok(result);
+                }
+            }
+            offset += size;
+        }
+        Ok(result)
+    }
+}
+
+impl<T> MongoRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    /// The command running `writes`, all of `kind`, with their indexes.
+    /// Empty updates are left out as there is nothing to send.
+    fn write_command(
+        &self,
+        kind: WriteKind,
+        writes: Vec<(usize, WriteOp<T>)>,
+        ordered: bool,
+    ) -> Result<(Vec<usize>, Document), StoreError> {
+        let name = self.collection.name();
+        let mut indexes = Vec::new();
+        let mut statements = Vec::new();
+        for (index, write) in writes {
+            let statement = match write {
+                WriteOp::Insert(entity) => {
+                    let mut document = to_document(&entity)?;
+                    if let Some(deleted_date) = &self.deleted_date {
+                        set_deleted_at(deleted_date, &mut document)?;
+                    }
+                    document
+                }
+                // like `upsert_by_id`, an upsert replaces a deleted document
+                WriteOp::Replace { id, entity, upsert } => doc! {
+                    "q": if upsert { doc! {"_id": id} } else { self.live(doc! {"_id": id}) },
+                    "u": to_document(&entity)?,
+                    "upsert": upsert,
+                },
+                WriteOp::Update { update, .. } if update.is_empty() => continue,
+                WriteOp::Update { id, update } => doc! {
+                    "q": self.live(doc! {"_id": id}),
+                    "u": update.to_document(),
+                },
+                WriteOp::Delete { id } => match &self.deleted_date {
+                    Some(deleted_date) => doc! {
+                        "q": self.live(doc! {"_id": id}),
+                        "u": soft_delete(deleted_date)?,
+                    },
+                    None => doc! {"q": {"_id": id}, "limit": 1},
+                },
+            };
+            indexes.push(index);
+            statements.push(statement);
+        }
+        let mut command = match kind {
+            WriteKind::Insert => doc! {"insert": name, "documents": statements},
+            // soft deletes are updates, their count is still read as deleted
+            WriteKind::Delete if self.deleted_date.is_some() => {
+                doc! {"update": name, "updates": statements}
+            }
+            WriteKind::Update => doc! {"update": name, "updates": statements},
+            WriteKind::Delete => doc! {"delete": name, "deletes": statements},
+        };
+        command.insert("ordered", ordered);
+        Ok((indexes, command))
+    }
+}
+
+/// Repository operations within a transaction, see `StoreClient::transaction`.
+#[async_trait::async_trait]
+pub trait SessionRepository<T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static>:
+    Repository<T>
+{
+    fn get_collection(&self) -> &Collection<T>;
+    /// Path of the metadata `deleted_date` when deletes are soft.
+    fn deleted_date_field(&self) -> Option<&FieldPath> {
+        None
+    }
+    async fn find_by_id_with_session(
+        &self,
+        id: &str,
+        session: &mut ClientSession,
+    ) -> Result<Option<T>, StoreError> {
+        let res = self
+            .get_collection()
+            .find_one_with_session(
+                live(self.deleted_date_field(), doc! {"_id": id}),
+                None,
+                session,
+            )
+            .await?;
+        Ok(res)
+    }
+    async fn find_one_by_with_session(
+        &self,
+        filter: Filter,
+        session: &mut ClientSession,
+    ) -> Result<Option<T>, StoreError> {
+        let res = self
+            .get_collection()
+            .find_one_with_session(
+                live(self.deleted_date_field(), filter.into()),
+                None,
+                session,
+            )
+            .await?;
+        Ok(res)
+    }
+    async fn find_by_with_session(
+        &self,
+        filter: Filter,
+        sort: Option<Sort>,
+        session: &mut ClientSession,
+    ) -> Result<Vec<T>, StoreError> {
+        let options = FindOptions::builder()
+            .sort(sort.map(Document::from))
+            .build();
+        let mut cursor = self
+            .get_collection()
+            .find_with_session(
+                live(self.deleted_date_field(), filter.into()),
+                options,
+                session,
+            )
+            .await?;
+        let res = cursor.stream(session).try_collect().await?;
+        Ok(res)
+    }
+    async fn count_by_with_session(
         &self,
-        id: String,
+        filter: Filter,
+        session: &mut ClientSession,
+    ) -> Result<u64, StoreError> {
+        let count = self
+            .get_collection()
+            .count_documents_with_session(
+                live(self.deleted_date_field(), filter.into()),
+                None,
+                session,
+            )
+            .await?;
+        Ok(count)
+    }
+    async fn insert_one_with_session(
+        &self,
+        data: &T,
+        session: &mut ClientSession,
+    ) -> Result<InsertOneResult, StoreError> {
+        let res = self
+            .get_collection()
+            .insert_one_with_session(data, None, session)
+            .await?;
+        Ok(res)
+    }
+    async fn insert_many_with_session(
+        &self,
+        data: &[T],
+        session: &mut ClientSession,
+    ) -> Result<InsertManyResult, StoreError> {
+        let res = self
+            .get_collection()
+            .insert_many_with_session(data, None, session)
+            .await?;
+        Ok(res)
+    }
+    async fn replace_by_id_with_session(
+        &self,
+        id: &str,
+        entity: &T,
+        returning: ReturnDocument,
+        session: &mut ClientSession,
+    ) -> Result<T, StoreError> {
+        let options = FindOneAndReplaceOptions::builder()
+            .return_document(returning)
+            .build();
+        self.get_collection()
+            .find_one_and_replace_with_session(
+                live(self.deleted_date_field(), doc! {"_id": id}),
+                entity,
+                options,
+                session,
+            )
+            .await?
+            .ok_or_else(|| StoreError::NotFound {
+                id: String::from(id),
+            })
+    }
+    async fn update_fields_by_id_with_session(
+        &self,
+        id: &str,
+        update: Update,
+        returning: ReturnDocument,
+        session: &mut ClientSession,
+    ) -> Result<T, StoreError> {
+        let not_found = || StoreError::NotFound {
+            id: String::from(id),
+        };
+        if update.is_empty() {
+            return self
+                .find_by_id_with_session(id, session)
+                .await?
+                .ok_or_else(not_found);
+        }
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(returning)
+            .build();
+        self.get_collection()
+            .find_one_and_update_with_session(
+                live(self.deleted_date_field(), doc! {"_id": id}),
+                update.to_document(),
+                options,
+                session,
+            )
+            .await?
+            .ok_or_else(not_found)
+    }
+    async fn upsert_by_id_with_session(
+        &self,
+        id: &str,
         entity: &T,
-    ) -> Result<Option<T>, Box<dyn std::error::Error>> {
+        returning: ReturnDocument,
+        session: &mut ClientSession,
+    ) -> Result<Option<T>, StoreError> {
+        let options = FindOneAndReplaceOptions::builder()
+            .return_document(returning)
+            .upsert(true)
+            .build();
+        let res = self
+            .get_collection()
+            .find_one_and_replace_with_session(doc! {"_id": id}, entity, options, session)
+            .await?;
+        Ok(res)
+    }
+    async fn delete_by_id_with_session(
+        &self,
+        id: &str,
+        session: &mut ClientSession,
+    ) -> Result<Option<T>, StoreError> {
         let collection = self.get_collection();
-        let update_doc = to_document(entity)?;
+        let filter = live(self.deleted_date_field(), doc! {"_id": id});
+        let res = match self.deleted_date_field() {
+            Some(deleted_date) => {
+                let options = FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build();
+                collection
+                    .find_one_and_update_with_session(
+                        filter,
+                        soft_delete(deleted_date)?,
+                        options,
+                        session,
+                    )
+                    .await?
+            }
+            None => {
+                collection
+                    .find_one_and_delete_with_session(filter, None, session)
+                    .await?
+            }
+        };
+        Ok(res)
+    }
+    /// Returns the number of documents deleted, or soft deleted.
+    async fn delete_many_with_session(
+        &self,
+        filter: Filter,
+        session: &mut ClientSession,
+    ) -> Result<u64, StoreError> {
+        let collection = self.get_collection();
+        let filter = live(self.deleted_date_field(), filter.into_document());
+        if let Some(deleted_date) = self.deleted_date_field() {
+            let res = collection
+                .update_many_with_session(filter, soft_delete(deleted_date)?, None, session)
+                .await?;
+            return Ok(res.modified_count);
+        }
         let res = collection
-            .find_one_and_update(doc! {"_id": id}, update_doc, None)
+            .delete_many_with_session(filter, None, session)
             .await?;
-        Ok(res)
+        Ok(res.deleted_count)
+    }
+    async fn save_versioned_with_session(
+        &self,
+        entity: &mut T,
+        session: &mut ClientSession,
+    ) -> Result<(), StoreError>
+    where
+        T: WithMetadata,
+    {
+        save_versioned(
+            self.get_collection(),
+            self.deleted_date_field(),
+            entity,
+            Some(session),
+        )
+        .await
+    }
+}
+
+impl<T> SessionRepository<T> for MongoRepository<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    fn get_collection(&self) -> &Collection<T> {
+        &self.collection
+    }
+    fn deleted_date_field(&self) -> Option<&FieldPath> {
+        self.deleted_date.as_ref()
+    }
+}
+
+async fn save_versioned<T>(
+    collection: &Collection<T>,
+    deleted_date: Option<&FieldPath>,
+    entity: &mut T,
+    mut session: Option<&mut ClientSession>,
+) -> Result<(), StoreError>
+where
+    T: Serialize + DeserializeOwned + WithMetadata + Unpin + Send + Sync + 'static,
+{
+    let previous = entity.domain_metadata().clone();
+    let id = previous.id().as_str();
+    let expected = previous.version().map(Bson::from).unwrap_or(Bson::Null);
+    entity.domain_metadata_mut().update_metadata();
+    let filter = live(
+        deleted_date,
+        doc! {"_id": id, format!("{}.version", T::metadata_field()): expected},
+    );
+    let replaced = match session.as_deref_mut() {
+        Some(session) => {
+            collection
+                .find_one_and_replace_with_session(filter, &*entity, None, session)
+                .await
+        }
+        None => {
+            collection
+                .find_one_and_replace(filter, &*entity, None)
+                .await
+        }
+    };
+    let error = match replaced {
+        Ok(Some(_)) => return Ok(()),
+        Ok(None) => {
+            let count = match session {
+                Some(session) => {
+                    collection
+                        .count_documents_with_session(
+                            live(deleted_date, doc! {"_id": id}),
+                            None,
+                            session,
+                        )
+                        .await
+                }
+                None => {
+                    collection
+                        .count_documents(live(deleted_date, doc! {"_id": id}), None)
+                        .await
+                }
+            };
+            match count {
+                Ok(0) => StoreError::NotFound {
+                    id: String::from(id),
+                },
+                Ok(_) => StoreError::VersionConflict {
+                    id: String::from(id),
+                    expected: previous.version().unwrap_or_default(),
+                },
+                Err(e) => e.into(),
+            }
+        }
+        Err(e) => e.into(),
+    };
+    *entity.domain_metadata_mut() = previous;
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use crate::repository::{live, set_deleted_at, soft_delete};
+    use domain::{FieldPath, Metadata, OffsetDateTime};
+
+    #[test]
+    fn test_live() {
+        let deleted_date = FieldPath::new("metadata").join(&Metadata::DELETED_DATE);
+        assert_eq!(doc! {"a": 1}, live(None, doc! {"a": 1}));
+        assert_eq!(
+            doc! {"metadata.deleted_date": null},
+            live(Some(&deleted_date), doc! {})
+        );
+        assert_eq!(
+            doc! {"$and": [{"a": 1}, {"metadata.deleted_date": null}]},
+            live(Some(&deleted_date), doc! {"a": 1})
+        );
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        let before = OffsetDateTime::now_utc();
+        let deleted_date = FieldPath::new("metadata").join(&Metadata::DELETED_DATE);
+        let update = soft_delete(&deleted_date).unwrap();
+        let date = update
+            .get_document("$set")
+            .unwrap()
+            .get("metadata.deleted_date")
+            .cloned()
+            .unwrap();
+        let date: OffsetDateTime = mongodb::bson::from_bson(date).unwrap();
+        assert!(before <= date && date <= OffsetDateTime::now_utc());
+        let deleted_at = update
+            .get_document("$set")
+            .unwrap()
+            .get_datetime("metadata.deleted_at")
+            .unwrap();
+        assert_eq!(
+            (date.unix_timestamp_nanos() / 1_000_000) as i64,
+            deleted_at.timestamp_millis()
+        );
+
+        let mut imported =
+            doc! {"_id": "a", "metadata": {"deleted_date": mongodb::bson::to_bson(&date).unwrap()}};
+        set_deleted_at(&deleted_date, &mut imported).unwrap();
+        assert_eq!(
+            deleted_at,
+            imported
+                .get_document("metadata")
+                .unwrap()
+                .get_datetime("deleted_at")
+                .unwrap()
+        );
+        let mut live = doc! {"_id": "b", "metadata": {"version": 1}};
+        set_deleted_at(&deleted_date, &mut live).unwrap();
+        assert_eq!(doc! {"_id": "b", "metadata": {"version": 1}}, live);
     }
 }
@@ -0,0 +1,91 @@
+use crate::{Bson, Document};
+
+/// Partial update of a document, built from `$set`, `$unset`, `$push` and
+/// `$pull` operators. Like in a `Filter`, fields can be `FieldPath` constants.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Update {
+    set: Document,
+    unset: Document,
+    push: Document,
+    pull: Document,
+}
+
+impl Update {
+    pub fn new() -> Update {
+        Update::default()
+    }
+    pub fn set(mut self, field: impl AsRef<str>, value: impl Into<Bson>) -> Self {
+        self.set.insert(field.as_ref(), value.into());
+        self
+    }
+    pub fn unset(mut self, field: impl AsRef<str>) -> Self {
+        self.unset.insert(field.as_ref(), "");
+        self
+    }
+    /// Appends `value` to the array `field`.
+    pub fn push(mut self, field: impl AsRef<str>, value: impl Into<Bson>) -> Self {
+        self.push.insert(field.as_ref(), value.into());
+        self
+    }
+    /// Removes every occurrence of `value` from the array `field`.
+    pub fn pull(mut self, field: impl AsRef<str>, value: impl Into<Bson>) -> Self {
+        self.pull.insert(field.as_ref(), value.into());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.unset.is_empty() && self.push.is_empty() && self.pull.is_empty()
+    }
+
+    pub fn to_document(&self) -> Document {
+        let mut update = Document::new();
+        for (operator, fields) in [
+            ("$set", &self.set),
+            ("$unset", &self.unset),
+            ("$push", &self.push),
+            ("$pull", &self.pull),
+        ] {
+            if !fields.is_empty() {
+                update.insert(operator, fields.clone());
+            }
+        }
+        update
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::update::Update;
+    use crate::{doc, DateTime};
+    use domain::FieldPath;
+
+    #[test]
+    fn test_update_document() {
+        let now = DateTime::now();
+        let update = Update::new()
+            .set("profile.first_name", "Nordine")
+            .set("updated_at", now)
+            .unset("password_reset_token")
+            .push("roles", "ADMIN")
+            .pull("roles", "GUEST");
+        assert_eq!(
+            doc! {
+                "$set": {"profile.first_name": "Nordine", "updated_at": now},
+                "$unset": {"password_reset_token": ""},
+                "$push": {"roles": "ADMIN"},
+                "$pull": {"roles": "GUEST"},
+            },
+            update.to_document()
+        );
+        let roles = FieldPath::new("roles");
+        assert_eq!(
+            doc! {"$push": {"roles": "ADMIN"}, "$pull": {"roles": "GUEST"}},
+            Update::new()
+                .push(&roles, "ADMIN")
+                .pull(roles, "GUEST")
+                .to_document()
+        );
+        assert!(Update::new().is_empty());
+        assert_eq!(doc! {}, Update::new().to_document());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::StoreError;
+use crate::{doc, Bson, DateTime, Document};
+use futures_util::stream::BoxStream;
+pub use mongodb::change_stream::event::ResumeToken;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType};
+use mongodb::options::ReplaceOptions;
+use mongodb::{Collection, Database};
+
+/// Collection where `ResumeTokens` keeps a token per consumer.
+pub const RESUME_TOKENS_COLLECTION: &str = "_resume_tokens";
+
+/// Changes to a collection as they happen, see `Repository::watch`.
+pub type ChangeStream<T> = BoxStream<'static, Result<Change<T>, StoreError>>;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ChangeEvent<T> {
+    Inserted {
+        id: Bson,
+        document: T,
+    },
+    /// `document` is the entity as it is when the event is read, which may
+    /// be after later changes, and `None` if it was deleted since.
+    Updated {
+        id: Bson,
+        updated_fields: Document,
+        removed_fields: Vec<String>,
+        document: Option<T>,
+    },
+    Replaced {
+        id: Bson,
+        document: T,
+    },
+    Deleted {
+        id: Bson,
+    },
+}
+
+impl<T> ChangeEvent<T> {
+    /// `_id` of the changed document.
+    pub fn id(&self) -> &Bson {
+        match self {
+            ChangeEvent::Inserted { id, .. }
+            | ChangeEvent::Updated { id, .. }
+            | ChangeEvent::Replaced { id, .. }
+            | ChangeEvent::Deleted { id } => id,
+        }
+    }
+
+    pub(crate) fn try_map<U>(
+        self,
+        f: impl Fn(T) -> Result<U, StoreError>,
+    ) -> Result<ChangeEvent<U>, StoreError> {
+        Ok(match self {
+            ChangeEvent::Inserted { id, document } => ChangeEvent::Inserted {
+                id,
+                document: f(document)?,
+            },
+            ChangeEvent::Updated {
+                id,
+                updated_fields,
+                removed_fields,
+                document,
+            } => ChangeEvent::Updated {
+                id,
+                updated_fields,
+                removed_fields,
+                document: document.map(f).transpose()?,
+            },
+            ChangeEvent::Replaced { id, document } => ChangeEvent::Replaced {
+                id,
+                document: f(document)?,
+            },
+            ChangeEvent::Deleted { id } => ChangeEvent::Deleted { id },
+        })
+    }
+}
+
+/// A change and the token to watch again from right after it.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Change<T> {
+    resume_token: ResumeToken,
+    event: ChangeEvent<T>,
+}
+
+impl<T> Change<T> {
+    pub(crate) fn new(resume_token: ResumeToken, event: ChangeEvent<T>) -> Change<T> {
+        Change {
+            resume_token,
+            event,
+        }
+    }
+
+    /// The change of a server event, `None` for the events on the whole
+    /// collection like a drop, after which the stream ends.
+    pub(crate) fn from_event(event: ChangeStreamEvent<T>) -> Result<Option<Change<T>>, StoreError> {
+        let id = event
+            .document_key
+            .as_ref()
+            .and_then(|key| key.get("_id"))
+            .cloned()
+            .unwrap_or(Bson::Null);
+        let document = event.full_document;
+        let change = match event.operation_type {
+            OperationType::Insert => ChangeEvent::Inserted {
+                id,
+                document: required(document)?,
+            },
+            OperationType::Replace => ChangeEvent::Replaced {
+                id,
+                document: required(document)?,
+            },
+            OperationType::Update => {
+                let (updated_fields, removed_fields) = event
+                    .update_description
+                    .map(|description| (description.updated_fields, description.removed_fields))
+                    .unwrap_or_default();
+                ChangeEvent::Updated {
+                    id,
+                    updated_fields,
+                    removed_fields,
+                    document,
+                }
+            }
+            OperationType::Delete => ChangeEvent::Deleted { id },
+            _ => return Ok(None),
+        };
+        Ok(Some(Change::new(event.id, change)))
+    }
+
+    pub fn resume_token(&self) -> &ResumeToken {
+        &self.resume_token
+    }
+    pub fn event(&self) -> &ChangeEvent<T> {
+        &self.event
+    }
+    pub fn into_event(self) -> ChangeEvent<T> {
+        self.event
+    }
+}
+
+fn required<T>(document: Option<T>) -> Result<T, StoreError> {
+    document.ok_or_else(|| StoreError::Serialization("change event without its document".into()))
+}
+
+/// Last resume token of each change stream consumer, so that a restarted
+/// consumer continues where it stopped:
+///
+/// ```ignore
+/// let mut changes = repository.watch(tokens.load("search").await?).await?;
+/// while let Some(change) = changes.try_next().await? {
+///     index(change.event()).await?;
+///     tokens.save("search", change.resume_token()).await?;
+/// }
+/// ```
+///
+/// Saving after handling a change means a crash in between has it handled
+/// again on restart.
+pub struct ResumeTokens {
+    collection: Collection<Document>,
+}
+
+impl ResumeTokens {
+    pub fn new(database: &Database) -> ResumeTokens {
+        ResumeTokens {
+            collection: database.collection(RESUME_TOKENS_COLLECTION),
+        }
+    }
+
+    pub async fn load(&self, consumer: &str) -> Result<Option<ResumeToken>, StoreError> {
+        let saved = self
+            .collection
+            .find_one(doc! {"_id": consumer}, None)
+            .await?;
+        let token = saved.and_then(|mut saved| saved.remove("token"));
+        Ok(token.map(mongodb::bson::from_bson).transpose()?)
+    }
+
+    pub async fn save(&self, consumer: &str, token: &ResumeToken) -> Result<(), StoreError> {
+        let saved = doc! {
+            "_id": consumer,
+            "token": mongodb::bson::to_bson(token)?,
+            "saved_at": DateTime::now(),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.collection
+            .replace_one(doc! {"_id": consumer}, saved, options)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watch::{Change, ChangeEvent};
+    use crate::{doc, Bson};
+    use mongodb::bson::{from_bson, from_document, to_bson};
+    use mongodb::change_stream::event::{ChangeStreamEvent, ResumeToken};
+
+    fn event(event: mongodb::bson::Document) -> ChangeStreamEvent<mongodb::bson::Document> {
+        from_document(event).unwrap()
+    }
+
+    #[test]
+    fn test_from_event() {
+        let update = event(doc! {
+            "_id": {"_data": "8263"},
+            "operationType": "update",
+            "documentKey": {"_id": "42"},
+            "updateDescription": {"updatedFields": {"title": "East of Eden"}, "removedFields": ["tags"]},
+            "fullDocument": {"_id": "42", "title": "East of Eden"},
+        });
+        let change = Change::from_event(update).unwrap().unwrap();
+        assert_eq!(
+            &ChangeEvent::Updated {
+                id: Bson::from("42"),
+                updated_fields: doc! {"title": "East of Eden"},
+                removed_fields: vec![String::from("tags")],
+                document: Some(doc! {"_id": "42", "title": "East of Eden"}),
+            },
+            change.event()
+        );
+        let token: ResumeToken = from_bson(Bson::from(doc! {"_data": "8263"})).unwrap();
+        assert_eq!(&token, change.resume_token());
+        assert_eq!(Bson::from(doc! {"_data": "8263"}), to_bson(&token).unwrap());
+
+        let delete = event(doc! {
+            "_id": {"_data": "8264"},
+            "operationType": "delete",
+            "documentKey": {"_id": "42"},
+        });
+        let change = Change::from_event(delete).unwrap().unwrap();
+        assert_eq!(&Bson::from("42"), change.event().id());
+        let drop = event(doc! {"_id": {"_data": "8265"}, "operationType": "drop"});
+        assert!(Change::from_event(drop).unwrap().is_none());
+        let insert = event(doc! {
+            "_id": {"_data": "8266"},
+            "operationType": "insert",
+            "documentKey": {"_id": "43"},
+        });
+        assert!(Change::from_event(insert).is_err());
+    }
+}
@@ -1,12 +1,17 @@
 #[cfg(test)]
 mod test {
     use domain::WithJsonProcessor;
-    use futures_util::TryStreamExt;
-    use store::{doc, MongoRepository, Repository};
+    use futures_util::{StreamExt, TryStreamExt};
+    use store::{
+        doc, export_jsonl, import_jsonl, BulkOptions, ChangeEvent, Document, ExportOptions, Filter,
+        IndexDrift, IndexSpec, KeysetPage, Migration, MigrationCommand, MigrationError,
+        MigrationState, Migrator, MongoRepository, Page, Repository, ResumeTokens, ReturnDocument,
+        SessionRepository, StoreError, Update, WriteOp, MIGRATIONS_COLLECTION,
+    };
     use tracing::Level;
     use tracing_subscriber::FmtSubscriber;
 
-    use domain::{Deserialize, Id, Serialize};
+    use domain::{Deserialize, Id, Metadata, Serialize, WithMetadata};
     use store::StoreClient;
 
     #[derive(Debug, Default, WithJsonProcessor, Serialize, Deserialize)]
@@ -15,6 +20,8 @@ mod test {
         id: Id,
         title: String,
         author: String,
+        #[serde(default)]
+        tags: Vec<String>,
     }
 
     #[tokio::test]
@@ -46,7 +53,7 @@ mod test {
         ];
         repository.insert_many(&books).await.unwrap();
         println!("from find by id");
-        let book = books.get(0).unwrap();
+        let book = books.first().unwrap();
         let id = &book.id;
         let result = repository.find_by_id(id).await.unwrap();
         if let Some(book) = result {
@@ -59,4 +66,580 @@ mod test {
             println!("{}", book.to_json().unwrap())
         }
     }
+
+    #[tokio::test]
+    async fn test_update_operations() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let repository = MongoRepository::new(store_client.get_db().collection::<Book>("updates"));
+        repository.delete_many(None).await.unwrap();
+        let book = Book {
+            title: "The Grapes of Wrath".to_string(),
+            author: "Steinbeck".to_string(),
+            ..Default::default()
+        };
+
+        let inserted = repository
+            .upsert_by_id(&book.id, &book, ReturnDocument::Before)
+            .await
+            .unwrap();
+        assert!(inserted.is_none());
+
+        let before = repository
+            .update_fields_by_id(
+                &book.id,
+                Update::new()
+                    .set("author", "John Steinbeck")
+                    .push("tags", "classic"),
+                ReturnDocument::Before,
+            )
+            .await
+            .unwrap();
+        assert_eq!("Steinbeck", before.author);
+        let after = repository
+            .update_fields_by_id(
+                &book.id,
+                Update::new().push("tags", "novel").pull("tags", "classic"),
+                ReturnDocument::After,
+            )
+            .await
+            .unwrap();
+        assert_eq!("John Steinbeck", after.author);
+        assert_eq!(vec![String::from("novel")], after.tags);
+
+        let replacement = Book {
+            id: book.id.clone(),
+            title: "East of Eden".to_string(),
+            ..Default::default()
+        };
+        let replaced = repository
+            .replace_by_id(&book.id, &replacement, ReturnDocument::After)
+            .await
+            .unwrap();
+        assert_eq!("East of Eden", replaced.title);
+        assert!(replaced.tags.is_empty());
+
+        let missing = repository
+            .replace_by_id("missing", &replacement, ReturnDocument::After)
+            .await;
+        assert!(matches!(missing, Err(StoreError::NotFound { .. })));
+        let count = repository.count().await.unwrap();
+        assert_eq!(1, count);
+    }
+
+    #[derive(Debug, Default, Clone, WithJsonProcessor, Serialize, Deserialize, WithMetadata)]
+    struct Account {
+        #[serde(rename = "_id")]
+        id: Id,
+        #[serde(rename = "metadata")]
+        domain_metadata: Metadata,
+        balance: i64,
+    }
+
+    #[tokio::test]
+    async fn test_save_versioned() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let repository =
+            MongoRepository::new(store_client.get_db().collection::<Account>("accounts"));
+        repository.delete_many(None).await.unwrap();
+        let id = Id::default();
+        let account = Account {
+            domain_metadata: Metadata::new_with_default(&id),
+            id,
+            balance: 10,
+        };
+        repository.insert_one(&account).await.unwrap();
+        let version = *account.domain_metadata.version();
+
+        let mut first = account.clone();
+        let mut second = account.clone();
+        first.balance += 5;
+        repository.save_versioned(&mut first).await.unwrap();
+        assert_eq!(version.map(|v| v + 1), *first.domain_metadata.version());
+        assert!(first.domain_metadata.updated_date().is_some());
+
+        second.balance -= 5;
+        let conflict = repository.save_versioned(&mut second).await;
+        assert!(matches!(conflict, Err(StoreError::VersionConflict { .. })));
+        assert_eq!(version, *second.domain_metadata.version());
+        let stored = repository.find_by_id(&account.id).await.unwrap().unwrap();
+        assert_eq!(15, stored.balance);
+    }
+
+    #[tokio::test]
+    async fn test_pagination() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let repository = MongoRepository::new(store_client.get_db().collection::<Book>("pages"));
+        repository.delete_many(None).await.unwrap();
+        let books: Vec<Book> = (0..25)
+            .map(|i| Book {
+                title: format!("Volume {i:02}"),
+                author: String::from(if i % 2 == 0 { "Even" } else { "Odd" }),
+                ..Default::default()
+            })
+            .collect();
+        repository.insert_many(&books).await.unwrap();
+
+        let query = Some(doc! {"author": "Even"});
+        let page = Page::new(2, 5).unwrap().set_sort(doc! {"title": 1});
+        let result = repository.find_page(query.clone(), page).await.unwrap();
+        assert_eq!(13, result.total());
+        assert_eq!(3, result.total_pages());
+        assert!(result.has_next());
+        let titles: Vec<&str> = result.items().iter().map(|b| b.title.as_str()).collect();
+        assert_eq!(
+            vec![
+                "Volume 10",
+                "Volume 12",
+                "Volume 14",
+                "Volume 16",
+                "Volume 18"
+            ],
+            titles
+        );
+        let past_the_end = repository
+            .find_page(query, Page::new(4, 5).unwrap())
+            .await
+            .unwrap();
+        assert!(past_the_end.items().is_empty());
+        assert_eq!(13, past_the_end.total());
+
+        let mut page = KeysetPage::by_field("author", 10).unwrap();
+        let mut seen = Vec::new();
+        loop {
+            let result = repository.find_keyset(None, page.clone()).await.unwrap();
+            seen.extend(result.items().iter().map(|b| b.id.to_string()));
+            match result.next() {
+                Some(next) => page = page.set_after(next.clone()),
+                None => break,
+            }
+        }
+        seen.sort();
+        seen.dedup();
+        assert_eq!(25, seen.len());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_indexes() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let collection = store_client.get_db().collection::<Book>("indexed_books");
+        collection.drop(None).await.unwrap();
+        let repository = MongoRepository::new(collection.clone()).set_indexes(vec![
+            IndexSpec::asc("title").set_unique(true),
+            IndexSpec::text(["title", "author"]),
+        ]);
+
+        let report = repository.ensure_indexes().await.unwrap();
+        assert_eq!(2, report.created().len());
+        let report = repository.ensure_indexes().await.unwrap();
+        assert!(report.created().is_empty());
+        assert_eq!(2, report.unchanged().len());
+
+        let book = Book {
+            title: "The Grapes of Wrath".to_string(),
+            author: "John Steinbeck".to_string(),
+            ..Default::default()
+        };
+        repository.insert_one(&book).await.unwrap();
+        let duplicate = Book {
+            title: book.title.clone(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            repository.insert_one(&duplicate).await,
+            Err(StoreError::DuplicateKey { .. })
+        ));
+
+        let drifted = MongoRepository::new(collection)
+            .set_indexes(vec![IndexSpec::asc("title"), IndexSpec::asc("author")]);
+        let report = drifted.ensure_indexes().await.unwrap();
+        assert_eq!(&vec![String::from("author_1")], report.created());
+        assert!(matches!(
+            &report.drift()[..],
+            [IndexDrift::Changed { .. }, IndexDrift::Undeclared { .. }]
+        ));
+    }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct AuditRecord {
+        #[serde(rename = "_id")]
+        id: Id,
+        book_id: String,
+        action: String,
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let db = store_client.get_db();
+        let books = MongoRepository::new(db.collection::<Book>("transaction_books"));
+        let audit = MongoRepository::new(db.collection::<AuditRecord>("transaction_audit"));
+        books.delete_many(None).await.unwrap();
+        audit.delete_many(None).await.unwrap();
+        let book = Book {
+            title: "The Grapes of Wrath".to_string(),
+            author: "Steinbeck".to_string(),
+            ..Default::default()
+        };
+        books.insert_one(&book).await.unwrap();
+
+        let renamed = store_client
+            .transaction(|session| {
+                Box::pin(async {
+                    let renamed = books
+                        .update_fields_by_id_with_session(
+                            &book.id,
+                            Update::new().set("author", "John Steinbeck"),
+                            ReturnDocument::After,
+                            session,
+                        )
+                        .await?;
+                    let record = AuditRecord {
+                        book_id: book.id.to_string(),
+                        action: String::from("rename author"),
+                        ..Default::default()
+                    };
+                    audit.insert_one_with_session(&record, session).await?;
+                    Ok(renamed)
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!("John Steinbeck", renamed.author);
+        assert_eq!(1, audit.count().await.unwrap());
+
+        let failed: Result<(), StoreError> = store_client
+            .transaction(|session| {
+                Box::pin(async {
+                    books.delete_by_id_with_session(&book.id, session).await?;
+                    Err(StoreError::NotFound {
+                        id: String::from("missing"),
+                    })
+                })
+            })
+            .await;
+        assert!(matches!(failed, Err(StoreError::NotFound { .. })));
+        assert!(books.find_by_id(&book.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_migrations() {
+        let store_client = StoreClient::new(String::from("migrations_test"))
+            .await
+            .unwrap();
+        let db = store_client.get_db();
+        db.collection::<Document>(MIGRATIONS_COLLECTION)
+            .drop(None)
+            .await
+            .unwrap();
+        db.collection::<Document>("migrated_books")
+            .drop(None)
+            .await
+            .unwrap();
+        let add_book = Migration::new(1, "add_book", |db| {
+            Box::pin(async move {
+                db.collection::<Document>("migrated_books")
+                    .insert_one(doc! {"title": "East of Eden"}, None)
+                    .await?;
+                Ok(())
+            })
+        });
+        let tag_books = Migration::new(2, "tag_books", |db| {
+            Box::pin(async move {
+                db.collection::<Document>("migrated_books")
+                    .update_many(doc! {}, doc! {"$set": {"tags": ["novel"]}}, None)
+                    .await?;
+                Ok(())
+            })
+        });
+
+        let migrator =
+            Migrator::new(db.clone(), vec![tag_books.clone(), add_book.clone()]).unwrap();
+        let plan = migrator
+            .run_command(MigrationCommand::DryRun)
+            .await
+            .unwrap();
+        assert_eq!(
+            "would apply 0001 add_book\nwould apply 0002 tag_books",
+            plan
+        );
+        assert_eq!(vec![1, 2], migrator.apply().await.unwrap());
+        assert!(migrator.apply().await.unwrap().is_empty());
+        let book = db
+            .collection::<Document>("migrated_books")
+            .find_one(None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, book.get_array("tags").unwrap().len());
+        let statuses = migrator.status().await.unwrap();
+        assert!(statuses
+            .iter()
+            .all(|s| matches!(s.state(), MigrationState::Applied { .. })));
+
+        let edited = Migrator::new(db, vec![add_book.set_source("edited"), tag_books]).unwrap();
+        assert!(matches!(
+            edited.apply().await,
+            Err(MigrationError::ChecksumMismatch { version: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let db = store_client.get_db();
+        let repository = MongoRepository::new(db.collection::<Book>("watched_books"));
+        let tokens = ResumeTokens::new(&db);
+        let mut changes = repository.watch(None).await.unwrap();
+        let book = Book {
+            title: "Of Mice and Men".to_string(),
+            author: "Steinbeck".to_string(),
+            ..Default::default()
+        };
+        repository.insert_one(&book).await.unwrap();
+        repository
+            .update_fields_by_id(
+                &book.id,
+                Update::new().set("author", "John Steinbeck"),
+                ReturnDocument::After,
+            )
+            .await
+            .unwrap();
+        repository.delete_by_id(book.id.to_string()).await.unwrap();
+
+        let inserted = changes.try_next().await.unwrap().unwrap();
+        assert!(
+            matches!(inserted.event(), ChangeEvent::Inserted { document, .. } if document.title == book.title)
+        );
+        tokens
+            .save("test_watch", inserted.resume_token())
+            .await
+            .unwrap();
+        let updated = changes.try_next().await.unwrap().unwrap();
+        assert!(matches!(
+            updated.event(),
+            ChangeEvent::Updated { updated_fields, .. } if updated_fields == &doc! {"author": "John Steinbeck"}
+        ));
+
+        let token = tokens.load("test_watch").await.unwrap();
+        assert_eq!(Some(inserted.resume_token()), token.as_ref());
+        let mut resumed = repository.watch(token).await.unwrap();
+        let next = resumed.try_next().await.unwrap().unwrap();
+        assert_eq!(updated.resume_token(), next.resume_token());
+        let deleted = resumed.try_next().await.unwrap().unwrap();
+        assert!(matches!(deleted.event(), ChangeEvent::Deleted { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let collection = store_client
+            .get_db()
+            .collection::<Account>("deleted_accounts");
+        collection.drop(None).await.unwrap();
+        let repository = MongoRepository::new(collection).set_soft_delete(true);
+        let report = repository.ensure_indexes().await.unwrap();
+        assert_eq!(
+            &vec![String::from("metadata.deleted_at_1")],
+            report.created()
+        );
+        let accounts: Vec<Account> = (0..2)
+            .map(|balance| {
+                let id = Id::default();
+                Account {
+                    domain_metadata: Metadata::new_with_default(&id),
+                    id,
+                    balance,
+                }
+            })
+            .collect();
+        repository.insert_many(&accounts).await.unwrap();
+        let id = accounts[0].id.to_string();
+
+        let deleted = repository.delete_by_id(id.clone()).await.unwrap().unwrap();
+        assert!(deleted.domain_metadata.is_deleted());
+        assert!(repository.delete_by_id(id.clone()).await.unwrap().is_none());
+        assert!(repository.find_by_id(&id).await.unwrap().is_none());
+        assert_eq!(1, repository.count().await.unwrap());
+        let found: Vec<Account> = repository
+            .find_deleted(Filter::all(), None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![id.clone()],
+            found.iter().map(|a| a.id.to_string()).collect::<Vec<_>>()
+        );
+
+        let restored = repository.restore_by_id(&id).await.unwrap().unwrap();
+        assert!(!restored.domain_metadata.is_deleted());
+        assert_eq!(2, repository.count().await.unwrap());
+
+        assert_eq!(2, repository.delete_many(None).await.unwrap());
+        let retention = std::time::Duration::from_secs(3600);
+        assert_eq!(0, repository.purge_deleted(retention).await.unwrap());
+        let purged = repository
+            .purge_deleted(std::time::Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(2, purged);
+        assert!(repository.restore_by_id(&id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_write() {
+        let store_client = StoreClient::new(String::from("test")).await.unwrap();
+        let collection = store_client.get_db().collection::<Book>("bulk_books");
+        collection.drop(None).await.unwrap();
+        let repository = MongoRepository::new(collection);
+        let books: Vec<Book> = ["East of Eden", "Cannery Row", "Tortilla Flat"]
+            .iter()
+            .map(|title| Book {
+                title: title.to_string(),
+                author: "John Steinbeck".to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let writes = vec![
+            WriteOp::Insert(Book {
+                id: books[0].id.clone(),
+                ..Default::default()
+            }),
+            WriteOp::Update {
+                id: books[0].id.to_string(),
+                update: Box::new(Update::new().set("title", "East of Eden")),
+            },
+            WriteOp::Insert(Book {
+                id: books[0].id.clone(),
+                ..Default::default()
+            }),
+            WriteOp::Replace {
+                id: books[1].id.to_string(),
+                entity: Book {
+                    id: books[1].id.clone(),
+                    title: "Cannery Row".to_string(),
+                    ..Default::default()
+                },
+                upsert: true,
+            },
+            WriteOp::Delete {
+                id: books[0].id.to_string(),
+            },
+        ];
+        let options = BulkOptions::default().set_ordered(false).set_batch_size(2);
+        let result = repository
+            .bulk_write(futures_util::stream::iter(writes).boxed(), options)
+            .await
+            .unwrap();
+        assert_eq!(1, result.inserted());
+        assert_eq!(1, result.modified());
+        assert_eq!(1, result.upserted());
+        assert_eq!(1, result.deleted());
+        assert_eq!(1, result.errors().len());
+        assert_eq!(2, result.errors()[0].index());
+        assert_eq!(Some(11000), result.errors()[0].code());
+
+        let mut exported = Vec::new();
+        export_jsonl(
+            &repository,
+            Filter::all(),
+            ExportOptions::default(),
+            &mut exported,
+        )
+        .await
+        .unwrap();
+        let mut lines = exported;
+        for book in &books[1..] {
+            lines.extend_from_slice(book.to_json().unwrap().as_bytes());
+            lines.push(b'\n');
+        }
+        let imported = import_jsonl(&repository, lines.as_slice(), BulkOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(0, imported.inserted());
+        assert_eq!(1, imported.errors().len());
+        let imported = import_jsonl(&repository, lines.as_slice(), options)
+            .await
+            .unwrap();
+        assert_eq!(1, imported.inserted());
+        assert_eq!(2, repository.count().await.unwrap());
+
+        let accounts = store_client
+            .get_db()
+            .collection::<Account>("bulk_deleted_accounts");
+        accounts.drop(None).await.unwrap();
+        let accounts = MongoRepository::new(accounts).set_soft_delete(true);
+        let id = Id::default();
+        let account = |balance| Account {
+            domain_metadata: Metadata::new_with_default(&id),
+            id: id.clone(),
+            balance,
+        };
+        let writes = vec![
+            WriteOp::Insert(account(10)),
+            WriteOp::Delete { id: id.to_string() },
+            WriteOp::Update {
+                id: id.to_string(),
+                update: Box::new(Update::new().set("balance", 20)),
+            },
+            WriteOp::Replace {
+                id: id.to_string(),
+                entity: account(30),
+                upsert: true,
+            },
+        ];
+        let result = accounts
+            .bulk_write(
+                futures_util::stream::iter(writes).boxed(),
+                BulkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!result.has_errors());
+        assert_eq!(1, result.deleted());
+        assert_eq!(1, result.matched());
+        let restored = accounts.find_by_id(&id).await.unwrap().unwrap();
+        assert_eq!(30, restored.balance);
+        assert!(!restored.domain_metadata.is_deleted());
+
+        // backups keep the deleted accounts, still deleted and purgeable once imported
+        accounts.delete_by_id(id.to_string()).await.unwrap();
+        let mut live = Vec::new();
+        export_jsonl(
+            &accounts,
+            Filter::all(),
+            ExportOptions::default(),
+            &mut live,
+        )
+        .await
+        .unwrap();
+        assert!(live.is_empty());
+        let mut backup = Vec::new();
+        let options = ExportOptions::default().set_include_deleted(true);
+        assert_eq!(
+            1,
+            export_jsonl(&accounts, Filter::all(), options, &mut backup)
+                .await
+                .unwrap()
+        );
+        let restored = store_client
+            .get_db()
+            .collection::<Account>("bulk_restored_accounts");
+        restored.drop(None).await.unwrap();
+        let restored = MongoRepository::new(restored).set_soft_delete(true);
+        let imported = import_jsonl(&restored, backup.as_slice(), BulkOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(1, imported.inserted());
+        assert!(imported.write_concern_errors().is_empty());
+        assert!(restored.find_by_id(&id).await.unwrap().is_none());
+        assert_eq!(
+            1,
+            restored
+                .purge_deleted(std::time::Duration::ZERO)
+                .await
+                .unwrap()
+        );
+    }
 }